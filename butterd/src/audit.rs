@@ -0,0 +1,285 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use zbus::{fdo, interface, message::Header, names::BusName, zvariant::ObjectPath};
+
+use crate::{config, Polkit, ToFdo};
+
+/// A single privileged operation performed by butterd
+#[derive(Clone, Debug, Default, Deserialize, Serialize, zbus::zvariant::Type)]
+pub struct AuditEntry {
+    pub unix_secs: i64,
+    /// D-Bus interface and method name, e.g. `Storage.RemoveSubvolumes`
+    pub operation: String,
+    pub arguments: Vec<String>,
+    pub caller_uid: Option<u32>,
+    pub caller_pid: Option<u32>,
+    /// `false` if polkit denied the request
+    pub authorized: bool,
+    /// `true` if the operation completed without error
+    pub succeeded: bool,
+    /// error message if the operation failed
+    pub error: String,
+}
+
+/// Append-only JSON lines log of privileged operations
+#[derive(Clone)]
+pub struct AuditLog {
+    dbus: fdo::DBusProxy<'static>,
+    polkit: Polkit,
+}
+
+static ACTION_ID: &str = "org.zhangyuannie.butter.read-audit-log";
+
+/// Size past which the log is rotated to [`rotated_path`], keeping at most
+/// twice as much on disk
+const MAX_LOG_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Size of the chunks the end of the log is read in
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+impl AuditLog {
+    pub const PATH: ObjectPath<'static> =
+        ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/AuditLog");
+
+    pub async fn new(conn: &zbus::Connection, polkit: Polkit) -> zbus::Result<Self> {
+        Ok(Self {
+            dbus: fdo::DBusProxy::new(conn).await?,
+            polkit,
+        })
+    }
+
+    async fn caller(&self, header: &Header<'_>) -> (Option<u32>, Option<u32>) {
+        let Some(sender) = header.sender() else {
+            return (None, None);
+        };
+        let name = BusName::from(sender.to_owned());
+        let uid = self.dbus.get_connection_unix_user(name.clone()).await.ok();
+        let pid = self.dbus.get_connection_unix_process_id(name).await.ok();
+        (uid, pid)
+    }
+
+    /// Record the outcome of `operation` invoked by the sender of `header`
+    pub async fn record<T>(
+        &self,
+        header: &Header<'_>,
+        operation: &str,
+        arguments: Vec<String>,
+        result: &fdo::Result<T>,
+    ) {
+        let (caller_uid, caller_pid) = self.caller(header).await;
+        let entry = AuditEntry {
+            unix_secs: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64),
+            operation: operation.to_owned(),
+            arguments,
+            caller_uid,
+            caller_pid,
+            authorized: !matches!(result, Err(fdo::Error::AuthFailed(_))),
+            succeeded: result.is_ok(),
+            error: match result {
+                Ok(_) => String::new(),
+                Err(err) => err.to_string(),
+            },
+        };
+
+        info!(
+            operation = %entry.operation,
+            uid = ?entry.caller_uid,
+            pid = ?entry.caller_pid,
+            authorized = entry.authorized,
+            succeeded = entry.succeeded,
+            "audit"
+        );
+
+        if let Err(err) = Self::append(Path::new(config::AUDIT_LOG), &entry) {
+            warn!("failed to write audit log: {}", err);
+        }
+    }
+
+    fn append(path: &Path, entry: &AuditEntry) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() >= MAX_LOG_SIZE => {
+                fs::rename(path, rotated_path(path))?;
+            }
+            _ => {}
+        }
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // a single write keeps lines intact with O_APPEND
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?
+            .write_all(&line)
+    }
+
+    /// Read the last `limit` entries, oldest first, including those in the
+    /// rotated log
+    pub fn read_recent(path: &Path, limit: usize) -> io::Result<Vec<AuditEntry>> {
+        let mut ret = read_tail(path, limit)?;
+        if ret.len() < limit {
+            let mut older = read_tail(&rotated_path(path), limit - ret.len())?;
+            older.append(&mut ret);
+            ret = older;
+        }
+        Ok(ret)
+    }
+}
+
+/// Where the log at `path` is moved once it reaches [`MAX_LOG_SIZE`]
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".1");
+    name.into()
+}
+
+/// Parse the last `limit` entries of the log at `path` without reading all of
+/// it
+fn read_tail(path: &Path, limit: usize) -> io::Result<Vec<AuditEntry>> {
+    let mut f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    // every complete line ends with a newline, so `limit` lines need one
+    // more newline before them unless the start of the file is reached
+    let mut pos = f.seek(SeekFrom::End(0))?;
+    let mut buf = Vec::new();
+    while pos > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= limit {
+        let len = pos.min(TAIL_CHUNK_SIZE);
+        pos -= len;
+        let mut chunk = vec![0; len as usize];
+        f.seek(SeekFrom::Start(pos))?;
+        f.read_exact(&mut chunk)?;
+        chunk.append(&mut buf);
+        buf = chunk;
+    }
+
+    let mut lines: Vec<&[u8]> = buf.split(|&b| b == b'\n').collect();
+    if pos > 0 {
+        // cut in the middle
+        lines.remove(0);
+    }
+    let mut ret: Vec<AuditEntry> = lines
+        .into_iter()
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect();
+    let skip = ret.len().saturating_sub(limit);
+    ret.drain(..skip);
+    Ok(ret)
+}
+
+#[interface(
+    name = "org.zhangyuannie.Butter1.AuditLog",
+    proxy(
+        gen_blocking = true,
+        default_service = "org.zhangyuannie.Butter1",
+        default_path = "/org/zhangyuannie/Butter1/AuditLog",
+    )
+)]
+impl AuditLog {
+    async fn recent_entries(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        limit: u32,
+    ) -> fdo::Result<Vec<AuditEntry>> {
+        self.polkit.validate(&header, ACTION_ID).await?;
+        Self::read_recent(Path::new(config::AUDIT_LOG), limit as usize).to_fdo()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn entry(unix_secs: i64) -> AuditEntry {
+        AuditEntry {
+            unix_secs,
+            operation: "Storage.RemoveSubvolumes".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_append_and_read_recent() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log/audit.log");
+        for i in 0..10 {
+            AuditLog::append(&path, &entry(i)).unwrap();
+        }
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        let recent = AuditLog::read_recent(&path, 3).unwrap();
+        let secs: Vec<i64> = recent.iter().map(|e| e.unix_secs).collect();
+        assert_eq!(secs, [7, 8, 9]);
+        assert_eq!(AuditLog::read_recent(&path, 100).unwrap().len(), 10);
+        assert!(AuditLog::read_recent(&dir.path().join("missing"), 3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_read_tail_across_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let mut e = entry(0);
+        e.arguments = vec!["x".repeat(TAIL_CHUNK_SIZE as usize / 3)];
+        for i in 0..8 {
+            e.unix_secs = i;
+            AuditLog::append(&path, &e).unwrap();
+        }
+
+        let secs: Vec<i64> = read_tail(&path, 5)
+            .unwrap()
+            .iter()
+            .map(|e| e.unix_secs)
+            .collect();
+        assert_eq!(secs, [3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        AuditLog::append(&path, &entry(0)).unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .set_len(MAX_LOG_SIZE)
+            .unwrap();
+        AuditLog::append(&path, &entry(1)).unwrap();
+        AuditLog::append(&path, &entry(2)).unwrap();
+
+        assert!(fs::metadata(&path).unwrap().len() < MAX_LOG_SIZE);
+        let secs: Vec<i64> = AuditLog::read_recent(&path, 3)
+            .unwrap()
+            .iter()
+            .map(|e| e.unix_secs)
+            .collect();
+        assert_eq!(secs, [0, 1, 2]);
+    }
+}
@@ -0,0 +1,171 @@
+#[cfg(any(test, feature = "test-support"))]
+mod fake;
+mod system;
+
+#[cfg(any(test, feature = "test-support"))]
+pub use fake::FakeBtrfs;
+pub use system::LibBtrfsutil;
+
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+
+use uuid::Uuid;
+
+/// What butterd needs to know about a subvolume
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubvolumeInfo {
+    pub id: u64,
+    pub parent_id: Option<u64>,
+    pub uuid: Uuid,
+    /// UUID of the subvolume this is a snapshot of
+    pub parent_uuid: Option<Uuid>,
+    /// UUID of the subvolume this was received from by `btrfs receive`
+    pub received_uuid: Option<Uuid>,
+    /// `BTRFS_ROOT_SUBVOL_*` flags
+    pub flags: u64,
+    /// transaction of the last change to the subvolume's tree
+    pub generation: u64,
+    /// transaction of the last change to the subvolume's root inode
+    pub ctransid: u64,
+    /// transaction the subvolume was created in
+    pub otransid: u64,
+    /// `ctransid` of the sent subvolume if received, otherwise 0
+    pub stransid: u64,
+    /// transaction the subvolume was received in, otherwise 0
+    pub rtransid: u64,
+    /// change time in unix seconds
+    pub ctime: i64,
+    /// creation time in unix seconds
+    pub otime: i64,
+}
+
+/// From `linux/btrfs_tree.h`
+pub const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 1 << 0;
+
+impl SubvolumeInfo {
+    pub fn is_read_only(&self) -> bool {
+        self.flags & BTRFS_ROOT_SUBVOL_RDONLY != 0
+    }
+}
+
+/// A Btrfs filesystem found on the system
+#[derive(Clone, Debug, Default)]
+pub struct ProbedFilesystem {
+    pub uuid: Uuid,
+    pub label: String,
+    pub devices: Vec<PathBuf>,
+    pub mount_points_by_subvol_id: HashMap<u64, Vec<PathBuf>>,
+}
+
+/// Size of a filesystem as seen by unprivileged users
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FilesystemSpace {
+    pub total_bytes: u64,
+    /// bytes available to unprivileged users
+    pub free_bytes: u64,
+}
+
+/// Longest filesystem label in bytes, `BTRFS_LABEL_SIZE` without the NUL
+pub const BTRFS_LABEL_MAX: usize = 255;
+
+/// Everything butterd does to Btrfs filesystems and block devices.
+///
+/// All paths are absolute paths in the current mount namespace unless
+/// stated otherwise.
+pub trait BtrfsBackend: Send + Sync {
+    /// All Btrfs filesystems on the system with their devices and mount points
+    fn probe_filesystems(&self) -> anyhow::Result<Vec<ProbedFilesystem>>;
+
+    fn is_subvolume(&self, path: &Path) -> io::Result<bool>;
+
+    /// Info of the subvolume containing `path`
+    fn subvolume_info(&self, path: &Path) -> io::Result<SubvolumeInfo> {
+        self.subvolume_info_with_id(path, 0)
+    }
+
+    /// Info of the subvolume `id` on the filesystem containing `path`.
+    ///
+    /// `id` 0 means the subvolume containing `path`.
+    fn subvolume_info_with_id(&self, path: &Path, id: u64) -> io::Result<SubvolumeInfo>;
+
+    /// Path of the subvolume containing `path`, relative to the filesystem root
+    fn subvolume_path(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// All subvolumes except the top-level one on the filesystem containing
+    /// `path`, with paths relative to the filesystem root
+    fn list_subvolumes(&self, path: &Path) -> io::Result<Vec<(PathBuf, SubvolumeInfo)>>;
+
+    /// ID of the default subvolume of the filesystem containing `path`
+    fn default_subvolume(&self, path: &Path) -> io::Result<u64>;
+
+    /// Generation of the filesystem containing `path`.
+    ///
+    /// It only advances when a new transaction starts, so changes made within
+    /// the running transaction are not reflected until the next one.
+    fn generation(&self, path: &Path) -> io::Result<u64>;
+
+    /// A path where the top-level subvolume of the filesystem containing
+    /// `path` is mounted, mounting it privately if it is not mounted anywhere
+    fn top_level_path(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Absolute paths of all subvolumes below the subvolume at `path`,
+    /// outermost first
+    fn nested_subvolumes(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Create an empty subvolume at `path`, added to the qgroups `qgroups` in
+    /// addition to its own level 0 qgroup
+    fn create_subvolume(&self, path: &Path, qgroups: &[u64]) -> io::Result<()>;
+
+    /// Create a writable snapshot of the subvolume at `src` at `dst`.
+    ///
+    /// Nested subvolumes are snapshotted too if `recursive`, otherwise they
+    /// show up as empty directories.
+    fn create_snapshot(&self, src: &Path, dst: &Path, recursive: bool) -> io::Result<()>;
+
+    /// Delete the subvolume at `path` and all subvolumes below it
+    fn delete_subvolume(&self, path: &Path) -> io::Result<()>;
+
+    fn set_read_only(&self, path: &Path, read_only: bool) -> io::Result<()>;
+
+    fn rename_subvolume(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Atomically swap the entries at `a` and `b`, either of which may be a
+    /// subvolume
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()>;
+
+    /// Copy the regular file at `src` to the new file `dst`, sharing its
+    /// extents instead of duplicating the data. `dst` gets the no
+    /// copy-on-write attribute of `src`, which cannot be added afterwards.
+    fn clone_file(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// `btrfs.compression` property of `path`, empty if unset
+    fn compression(&self, path: &Path) -> io::Result<String>;
+
+    /// Set the `btrfs.compression` property of `path`, removing it if empty
+    fn set_compression(&self, path: &Path, value: &str) -> io::Result<()>;
+
+    /// `true` if `path` has the no copy-on-write attribute, inherited by
+    /// files created in it
+    fn is_nodatacow(&self, path: &Path) -> io::Result<bool>;
+
+    fn set_nodatacow(&self, path: &Path, nodatacow: bool) -> io::Result<()>;
+
+    /// Give `dst` the inode flags of `src`, such as immutable or no
+    /// copy-on-write, neither of which may be a symlink
+    fn copy_inode_flags(&self, src: &Path, dst: &Path) -> io::Result<()>;
+
+    /// Label of the filesystem containing `path`
+    fn label(&self, path: &Path) -> io::Result<String>;
+
+    fn set_label(&self, path: &Path, label: &str) -> io::Result<()>;
+
+    /// Size and free space of the filesystem containing `path`
+    fn space(&self, path: &Path) -> io::Result<FilesystemSpace>;
+
+    /// Bytes referred to by the subvolume at `path` only, from its level 0
+    /// qgroup. `None` if quotas are disabled on the filesystem.
+    fn exclusive_bytes(&self, path: &Path) -> io::Result<Option<u64>>;
+}
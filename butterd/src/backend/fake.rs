@@ -0,0 +1,600 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use uuid::Uuid;
+
+use super::{
+    BtrfsBackend, FilesystemSpace, ProbedFilesystem, SubvolumeInfo, BTRFS_LABEL_MAX,
+    BTRFS_ROOT_SUBVOL_RDONLY,
+};
+
+/// An in-memory Btrfs filesystem for testing.
+///
+/// The subvolume tree lives in memory while file contents live in a scratch
+/// directory on the real filesystem. The top-level subvolume is the scratch
+/// directory itself, which is also its only mount point unless [`Self::mount`]
+/// is used. Read-only subvolumes are tracked but not enforced.
+pub struct FakeBtrfs {
+    root: PathBuf,
+    uuid: Uuid,
+    state: Mutex<State>,
+}
+
+struct State {
+    now: Option<i64>,
+    generation: u64,
+    next_id: u64,
+    default_id: u64,
+    subvols: BTreeMap<u64, FakeSubvol>,
+    mounts: Vec<(PathBuf, u64)>,
+    label: String,
+    space: FilesystemSpace,
+    /// operations made to fail by [`FakeBtrfs::fail`]
+    failures: Vec<(&'static str, PathBuf)>,
+}
+
+struct FakeSubvol {
+    info: SubvolumeInfo,
+    compression: String,
+    nodatacow: bool,
+    /// qgroups the subvolume was added to when created
+    qgroups: Vec<u64>,
+    /// `None` until set, as if quotas were disabled
+    exclusive_bytes: Option<u64>,
+    /// relative to the filesystem root
+    root_path: PathBuf,
+}
+
+fn not_found() -> io::Error {
+    io::Error::from(io::ErrorKind::NotFound)
+}
+
+/// Copy `src` to `dst`, leaving out `exclude`
+fn copy_dir(
+    src: &Path,
+    dst: &Path,
+    exclude: &Path,
+    skip: &dyn Fn(&Path) -> bool,
+) -> io::Result<()> {
+    fs::create_dir(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let from = entry.path();
+        if from == exclude {
+            continue;
+        }
+        let to = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if skip(&from) {
+            // nested subvolumes show up as empty directories in non-recursive snapshots
+            fs::create_dir(&to)?;
+        } else if file_type.is_dir() {
+            copy_dir(&from, &to, exclude, skip)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else {
+            fs::copy(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+impl FakeBtrfs {
+    /// Turn the existing directory `root` into the top-level subvolume of a
+    /// new fake filesystem mounted at `root`
+    pub fn new(root: &Path) -> Self {
+        let top_level = FakeSubvol {
+            info: SubvolumeInfo {
+                id: libbtrfsutil::FS_TREE_OBJECTID,
+                uuid: Uuid::new_v4(),
+                ..Default::default()
+            },
+            compression: String::new(),
+            nodatacow: false,
+            qgroups: Vec::new(),
+            exclusive_bytes: None,
+            root_path: PathBuf::new(),
+        };
+        Self {
+            root: root.to_owned(),
+            uuid: Uuid::new_v4(),
+            state: Mutex::new(State {
+                now: None,
+                generation: 1,
+                next_id: 256,
+                default_id: libbtrfsutil::FS_TREE_OBJECTID,
+                subvols: BTreeMap::from([(libbtrfsutil::FS_TREE_OBJECTID, top_level)]),
+                mounts: vec![(root.to_owned(), libbtrfsutil::FS_TREE_OBJECTID)],
+                label: "fake".to_owned(),
+                failures: Vec::new(),
+                space: FilesystemSpace {
+                    total_bytes: 1 << 40,
+                    free_bytes: 1 << 40,
+                },
+            }),
+        }
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.uuid
+    }
+
+    /// Use `unix_secs` as the creation time of new subvolumes
+    pub fn set_now(&self, unix_secs: i64) {
+        self.state().now = Some(unix_secs);
+    }
+
+    /// Report `space` as the size of the filesystem. Deleting subvolumes does
+    /// not change it, like Btrfs freeing their space in the background.
+    pub fn set_space(&self, space: FilesystemSpace) {
+        self.state().space = space;
+    }
+
+    /// Report `bytes` as the exclusive size of the subvolume at `path`
+    pub fn set_exclusive_bytes(&self, path: &Path, bytes: u64) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        // unwrap: id is from the map
+        state.subvols.get_mut(&id).unwrap().exclusive_bytes = Some(bytes);
+        Ok(())
+    }
+
+    /// Create an empty subvolume that is not a snapshot
+    pub fn create_subvolume(&self, path: &Path) -> io::Result<()> {
+        BtrfsBackend::create_subvolume(self, path, &[])
+    }
+
+    /// Qgroups the subvolume at `path` was added to when created
+    pub fn qgroups(&self, path: &Path) -> io::Result<Vec<u64>> {
+        let state = self.state();
+        Ok(self.exact(&state, path)?.qgroups.clone())
+    }
+
+    /// Add the subvolume at `path` as mounted at `path`
+    pub fn mount(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        state.mounts.push((path.to_owned(), id));
+        Ok(())
+    }
+
+    /// Remove the mount point at `path`, the subvolume stays reachable
+    /// through the scratch directory
+    pub fn unmount(&self, path: &Path) {
+        self.state().mounts.retain(|(p, _)| p != path);
+    }
+
+    pub fn set_default_subvolume(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.default_id = self.exact(&state, path)?.info.id;
+        Ok(())
+    }
+
+    /// Record a change to the content of the subvolume at `path`
+    pub fn touch(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        state.commit();
+        let generation = state.generation;
+        // unwrap: id is from the map
+        state.subvols.get_mut(&id).unwrap().info.generation = generation;
+        Ok(())
+    }
+
+    /// Mark the subvolume at `path` as received from `received_uuid`
+    pub fn set_received(&self, path: &Path, received_uuid: Uuid) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        // unwrap: id is from the map
+        state.subvols.get_mut(&id).unwrap().info.received_uuid = Some(received_uuid);
+        Ok(())
+    }
+
+    pub fn is_read_only(&self, path: &Path) -> io::Result<bool> {
+        let state = self.state();
+        Ok(self.exact(&state, path)?.info.is_read_only())
+    }
+
+    /// Make the [`BtrfsBackend`] method `operation` fail when called on
+    /// `path`. Supported for `create_snapshot` with the destination and
+    /// `delete_subvolume`.
+    pub fn fail(&self, operation: &'static str, path: &Path) {
+        self.state().failures.push((operation, path.to_owned()));
+    }
+
+    fn check_failure(state: &State, operation: &str, path: &Path) -> io::Result<()> {
+        if state
+            .failures
+            .iter()
+            .any(|(op, p)| *op == operation && p == path)
+        {
+            return Err(io::Error::new(io::ErrorKind::Other, "injected failure"));
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn relative(&self, path: &Path) -> io::Result<PathBuf> {
+        // follow open directories like the kernel does
+        let resolved;
+        let path = match path.strip_prefix("/proc/self/fd") {
+            Ok(rest) => {
+                let mut components = rest.components();
+                let fd = components.next().ok_or_else(not_found)?;
+                resolved =
+                    fs::read_link(Path::new("/proc/self/fd").join(fd))?.join(components.as_path());
+                &resolved
+            }
+            Err(_) => path,
+        };
+        path.strip_prefix(&self.root)
+            .map(Path::to_owned)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    /// The subvolume containing `path`
+    fn containing<'a>(&self, state: &'a State, path: &Path) -> io::Result<&'a FakeSubvol> {
+        let rel = self.relative(path)?;
+        state
+            .subvols
+            .values()
+            .filter(|subvol| rel.starts_with(&subvol.root_path))
+            .max_by_key(|subvol| subvol.root_path.components().count())
+            .ok_or_else(not_found)
+    }
+
+    /// The subvolume whose root is `path`
+    fn exact<'a>(&self, state: &'a State, path: &Path) -> io::Result<&'a FakeSubvol> {
+        let rel = self.relative(path)?;
+        state
+            .subvols
+            .values()
+            .find(|subvol| subvol.root_path == rel)
+            .ok_or_else(not_found)
+    }
+}
+
+impl State {
+    /// Every change is its own transaction
+    fn commit(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Add a new subvolume, returning its ID
+    fn insert(
+        &mut self,
+        root_path: PathBuf,
+        parent_id: Option<u64>,
+        parent_uuid: Option<Uuid>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.commit();
+        let now = self.now();
+        self.subvols.insert(
+            id,
+            FakeSubvol {
+                info: SubvolumeInfo {
+                    id,
+                    parent_id,
+                    uuid: Uuid::new_v4(),
+                    parent_uuid,
+                    generation: self.generation,
+                    ctransid: self.generation,
+                    otransid: self.generation,
+                    ctime: now,
+                    otime: now,
+                    ..Default::default()
+                },
+                compression: String::new(),
+                nodatacow: false,
+                qgroups: Vec::new(),
+                exclusive_bytes: None,
+                root_path,
+            },
+        );
+        id
+    }
+
+    fn now(&self) -> i64 {
+        self.now.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64)
+        })
+    }
+}
+
+impl BtrfsBackend for FakeBtrfs {
+    fn probe_filesystems(&self) -> anyhow::Result<Vec<ProbedFilesystem>> {
+        let state = self.state();
+        let mut ret = ProbedFilesystem {
+            uuid: self.uuid,
+            label: state.label.clone(),
+            devices: vec![PathBuf::from("/dev/fake")],
+            mount_points_by_subvol_id: Default::default(),
+        };
+        for (path, id) in &state.mounts {
+            ret.mount_points_by_subvol_id
+                .entry(*id)
+                .or_default()
+                .push(path.clone());
+        }
+        Ok(vec![ret])
+    }
+
+    fn is_subvolume(&self, path: &Path) -> io::Result<bool> {
+        let state = self.state();
+        Ok(self.exact(&state, path).is_ok())
+    }
+
+    fn subvolume_info_with_id(&self, path: &Path, id: u64) -> io::Result<SubvolumeInfo> {
+        let state = self.state();
+        let subvol = self.containing(&state, path)?;
+        if id == 0 {
+            return Ok(subvol.info.clone());
+        }
+        state
+            .subvols
+            .get(&id)
+            .map(|subvol| subvol.info.clone())
+            .ok_or_else(not_found)
+    }
+
+    fn subvolume_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.state();
+        Ok(self.containing(&state, path)?.root_path.clone())
+    }
+
+    fn list_subvolumes(&self, path: &Path) -> io::Result<Vec<(PathBuf, SubvolumeInfo)>> {
+        let state = self.state();
+        self.containing(&state, path)?;
+        Ok(state
+            .subvols
+            .values()
+            .filter(|subvol| subvol.info.id != libbtrfsutil::FS_TREE_OBJECTID)
+            .map(|subvol| (subvol.root_path.clone(), subvol.info.clone()))
+            .collect())
+    }
+
+    fn default_subvolume(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state();
+        self.containing(&state, path)?;
+        Ok(state.default_id)
+    }
+
+    fn generation(&self, path: &Path) -> io::Result<u64> {
+        let state = self.state();
+        self.containing(&state, path)?;
+        Ok(state.generation)
+    }
+
+    fn top_level_path(&self, path: &Path) -> io::Result<PathBuf> {
+        self.relative(path)?;
+        // the scratch directory stands in for a private mount
+        Ok(self
+            .state()
+            .mounts
+            .iter()
+            .find(|(_, id)| *id == libbtrfsutil::FS_TREE_OBJECTID)
+            .map_or_else(|| self.root.clone(), |(p, _)| p.clone()))
+    }
+
+    fn nested_subvolumes(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state();
+        let rel = self.exact(&state, path)?.root_path.clone();
+        let mut ret: Vec<&Path> = state
+            .subvols
+            .values()
+            .map(|subvol| subvol.root_path.as_path())
+            .filter(|p| *p != rel && p.starts_with(&rel))
+            .collect();
+        ret.sort_by_key(|p| p.components().count());
+        Ok(ret.into_iter().map(|p| self.root.join(p)).collect())
+    }
+
+    fn create_subvolume(&self, path: &Path, qgroups: &[u64]) -> io::Result<()> {
+        let mut state = self.state();
+        let parent = self.containing(&state, path.parent().ok_or_else(not_found)?)?;
+        let parent_id = parent.info.id;
+        let root_path = self.relative(path)?;
+        fs::create_dir(path)?;
+        let id = state.insert(root_path, Some(parent_id), None);
+        // unwrap: just inserted
+        state.subvols.get_mut(&id).unwrap().qgroups = qgroups.to_vec();
+        Ok(())
+    }
+
+    fn create_snapshot(&self, src: &Path, dst: &Path, recursive: bool) -> io::Result<()> {
+        let mut state = self.state();
+        Self::check_failure(&state, "create_snapshot", dst)?;
+        let src_rel = self.exact(&state, src)?.root_path.clone();
+        let dst_rel = self.relative(dst)?;
+        // the source itself comes first, then nested subvolumes from the outermost
+        let mut sources: Vec<(PathBuf, Uuid)> = state
+            .subvols
+            .values()
+            .filter(|subvol| subvol.root_path.starts_with(&src_rel))
+            .map(|subvol| (subvol.root_path.clone(), subvol.info.uuid))
+            .collect();
+        sources.sort_by_key(|(p, _)| p.components().count());
+        let nested: Vec<PathBuf> = sources[1..]
+            .iter()
+            .map(|(p, _)| self.root.join(p))
+            .collect();
+
+        if dst.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        // snapshots taken into their source do not contain themselves
+        let dst = self.root.join(&dst_rel);
+        copy_dir(&self.root.join(&src_rel), &dst, &dst, &|p| {
+            !recursive && nested.iter().any(|n| n == p)
+        })?;
+
+        let count = if recursive { sources.len() } else { 1 };
+        for (root_path, uuid) in sources.into_iter().take(count) {
+            // unwrap: filtered by starts_with
+            let rest = root_path.strip_prefix(&src_rel).unwrap();
+            let new_rel = if rest.as_os_str().is_empty() {
+                dst_rel.clone()
+            } else {
+                dst_rel.join(rest)
+            };
+            let new_path = self.root.join(&new_rel);
+            let parent_id = self
+                .containing(&state, new_path.parent().ok_or_else(not_found)?)?
+                .info
+                .id;
+            state.insert(new_rel, Some(parent_id), Some(uuid));
+        }
+        Ok(())
+    }
+
+    fn delete_subvolume(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        Self::check_failure(&state, "delete_subvolume", path)?;
+        let rel = self.exact(&state, path)?.root_path.clone();
+        if rel.as_os_str().is_empty() {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        fs::remove_dir_all(path)?;
+        state
+            .subvols
+            .retain(|_, subvol| !subvol.root_path.starts_with(&rel));
+        state.mounts.retain(|(mnt, _)| !mnt.starts_with(path));
+        state.commit();
+        Ok(())
+    }
+
+    fn set_read_only(&self, path: &Path, read_only: bool) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        state.commit();
+        let (generation, now) = (state.generation, state.now());
+        // unwrap: id is from the map
+        let info = &mut state.subvols.get_mut(&id).unwrap().info;
+        if read_only {
+            info.flags |= BTRFS_ROOT_SUBVOL_RDONLY;
+        } else {
+            info.flags &= !BTRFS_ROOT_SUBVOL_RDONLY;
+        }
+        info.ctransid = generation;
+        info.ctime = now;
+        Ok(())
+    }
+
+    fn rename_subvolume(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let src_rel = self.exact(&state, src)?.root_path.clone();
+        let dst_rel = self.relative(dst)?;
+        fs::rename(src, dst)?;
+        for subvol in state.subvols.values_mut() {
+            if let Ok(rest) = subvol.root_path.strip_prefix(&src_rel) {
+                subvol.root_path = if rest.as_os_str().is_empty() {
+                    dst_rel.clone()
+                } else {
+                    dst_rel.join(rest)
+                };
+            }
+        }
+        state.commit();
+        Ok(())
+    }
+
+    /// Not atomic, which tests cannot tell apart
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        let a_rel = self.relative(a)?;
+        let b_rel = self.relative(b)?;
+        let tmp = a.with_file_name(".fake-exchange");
+        fs::rename(a, &tmp)?;
+        fs::rename(b, a)?;
+        fs::rename(&tmp, b)?;
+        for subvol in state.subvols.values_mut() {
+            let swapped = if let Ok(rest) = subvol.root_path.strip_prefix(&a_rel) {
+                b_rel.join(rest)
+            } else if let Ok(rest) = subvol.root_path.strip_prefix(&b_rel) {
+                a_rel.join(rest)
+            } else {
+                continue;
+            };
+            subvol.root_path = swapped.components().collect();
+        }
+        state.commit();
+        Ok(())
+    }
+
+    fn clone_file(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        if dst.exists() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        fs::copy(src, dst)?;
+        Ok(())
+    }
+
+    fn compression(&self, path: &Path) -> io::Result<String> {
+        let state = self.state();
+        Ok(self.exact(&state, path)?.compression.clone())
+    }
+
+    fn set_compression(&self, path: &Path, value: &str) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        // unwrap: id is from the map
+        state.subvols.get_mut(&id).unwrap().compression = value.to_owned();
+        Ok(())
+    }
+
+    fn is_nodatacow(&self, path: &Path) -> io::Result<bool> {
+        let state = self.state();
+        Ok(self.exact(&state, path)?.nodatacow)
+    }
+
+    fn set_nodatacow(&self, path: &Path, nodatacow: bool) -> io::Result<()> {
+        let mut state = self.state();
+        let id = self.exact(&state, path)?.info.id;
+        // unwrap: id is from the map
+        state.subvols.get_mut(&id).unwrap().nodatacow = nodatacow;
+        Ok(())
+    }
+
+    fn copy_inode_flags(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        // only subvolumes have flags here
+        self.relative(src)?;
+        self.relative(dst)?;
+        Ok(())
+    }
+
+    fn label(&self, path: &Path) -> io::Result<String> {
+        self.relative(path)?;
+        Ok(self.state().label.clone())
+    }
+
+    fn set_label(&self, path: &Path, label: &str) -> io::Result<()> {
+        self.relative(path)?;
+        if label.len() > BTRFS_LABEL_MAX {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.state().label = label.to_owned();
+        Ok(())
+    }
+
+    fn space(&self, path: &Path) -> io::Result<FilesystemSpace> {
+        self.relative(path)?;
+        Ok(self.state().space)
+    }
+
+    fn exclusive_bytes(&self, path: &Path) -> io::Result<Option<u64>> {
+        let state = self.state();
+        Ok(self.exact(&state, path)?.exclusive_bytes)
+    }
+}
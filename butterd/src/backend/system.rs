@@ -0,0 +1,547 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs, io,
+    os::{
+        fd::AsRawFd,
+        unix::{ffi::OsStrExt, fs::DirBuilderExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use libblkid_rs::{evaluate_spec, BlkidCache};
+use uuid::Uuid;
+
+use super::{BtrfsBackend, FilesystemSpace, ProbedFilesystem, SubvolumeInfo, BTRFS_LABEL_MAX};
+use crate::MntEntries;
+
+/// The real system, through `libbtrfsutil` and `libblkid`
+pub struct LibBtrfsutil;
+
+impl From<libbtrfsutil::SubvolumeInfo> for SubvolumeInfo {
+    fn from(info: libbtrfsutil::SubvolumeInfo) -> Self {
+        Self {
+            id: info.id(),
+            parent_id: info.parent_id().map(Into::into),
+            uuid: info.uuid(),
+            parent_uuid: info.parent_uuid(),
+            received_uuid: info.received_uuid(),
+            flags: info.flags(),
+            generation: info.generation(),
+            ctransid: info.ctransid(),
+            otransid: info.otransid(),
+            stransid: info.stransid().map_or(0, u64::from),
+            rtransid: info.rtransid().map_or(0, u64::from),
+            ctime: info.ctime(),
+            otime: info.otime(),
+        }
+    }
+}
+
+/// `struct btrfs_ioctl_fs_info_args` from `linux/btrfs.h`
+#[repr(C)]
+struct FsInfoArgs {
+    max_id: u64,
+    num_devices: u64,
+    fsid: [u8; 16],
+    nodesize: u32,
+    sectorsize: u32,
+    clone_alignment: u32,
+    csum_type: u16,
+    csum_size: u16,
+    flags: u64,
+    generation: u64,
+    metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+/// `_IOR(BTRFS_IOCTL_MAGIC, 31, struct btrfs_ioctl_fs_info_args)`
+const BTRFS_IOC_FS_INFO: u32 = 0x8400_941f;
+const BTRFS_FS_INFO_FLAG_GENERATION: u64 = 1 << 1;
+
+/// `_IOR(BTRFS_IOCTL_MAGIC, 49, char[BTRFS_LABEL_SIZE])`
+const BTRFS_IOC_GET_FSLABEL: u32 = 0x8100_9431;
+/// `_IOW(BTRFS_IOCTL_MAGIC, 50, char[BTRFS_LABEL_SIZE])`
+const BTRFS_IOC_SET_FSLABEL: u32 = 0x4100_9432;
+const BTRFS_LABEL_SIZE: usize = 256;
+
+/// `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS` from `linux/fs.h`
+const FS_IOC_GETFLAGS: u32 = 0x8008_6601;
+const FS_IOC_SETFLAGS: u32 = 0x4008_6602;
+const FS_NOCOW_FL: libc::c_int = 0x0080_0000;
+
+/// `_IOW(0x94, 9, int)` from `linux/fs.h`
+const FICLONE: u32 = 0x4004_9409;
+
+const COMPRESSION_XATTR: &std::ffi::CStr = c"btrfs.compression";
+
+/// Where top-level subvolumes that are not mounted anywhere get mounted to
+/// keep the metadata store
+const TOP_LEVEL_MOUNT_DIR: &str = "/run/butterd";
+
+fn cpath(path: &Path) -> io::Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn inode_flags(f: &fs::File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+fn set_inode_flags(f: &fs::File, flags: libc::c_int) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn fs_info(path: &Path, flags: u64) -> io::Result<FsInfoArgs> {
+    let f = fs::File::open(path)?;
+    // safety: all zeros is a valid value of a plain C struct
+    let mut args: FsInfoArgs = unsafe { std::mem::zeroed() };
+    args.flags = flags;
+    let ret = unsafe { libc::ioctl(f.as_raw_fd(), BTRFS_IOC_FS_INFO as _, &mut args) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(args)
+}
+
+/// Mount the top-level subvolume of the filesystem `fsid` on `device` at
+/// [`TOP_LEVEL_MOUNT_DIR`], where only root can reach it and from where it
+/// does not propagate to other mount namespaces
+fn mount_top_level(device: &str, fsid: Uuid) -> io::Result<PathBuf> {
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .recursive(true)
+        .create(TOP_LEVEL_MOUNT_DIR)?;
+    let target = Path::new(TOP_LEVEL_MOUNT_DIR).join(fsid.to_string());
+    match fs::create_dir(&target) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => {}
+    }
+
+    let device = CString::new(device)?;
+    let ctarget = cpath(&target)?;
+    let ret = unsafe {
+        libc::mount(
+            device.as_ptr(),
+            ctarget.as_ptr(),
+            c"btrfs".as_ptr(),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            c"subvolid=5".as_ptr().cast(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            ctarget.as_ptr(),
+            std::ptr::null(),
+            libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        unsafe { libc::umount2(ctarget.as_ptr(), libc::MNT_DETACH) };
+        return Err(err);
+    }
+    Ok(target)
+}
+
+fn subvol_id_from_mnt_options(options: &str) -> Option<u64> {
+    for seg in options.split(',') {
+        if let Some(id) = seg.strip_prefix("subvolid=") {
+            return id.parse::<u64>().ok();
+        }
+    }
+    None
+}
+
+impl BtrfsBackend for LibBtrfsutil {
+    fn probe_filesystems(&self) -> anyhow::Result<Vec<ProbedFilesystem>> {
+        let mut ret: HashMap<Uuid, ProbedFilesystem> = HashMap::new();
+
+        let mut cache = BlkidCache::get_cache(None)?;
+        cache.probe_all()?;
+
+        let mut uuid_by_devname = HashMap::new();
+
+        for dev in cache.iter().search("TYPE", "btrfs")? {
+            if let Some(dev) = cache.verify(dev) {
+                let devname = dev.devname()?;
+                let uuid = cache.get_tag_value("UUID", &devname)?;
+                let uuid = Uuid::try_parse(&uuid)?;
+                let label = cache.get_tag_value("LABEL", &devname)?;
+                let vref = ret.entry(uuid).or_insert(ProbedFilesystem {
+                    uuid,
+                    label,
+                    devices: Vec::new(),
+                    mount_points_by_subvol_id: Default::default(),
+                });
+
+                uuid_by_devname.insert(devname.clone(), uuid);
+
+                vref.devices.push(devname)
+            }
+        }
+
+        let f = fs::File::open("/proc/self/mounts")?;
+        let entries = MntEntries::new(io::BufReader::new(f));
+        for entry in entries.flatten() {
+            if entry.fs_type != "btrfs" {
+                continue;
+            }
+            let mnt_path = entry.target.context("failed to read target")?;
+            let devname = evaluate_spec(&entry.spec, Some(&mut cache))?;
+            if let Some(uuid) = uuid_by_devname.get(&devname) {
+                let subvol_id = subvol_id_from_mnt_options(&entry.options)
+                    .ok_or(io::Error::from(io::ErrorKind::InvalidData))?;
+                // unwrap: if uuid in uuid_by_devname, then it must be in ret
+                ret.get_mut(uuid)
+                    .unwrap()
+                    .mount_points_by_subvol_id
+                    .entry(subvol_id)
+                    .or_default()
+                    .push(mnt_path);
+            }
+        }
+
+        Ok(ret.into_values().collect())
+    }
+
+    fn is_subvolume(&self, path: &Path) -> io::Result<bool> {
+        libbtrfsutil::is_subvolume(path).map_err(|e| e.os_error())
+    }
+
+    fn subvolume_info_with_id(&self, path: &Path, id: u64) -> io::Result<SubvolumeInfo> {
+        libbtrfsutil::subvolume_info_with_id(path, id)
+            .map(Into::into)
+            .map_err(|e| e.os_error())
+    }
+
+    fn subvolume_path(&self, path: &Path) -> io::Result<PathBuf> {
+        libbtrfsutil::subvolume_path(path).map_err(|e| e.os_error())
+    }
+
+    fn list_subvolumes(&self, path: &Path) -> io::Result<Vec<(PathBuf, SubvolumeInfo)>> {
+        let iter = libbtrfsutil::IterateSubvolume::new(path)
+            .all()
+            .iter_with_info()
+            .map_err(|e| e.os_error())?;
+        Ok(iter
+            .flatten()
+            .map(|(root_path, info)| (root_path, info.into()))
+            .collect())
+    }
+
+    /// Not wrapped by `libbtrfsutil`
+    fn default_subvolume(&self, path: &Path) -> io::Result<u64> {
+        let cpath = cpath(path)?;
+        let mut id = 0;
+        let errcode =
+            unsafe { libbtrfsutil_sys::btrfs_util_get_default_subvolume(cpath.as_ptr(), &mut id) };
+        if errcode == libbtrfsutil_sys::btrfs_util_error::BTRFS_UTIL_OK {
+            Ok(id)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Not wrapped by `libbtrfsutil`, needs a kernel reporting the generation
+    /// in `BTRFS_IOC_FS_INFO`
+    fn generation(&self, path: &Path) -> io::Result<u64> {
+        let args = fs_info(path, BTRFS_FS_INFO_FLAG_GENERATION)?;
+        if args.flags & BTRFS_FS_INFO_FLAG_GENERATION == 0 {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+        Ok(args.generation)
+    }
+
+    fn top_level_path(&self, path: &Path) -> io::Result<PathBuf> {
+        let fsid = fs_info(path, 0)?.fsid;
+
+        let f = fs::File::open("/proc/self/mounts")?;
+        let entries = MntEntries::new(io::BufReader::new(f));
+        let mut device = None;
+        for entry in entries.flatten() {
+            if entry.fs_type != "btrfs" {
+                continue;
+            }
+            let Some(mnt_path) = entry.target else {
+                continue;
+            };
+            if !fs_info(&mnt_path, 0).is_ok_and(|args| args.fsid == fsid) {
+                continue;
+            }
+            if subvol_id_from_mnt_options(&entry.options) == Some(libbtrfsutil::FS_TREE_OBJECTID) {
+                return Ok(mnt_path);
+            }
+            device = Some(entry.spec);
+        }
+        let device = device.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{}' is not on a mounted device", path.display()),
+            )
+        })?;
+        mount_top_level(&device, Uuid::from_bytes(fsid))
+    }
+
+    fn nested_subvolumes(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let iter = libbtrfsutil::IterateSubvolume::new(path)
+            .iter_with_id()
+            .map_err(|e| e.os_error())?;
+        Ok(iter.flatten().map(|(p, _)| path.join(p)).collect())
+    }
+
+    /// Not wrapped by `libbtrfsutil` with qgroup inheritance
+    fn create_subvolume(&self, path: &Path, qgroups: &[u64]) -> io::Result<()> {
+        use libbtrfsutil_sys::{btrfs_util_error::BTRFS_UTIL_OK, btrfs_util_qgroup_inherit};
+
+        let cpath = cpath(path)?;
+        let mut inherit: *mut btrfs_util_qgroup_inherit = std::ptr::null_mut();
+        let mut errcode = BTRFS_UTIL_OK;
+        if !qgroups.is_empty() {
+            errcode =
+                unsafe { libbtrfsutil_sys::btrfs_util_create_qgroup_inherit(0, &mut inherit) };
+            for qgroup in qgroups {
+                if errcode != BTRFS_UTIL_OK {
+                    break;
+                }
+                errcode = unsafe {
+                    libbtrfsutil_sys::btrfs_util_qgroup_inherit_add_group(&mut inherit, *qgroup)
+                };
+            }
+        }
+        if errcode == BTRFS_UTIL_OK {
+            errcode = unsafe {
+                libbtrfsutil_sys::btrfs_util_create_subvolume(
+                    cpath.as_ptr(),
+                    0,
+                    std::ptr::null_mut(),
+                    inherit,
+                )
+            };
+        }
+        // capture errno before freeing anything
+        let err = io::Error::last_os_error();
+        if !inherit.is_null() {
+            unsafe { libbtrfsutil_sys::btrfs_util_destroy_qgroup_inherit(inherit) };
+        }
+        if errcode == BTRFS_UTIL_OK {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    fn create_snapshot(&self, src: &Path, dst: &Path, recursive: bool) -> io::Result<()> {
+        libbtrfsutil::CreateSnapshotOptions::new()
+            .recursive(recursive)
+            .create(src, dst)
+            .map_err(|e| e.os_error())
+    }
+
+    fn delete_subvolume(&self, path: &Path) -> io::Result<()> {
+        libbtrfsutil::DeleteSubvolumeOptions::new()
+            .recursive(true)
+            .delete(path)
+            .map_err(|e| e.os_error())
+    }
+
+    fn set_read_only(&self, path: &Path, read_only: bool) -> io::Result<()> {
+        libbtrfsutil::set_subvolume_read_only(path, read_only).map_err(|e| e.os_error())
+    }
+
+    fn rename_subvolume(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        fs::rename(src, dst)
+    }
+
+    fn exchange(&self, a: &Path, b: &Path) -> io::Result<()> {
+        let a = cpath(a)?;
+        let b = cpath(b)?;
+        let ret = unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                a.as_ptr(),
+                libc::AT_FDCWD,
+                b.as_ptr(),
+                libc::RENAME_EXCHANGE,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn clone_file(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let src_file = fs::File::open(src)?;
+        let dst_file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dst)?;
+        // nodatacow only sticks to empty files, and cloning between nodatacow
+        // and datacow files fails
+        let res = match inode_flags(&src_file) {
+            Ok(flags) if flags & FS_NOCOW_FL != 0 => set_inode_flags(&dst_file, FS_NOCOW_FL),
+            _ => Ok(()),
+        };
+        if let Err(err) = res {
+            drop(dst_file);
+            let _ = fs::remove_file(dst);
+            return Err(err);
+        }
+        let ret = unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            drop(dst_file);
+            let _ = fs::remove_file(dst);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn compression(&self, path: &Path) -> io::Result<String> {
+        let cpath = cpath(path)?;
+        let mut buf = [0u8; 64];
+        let len = unsafe {
+            libc::getxattr(
+                cpath.as_ptr(),
+                COMPRESSION_XATTR.as_ptr(),
+                buf.as_mut_ptr().cast(),
+                buf.len(),
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENODATA) {
+                return Ok(String::new());
+            }
+            return Err(err);
+        }
+        Ok(String::from_utf8_lossy(&buf[..len as usize]).into_owned())
+    }
+
+    fn set_compression(&self, path: &Path, value: &str) -> io::Result<()> {
+        let cpath = cpath(path)?;
+        let ret = if value.is_empty() {
+            let ret = unsafe { libc::removexattr(cpath.as_ptr(), COMPRESSION_XATTR.as_ptr()) };
+            if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ENODATA) {
+                return Ok(());
+            }
+            ret
+        } else {
+            unsafe {
+                libc::setxattr(
+                    cpath.as_ptr(),
+                    COMPRESSION_XATTR.as_ptr(),
+                    value.as_ptr().cast(),
+                    value.len(),
+                    0,
+                )
+            }
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn is_nodatacow(&self, path: &Path) -> io::Result<bool> {
+        let f = fs::File::open(path)?;
+        Ok(inode_flags(&f)? & FS_NOCOW_FL != 0)
+    }
+
+    fn set_nodatacow(&self, path: &Path, nodatacow: bool) -> io::Result<()> {
+        let f = fs::File::open(path)?;
+        let mut flags = inode_flags(&f)?;
+        if nodatacow {
+            flags |= FS_NOCOW_FL;
+        } else {
+            flags &= !FS_NOCOW_FL;
+        }
+        set_inode_flags(&f, flags)
+    }
+
+    fn copy_inode_flags(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let flags = match inode_flags(&fs::File::open(src)?) {
+            Ok(flags) => flags,
+            // no flags to copy on filesystems without them
+            Err(err) if matches!(err.raw_os_error(), Some(libc::ENOTTY | libc::EOPNOTSUPP)) => {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+        let f = fs::File::open(dst)?;
+        if inode_flags(&f)? == flags {
+            return Ok(());
+        }
+        set_inode_flags(&f, flags)
+    }
+
+    fn label(&self, path: &Path) -> io::Result<String> {
+        let f = fs::File::open(path)?;
+        let mut buf = [0u8; BTRFS_LABEL_SIZE];
+        let ret = unsafe { libc::ioctl(f.as_raw_fd(), BTRFS_IOC_GET_FSLABEL as _, &mut buf) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        Ok(String::from_utf8_lossy(&buf[..len]).into_owned())
+    }
+
+    fn set_label(&self, path: &Path, label: &str) -> io::Result<()> {
+        if label.len() > BTRFS_LABEL_MAX || label.contains('\0') {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let f = fs::File::open(path)?;
+        let mut buf = [0u8; BTRFS_LABEL_SIZE];
+        buf[..label.len()].copy_from_slice(label.as_bytes());
+        let ret = unsafe { libc::ioctl(f.as_raw_fd(), BTRFS_IOC_SET_FSLABEL as _, &buf) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn space(&self, path: &Path) -> io::Result<FilesystemSpace> {
+        let cpath = cpath(path)?;
+        // safety: all zeros is a valid value of a plain C struct
+        let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+        let ret = unsafe { libc::statvfs(cpath.as_ptr(), &mut buf) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(FilesystemSpace {
+            total_bytes: buf.f_blocks as u64 * buf.f_frsize as u64,
+            free_bytes: buf.f_bavail as u64 * buf.f_frsize as u64,
+        })
+    }
+
+    /// From sysfs, which lists qgroups since Linux 5.9
+    fn exclusive_bytes(&self, path: &Path) -> io::Result<Option<u64>> {
+        let fsid = Uuid::from_bytes(fs_info(path, 0)?.fsid);
+        let id = self.subvolume_info(path)?.id;
+        let file = format!("/sys/fs/btrfs/{}/qgroups/0_{}/exclusive", fsid, id);
+        match fs::read_to_string(file) {
+            Ok(s) => s
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidData)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
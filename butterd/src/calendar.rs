@@ -0,0 +1,328 @@
+use std::str::FromStr;
+
+use chrono::{Datelike, Days, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+
+/// Days searched for the next match, enough for leap days on a given weekday
+const SEARCH_DAYS: u64 = 366 * 29;
+/// Largest year expanded from a repetition such as `2024/2`
+const MAX_YEAR: u32 = 2199;
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Values one component of a calendar event matches, `None` matching all
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Field(Option<Vec<u32>>);
+
+impl Field {
+    fn any() -> Self {
+        Self(None)
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        match &self.0 {
+            Some(v) => v.contains(&value),
+            None => true,
+        }
+    }
+
+    /// Parse `*`, `a`, `a..b`, `a/n` and `*/n` separated by commas
+    fn parse(s: &str, min: u32, max: u32) -> Result<Self, String> {
+        if s == "*" {
+            return Ok(Self::any());
+        }
+        let invalid = || format!("invalid value '{}'", s);
+        let num = |s: &str| -> Result<u32, String> {
+            let n: u32 = s.parse().map_err(|_| invalid())?;
+            if n < min || n > max {
+                return Err(invalid());
+            }
+            Ok(n)
+        };
+        let mut values = Vec::new();
+        for part in s.split(',') {
+            if let Some((start, step)) = part.split_once('/') {
+                let start = if start == "*" { min } else { num(start)? };
+                let step: u32 = step.parse().map_err(|_| invalid())?;
+                if step == 0 {
+                    return Err(invalid());
+                }
+                values.extend((start..=max).step_by(step as usize));
+            } else if let Some((start, end)) = part.split_once("..") {
+                let (start, end) = (num(start)?, num(end)?);
+                if start > end {
+                    return Err(invalid());
+                }
+                values.extend(start..=end);
+            } else {
+                values.push(num(part)?);
+            }
+        }
+        values.sort_unstable();
+        values.dedup();
+        Ok(Self(Some(values)))
+    }
+
+    /// Matching values from `min` to `max` in ascending order
+    fn values(&self, min: u32, max: u32) -> Vec<u32> {
+        match &self.0 {
+            Some(v) => v
+                .iter()
+                .copied()
+                .filter(|n| (min..=max).contains(n))
+                .collect(),
+            None => (min..=max).collect(),
+        }
+    }
+}
+
+fn parse_weekday(s: &str) -> Result<u32, String> {
+    let lower = s.to_ascii_lowercase();
+    WEEKDAYS
+        .iter()
+        .position(|d| {
+            lower.len() >= 3 && lower.starts_with(d) && full_weekday(d).starts_with(&lower)
+        })
+        .map(|i| i as u32)
+        .ok_or_else(|| format!("invalid weekday '{}'", s))
+}
+
+fn full_weekday(short: &str) -> &'static str {
+    match short {
+        "mon" => "monday",
+        "tue" => "tuesday",
+        "wed" => "wednesday",
+        "thu" => "thursday",
+        "fri" => "friday",
+        "sat" => "saturday",
+        _ => "sunday",
+    }
+}
+
+/// A calendar event in the `OnCalendar=` syntax of systemd timers, evaluated
+/// in local time.
+///
+/// Supports the shorthands such as `hourly` and
+/// `[Weekdays] [[Year-]Month-Day] [Hour:Minute[:Second]]` with `*`, lists,
+/// ranges and repetitions, but not time zones or `~` for the last days of a
+/// month.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CalendarSpec {
+    /// 0 is Monday
+    weekdays: Field,
+    years: Field,
+    months: Field,
+    days: Field,
+    hours: Field,
+    minutes: Field,
+    seconds: Field,
+}
+
+impl FromStr for CalendarSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let expanded = match s.to_ascii_lowercase().as_str() {
+            "minutely" => "*-*-* *:*:00",
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            "monthly" => "*-*-01 00:00:00",
+            "quarterly" => "*-01,04,07,10-01 00:00:00",
+            "semiannually" => "*-01,07-01 00:00:00",
+            "yearly" | "annually" => "*-01-01 00:00:00",
+            _ => s,
+        };
+
+        let mut ret = Self {
+            weekdays: Field::any(),
+            years: Field::any(),
+            months: Field::any(),
+            days: Field::any(),
+            hours: Field(Some(vec![0])),
+            minutes: Field(Some(vec![0])),
+            seconds: Field(Some(vec![0])),
+        };
+        let mut tokens = expanded.split_whitespace().peekable();
+        if tokens.peek().is_none() {
+            return Err("empty calendar event".to_owned());
+        }
+
+        if let Some(token) = tokens.next_if(|t| t.starts_with(|c: char| c.is_ascii_alphabetic())) {
+            let mut weekdays = Vec::new();
+            for part in token.split(',') {
+                match part.split_once("..") {
+                    Some((start, end)) => {
+                        let (start, end) = (parse_weekday(start)?, parse_weekday(end)?);
+                        if start > end {
+                            return Err(format!("invalid weekdays '{}'", token));
+                        }
+                        weekdays.extend(start..=end);
+                    }
+                    None => weekdays.push(parse_weekday(part)?),
+                }
+            }
+            weekdays.sort_unstable();
+            weekdays.dedup();
+            ret.weekdays = Field(Some(weekdays));
+        }
+
+        if let Some(token) = tokens.next_if(|t| t.contains('-')) {
+            let parts: Vec<&str> = token.split('-').collect();
+            let (year, month, day) = match parts[..] {
+                [year, month, day] => (year, month, day),
+                [month, day] => ("*", month, day),
+                _ => return Err(format!("invalid date '{}'", token)),
+            };
+            ret.years = Field::parse(year, 1970, MAX_YEAR)?;
+            ret.months = Field::parse(month, 1, 12)?;
+            ret.days = Field::parse(day, 1, 31)?;
+        }
+
+        if let Some(token) = tokens.next_if(|t| t.contains(':')) {
+            let parts: Vec<&str> = token.split(':').collect();
+            let (hour, minute, second) = match parts[..] {
+                [hour, minute, second] => (hour, minute, second),
+                [hour, minute] => (hour, minute, "00"),
+                _ => return Err(format!("invalid time '{}'", token)),
+            };
+            ret.hours = Field::parse(hour, 0, 23)?;
+            ret.minutes = Field::parse(minute, 0, 59)?;
+            ret.seconds = Field::parse(second, 0, 59)?;
+        }
+
+        if let Some(token) = tokens.next() {
+            return Err(format!("unsupported '{}'", token));
+        }
+        Ok(ret)
+    }
+}
+
+impl CalendarSpec {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        self.weekdays.matches(date.weekday().num_days_from_monday())
+            && date.year() >= 0
+            && self.years.matches(date.year() as u32)
+            && self.months.matches(date.month())
+            && self.days.matches(date.day())
+    }
+
+    /// Earliest time of day matching, at or after `from`
+    fn first_time(&self, from: NaiveTime) -> Option<NaiveTime> {
+        for hour in self.hours.values(from.hour(), 23) {
+            let min_minute = if hour == from.hour() {
+                from.minute()
+            } else {
+                0
+            };
+            for minute in self.minutes.values(min_minute, 59) {
+                let min_second = if hour == from.hour() && minute == from.minute() {
+                    from.second()
+                } else {
+                    0
+                };
+                if let Some(second) = self.seconds.values(min_second, 59).first() {
+                    return NaiveTime::from_hms_opt(hour, minute, *second);
+                }
+            }
+        }
+        None
+    }
+
+    /// The first time the event elapses strictly after `after`
+    pub fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        // whole seconds only
+        let from = after.with_nanosecond(0)? + chrono::Duration::seconds(1);
+        let mut date = from.date();
+        let mut time = from.time();
+        for _ in 0..SEARCH_DAYS {
+            if self.matches_date(date) {
+                if let Some(t) = self.first_time(time) {
+                    return Some(date.and_time(t));
+                }
+            }
+            date = date.checked_add_days(Days::new(1))?;
+            time = NaiveTime::MIN;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn next(spec: &str, after: &str) -> NaiveDateTime {
+        spec.parse::<CalendarSpec>()
+            .unwrap()
+            .next_after(at(after))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_shorthands() {
+        assert_eq!(
+            next("hourly", "2024-03-10 10:00:00"),
+            at("2024-03-10 11:00:00")
+        );
+        assert_eq!(
+            next("daily", "2024-12-31 23:59:59"),
+            at("2025-01-01 00:00:00")
+        );
+        // 2024-03-10 is a Sunday
+        assert_eq!(
+            next("weekly", "2024-03-10 10:00:00"),
+            at("2024-03-11 00:00:00")
+        );
+        assert_eq!(
+            next("monthly", "2024-03-10 10:00:00"),
+            at("2024-04-01 00:00:00")
+        );
+    }
+
+    #[test]
+    fn test_full_syntax() {
+        assert_eq!(
+            next("*:0/15", "2024-03-10 10:07:00"),
+            at("2024-03-10 10:15:00")
+        );
+        assert_eq!(
+            next("*:0/15", "2024-03-10 10:45:00"),
+            at("2024-03-10 11:00:00")
+        );
+        assert_eq!(
+            next("Mon..Fri 09:30", "2024-03-08 10:00:00"),
+            at("2024-03-11 09:30:00")
+        );
+        assert_eq!(
+            next("Sat,Sun *-*-* 03:00:00", "2024-03-08 10:00:00"),
+            at("2024-03-09 03:00:00")
+        );
+        assert_eq!(
+            next("02-29 12:00", "2024-03-01 00:00:00"),
+            at("2028-02-29 12:00:00")
+        );
+        assert_eq!(
+            next("2030-01-01", "2024-03-01 00:00:00"),
+            at("2030-01-01 00:00:00")
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        for spec in [
+            "",
+            "*:60",
+            "Funday",
+            "*-13-01",
+            "12:00 UTC",
+            "*-*-1~1",
+            "*:*/0",
+        ] {
+            assert!(spec.parse::<CalendarSpec>().is_err(), "{}", spec);
+        }
+    }
+}
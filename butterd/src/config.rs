@@ -4,5 +4,13 @@ pub const APP_VERSION: &str = "1.1";
 pub const GETTEXT_PACKAGE: &str = "butter";
 pub const LOCALEDIR: &str = "/usr/share/locale";
 pub const GRESOURCE_FILE: &str = concat!("/usr/share/butter", "/resources.gresource");
+pub const BINDIR: &str = "/usr/bin";
 pub const PKGSYSCONFDIR: &str = "/etc/butter";
 pub const SCHEDULE_DIR: &str = concat!("/etc/butter", "/schedules");
+pub const DAEMON_CONFIG: &str = concat!("/etc/butter", "/butterd.conf");
+pub const USER_SNAPSHOT_ALLOWLIST: &str = concat!("/etc/butter", "/user-snapshots.json");
+pub const PKGLOGDIR: &str = "/var/log/butter";
+pub const AUDIT_LOG: &str = concat!("/var/log/butter", "/audit.log");
+pub const PKGSHAREDSTATEDIR: &str = "/var/lib/butter";
+pub const SCHEDULER_STATE: &str = concat!("/var/lib/butter", "/scheduler.json");
+pub const RULE_HISTORY_DIR: &str = concat!("/var/lib/butter", "/history");
@@ -0,0 +1,263 @@
+use std::{
+    ffi::CString,
+    fs::{self, File, FileTimes, Metadata},
+    io::{self, BufRead, BufReader},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use tracing::warn;
+
+use crate::BtrfsBackend;
+
+fn invalid(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{}' {}", path.display(), reason),
+    )
+}
+
+/// Where the contents of `path` are copied to before being swapped in
+fn staging_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| invalid(path, "has no name"))?;
+    let mut staging = std::ffi::OsString::from(".");
+    staging.push(name);
+    staging.push(".butter-convert");
+    Ok(path.with_file_name(staging))
+}
+
+/// Call `f` with a buffer until it is large enough for the value whose size
+/// `f` returns when given an empty buffer, like `getxattr(2)`
+fn read_sized(f: impl Fn(*mut libc::c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = f(buf.as_mut_ptr().cast(), buf.len());
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            // grown in between
+            if err.raw_os_error() == Some(libc::ERANGE) {
+                continue;
+            }
+            return Err(err);
+        }
+        buf.truncate(len as usize);
+        return Ok(buf);
+    }
+}
+
+/// Extended attributes of `path` sorted by name, including POSIX ACLs,
+/// without following symlinks
+fn xattrs(path: &Path) -> io::Result<Vec<(CString, Vec<u8>)>> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let names =
+        match read_sized(|buf, len| unsafe { libc::llistxattr(cpath.as_ptr(), buf.cast(), len) }) {
+            Ok(names) => names,
+            Err(err) if err.raw_os_error() == Some(libc::EOPNOTSUPP) => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+    let mut ret = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+        let value = read_sized(|buf, len| unsafe {
+            libc::lgetxattr(cpath.as_ptr(), name.as_ptr(), buf, len)
+        })?;
+        ret.push((name, value));
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+fn copy_xattrs(src: &Path, dst: &Path) -> io::Result<()> {
+    let cpath = CString::new(dst.as_os_str().as_bytes())?;
+    for (name, value) in xattrs(src)? {
+        let ret = unsafe {
+            libc::lsetxattr(
+                cpath.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Give `dst` the owner, extended attributes, mode, timestamps and inode
+/// flags of `src`
+fn copy_metadata(
+    backend: &dyn BtrfsBackend,
+    src: &Path,
+    meta: &Metadata,
+    dst: &Path,
+) -> io::Result<()> {
+    lchown(dst, Some(meta.uid()), Some(meta.gid()))?;
+    // after chown, which clears file capabilities
+    copy_xattrs(src, dst)?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode()))?;
+    let times = FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?);
+    File::open(dst)?.set_times(times)?;
+    // last, since an immutable file cannot be changed anymore
+    backend.copy_inode_flags(src, dst)
+}
+
+/// `true` if the files at `a` and `b` have the same contents
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let (x, y) = (a.fill_buf()?, b.fill_buf()?);
+        if x.is_empty() || y.is_empty() {
+            return Ok(x.is_empty() && y.is_empty());
+        }
+        let len = x.len().min(y.len());
+        if x[..len] != y[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// Reflink the contents of the directory `src` into the existing directory
+/// `dst`, refusing anything that is not on the same subvolume
+fn copy_tree(backend: &dyn BtrfsBackend, src: &Path, dst: &Path, dev: u64) -> io::Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let from = entry.path();
+        let to = dst.join(entry.file_name());
+        let meta = fs::symlink_metadata(&from)?;
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            if meta.dev() != dev || backend.is_subvolume(&from)? {
+                return Err(invalid(&from, "is a subvolume or mount point"));
+            }
+            fs::create_dir(&to)?;
+            copy_tree(backend, &from, &to, dev)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(fs::read_link(&from)?, &to)?;
+        } else if file_type.is_file() {
+            backend.clone_file(&from, &to)?;
+        } else {
+            return Err(invalid(&from, "is not a file, directory or symlink"));
+        }
+        // directories last so that filling them does not change their times
+        copy_metadata(backend, &from, &meta, &to)?;
+    }
+    Ok(())
+}
+
+/// Check that `dst` holds the same entries as `src` with the same type,
+/// contents, owner, mode and extended attributes
+fn verify_tree(src: &Path, dst: &Path) -> io::Result<()> {
+    let mismatch =
+        |path: &Path| io::Error::other(format!("copy of '{}' does not match", path.display()));
+    let mut src_names = fs::read_dir(src)?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut dst_names = fs::read_dir(dst)?
+        .map(|e| e.map(|e| e.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+    src_names.sort();
+    dst_names.sort();
+    if src_names != dst_names {
+        return Err(mismatch(src));
+    }
+    for name in src_names {
+        let from = src.join(&name);
+        let to = dst.join(&name);
+        let a = fs::symlink_metadata(&from)?;
+        let b = fs::symlink_metadata(&to)?;
+        if a.file_type() != b.file_type()
+            || a.uid() != b.uid()
+            || a.gid() != b.gid()
+            || a.mode() != b.mode()
+            || (a.is_file() && a.len() != b.len())
+        {
+            return Err(mismatch(&from));
+        }
+        if a.file_type().is_symlink() && fs::read_link(&from)? != fs::read_link(&to)? {
+            return Err(mismatch(&from));
+        }
+        if a.is_file() && !same_contents(&from, &to)? {
+            return Err(mismatch(&from));
+        }
+        if xattrs(&from)? != xattrs(&to)? {
+            return Err(mismatch(&from));
+        }
+        if a.is_dir() {
+            verify_tree(&from, &to)?;
+        }
+    }
+    Ok(())
+}
+
+/// Turn the directory at `path` into a subvolume with the same contents.
+///
+/// The contents are reflinked into a new subvolume next to it, which is
+/// swapped in atomically once the copy matches the original. The original is
+/// only deleted after that, which may leave it behind at the staging path if
+/// that fails. Extended attributes, POSIX ACLs and inode flags
+/// are kept. Files changed while converting may lose those changes, and hard
+/// links are copied as separate files.
+pub fn convert_to_subvolume(backend: &dyn BtrfsBackend, path: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Err(invalid(path, "is not a directory"));
+    }
+    if backend.is_subvolume(path)? {
+        return Err(invalid(path, "is already a subvolume"));
+    }
+    let parent = path
+        .parent()
+        .ok_or_else(|| invalid(path, "has no parent"))?;
+    if fs::metadata(parent)?.dev() != meta.dev() {
+        return Err(invalid(path, "is a mount point"));
+    }
+    let staging = staging_path(path)?;
+    if staging.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", staging.display()),
+        ));
+    }
+
+    backend.create_subvolume(&staging, &[])?;
+    let res = copy_tree(backend, path, &staging, meta.dev())
+        .and_then(|_| copy_metadata(backend, path, &meta, &staging))
+        .and_then(|_| verify_tree(path, &staging));
+    if let Err(err) = res {
+        let _ = backend.delete_subvolume(&staging);
+        return Err(err);
+    }
+
+    backend.exchange(path, &staging)?;
+    // the original directory is now at the staging path. `path` is converted
+    // regardless, e.g. immutable files only make this leave some behind.
+    if let Err(err) = fs::remove_dir_all(&staging) {
+        warn!(
+            "failed to delete the original of '{}' at '{}': {}",
+            path.display(),
+            staging.display(),
+            err
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use zbus::{fdo, interface, message::Header, zvariant, zvariant::ObjectPath};
+
+use crate::{config, AuditLog, Polkit, ToFdo};
+
+/// Settings of butterd read from [`config::DAEMON_CONFIG`]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, zvariant::Type)]
+#[serde(default)]
+pub struct DaemonConfig {
+    /// exit after being idle for this long
+    pub idle_timeout_secs: u64,
+    /// paths that are considered primary subvolumes when mounted there.
+    ///
+    /// `*` matches exactly one path component.
+    pub protected_paths: Vec<String>,
+    /// default location for manually created snapshots
+    pub snapshot_dir: PathBuf,
+    pub schedule_dir: PathBuf,
+    /// delete subvolumes moved aside by restoring a snapshot once they are
+    /// older than this, 0 keeps them forever
+    pub trash_retention_secs: u64,
+    /// what runs the rules when the schedule is enabled
+    pub scheduler: SchedulerKind,
+    /// one of `error`, `warn`, `info`, `debug` and `trace`.
+    ///
+    /// Only applied on startup.
+    pub log_level: String,
+}
+
+/// Runs the rules on their schedule
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, zvariant::Type)]
+#[serde(rename_all = "lowercase")]
+#[zvariant(signature = "s")]
+pub enum SchedulerKind {
+    /// timers and services of systemd
+    #[default]
+    Systemd,
+    /// butterd itself, which then stays running while the schedule is
+    /// enabled. For systems without systemd.
+    Builtin,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: 15 * 60,
+            // Some hardcoded paths that are extremly often to be their own subvolume
+            // and important for the system.
+            protected_paths: [
+                "/",
+                "/boot",
+                "/home",
+                "/mnt",
+                "/opt",
+                "/root",
+                "/srv",
+                "/tmp",
+                "/usr",
+                "/usr/local",
+                "/var",
+                "/var/lib",
+                "/var/lib/machines",
+                "/var/log",
+            ]
+            .map(String::from)
+            .to_vec(),
+            snapshot_dir: PathBuf::from("/var/snapshots"),
+            schedule_dir: PathBuf::from(config::SCHEDULE_DIR),
+            trash_retention_secs: 30 * 24 * 60 * 60,
+            scheduler: SchedulerKind::default(),
+            log_level: "info".to_owned(),
+        }
+    }
+}
+
+/// `None` until loaded
+static CURRENT: RwLock<Option<Arc<DaemonConfig>>> = RwLock::new(None);
+
+impl DaemonConfig {
+    /// Read the config file, falling back to defaults if it does not exist
+    pub fn read() -> io::Result<Self> {
+        match fs::read(config::DAEMON_CONFIG) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Read the config file and make it current, falling back to defaults
+    /// if it is invalid.
+    ///
+    /// Call once logging is set up, so that failures are reported.
+    pub fn load() -> Arc<Self> {
+        let next = Arc::new(Self::read().unwrap_or_else(|err| {
+            warn!("failed to read {}: {}", config::DAEMON_CONFIG, err);
+            Self::default()
+        }));
+        *CURRENT.write().unwrap() = Some(next.clone());
+        next
+    }
+
+    /// The config currently in effect, loaded on first use if not yet
+    pub fn current() -> Arc<Self> {
+        if let Some(cfg) = CURRENT.read().unwrap().as_ref() {
+            return cfg.clone();
+        }
+        Self::load()
+    }
+
+    /// Re-read the config file and make it current
+    pub fn reload() -> io::Result<Arc<Self>> {
+        let next = Arc::new(Self::read()?);
+        *CURRENT.write().unwrap() = Some(next.clone());
+        info!("reloaded {}", config::DAEMON_CONFIG);
+        Ok(next)
+    }
+
+    pub fn is_protected_path(&self, path: &Path) -> bool {
+        self.protected_paths
+            .iter()
+            .any(|pattern| path_matches(Path::new(pattern), path))
+    }
+}
+
+fn path_matches(pattern: &Path, path: &Path) -> bool {
+    let mut pattern = pattern.components();
+    let mut path = path.components();
+    loop {
+        match (pattern.next(), path.next()) {
+            (None, None) => return true,
+            (Some(Component::Normal(p)), Some(Component::Normal(_))) if p == "*" => continue,
+            (Some(p), Some(c)) if p == c => continue,
+            _ => return false,
+        }
+    }
+}
+
+pub struct Daemon {
+    polkit: Polkit,
+    audit: AuditLog,
+}
+
+static ACTION_ID: &str = "org.zhangyuannie.butter.manage-daemon";
+
+impl Daemon {
+    pub const PATH: ObjectPath<'static> =
+        ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/Daemon");
+
+    pub fn new(polkit: Polkit, audit: AuditLog) -> Self {
+        Self { polkit, audit }
+    }
+
+    async fn reload_config_impl(&self, header: &Header<'_>) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        DaemonConfig::reload().to_fdo()?;
+        Ok(())
+    }
+}
+
+#[interface(
+    name = "org.zhangyuannie.Butter1.Daemon",
+    proxy(
+        gen_blocking = true,
+        default_service = "org.zhangyuannie.Butter1",
+        default_path = "/org/zhangyuannie/Butter1/Daemon",
+    )
+)]
+impl Daemon {
+    fn config(&self) -> DaemonConfig {
+        DaemonConfig::current().as_ref().clone()
+    }
+
+    async fn reload_config(&self, #[zbus(header)] header: Header<'_>) -> fdo::Result<()> {
+        let res = self.reload_config_impl(&header).await;
+        self.audit
+            .record(&header, "Daemon.ReloadConfig", Vec::new(), &res)
+            .await;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches(Path::new("/"), Path::new("/")));
+        assert!(path_matches(Path::new("/home"), Path::new("/home/")));
+        assert!(!path_matches(Path::new("/home"), Path::new("/home/foo")));
+        assert!(!path_matches(Path::new("/home/foo"), Path::new("/home")));
+        assert!(path_matches(Path::new("/home/*"), Path::new("/home/foo")));
+        assert!(!path_matches(Path::new("/home/*"), Path::new("/home")));
+        assert!(!path_matches(
+            Path::new("/home/*"),
+            Path::new("/home/foo/bar")
+        ));
+        assert!(path_matches(Path::new("/*/lib"), Path::new("/var/lib")));
+    }
+}
@@ -1,14 +1,19 @@
 use std::{
     cell::{OnceCell, RefCell},
+    cmp,
     collections::HashMap,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
 };
 
 use anyhow::Context;
 use uuid::Uuid;
 use zbus::{interface, zvariant::ObjectPath};
 
-use crate::{Subvolume, ToFdo, ZPathBuf, ZUuid};
+use crate::{
+    read_metadata_store, BtrfsBackend, ProbedFilesystem, SnapshotMetadata, Subvolume,
+    SubvolumeDelta, SubvolumeFilter, SubvolumeInfo, ToFdo, ZPathBuf, ZUuid,
+};
 
 pub struct Filesystem {
     pub(crate) uuid: ZUuid,
@@ -16,9 +21,139 @@ pub struct Filesystem {
     /// Must be Sorted
     pub(crate) devices: Vec<ZPathBuf>,
     pub(crate) mount_points_by_subvol_id: HashMap<u64, Vec<ZPathBuf>>,
+    pub(crate) backend: Arc<dyn BtrfsBackend>,
+    cache: Mutex<SubvolumeCache>,
+}
+
+/// Removed subvolumes remembered for deltas
+const MAX_REMOVED: usize = 4096;
+
+/// Subvolume listing kept until the filesystem generation changes
+struct SubvolumeCache {
+    /// random, so that generations of another listing are not mistaken for
+    /// ones of this listing
+    epoch: u64,
+    /// filesystem generation the listing was built at, `None` if it must be rebuilt
+    fs_generation: Option<u64>,
+    /// bumped whenever the listing changes, never lower than `fs_generation`
+    generation: u64,
+    /// oldest generation deltas can be computed from
+    since: u64,
+    /// subvolume and the generation it last changed at, by UUID
+    subvols: HashMap<Uuid, (u64, Subvolume)>,
+    /// generation each subvolume was removed at, by UUID
+    removed: HashMap<Uuid, u64>,
+}
+
+impl Default for SubvolumeCache {
+    fn default() -> Self {
+        Self {
+            epoch: Uuid::new_v4().as_u128() as u64,
+            fs_generation: None,
+            generation: 0,
+            since: 0,
+            subvols: HashMap::new(),
+            removed: HashMap::new(),
+        }
+    }
+}
+
+impl SubvolumeCache {
+    fn apply(&mut self, fs_generation: Option<u64>, subvols: Vec<Subvolume>) {
+        let next = cmp::max(fs_generation.unwrap_or_default(), self.generation + 1);
+        let is_fresh = self.subvols.is_empty();
+        let mut changed = false;
+
+        let mut prev = std::mem::take(&mut self.subvols);
+        for subvol in subvols {
+            let uuid = *subvol.uuid.as_uuid();
+            let changed_at = match prev.remove(&uuid) {
+                Some((changed_at, old)) if old == subvol => changed_at,
+                _ => {
+                    changed = true;
+                    next
+                }
+            };
+            self.removed.remove(&uuid);
+            self.subvols.insert(uuid, (changed_at, subvol));
+        }
+        for uuid in prev.into_keys() {
+            changed = true;
+            self.removed.insert(uuid, next);
+        }
+
+        if self.removed.len() > MAX_REMOVED {
+            let mut removed_at: Vec<u64> = self.removed.values().copied().collect();
+            removed_at.sort_unstable();
+            let cutoff = removed_at[removed_at.len() - MAX_REMOVED];
+            self.removed.retain(|_, g| *g >= cutoff);
+            self.since = cmp::max(self.since, cutoff);
+        }
+
+        if changed {
+            self.generation = next;
+        }
+        if is_fresh {
+            self.since = self.generation;
+        }
+        self.fs_generation = fs_generation;
+    }
+
+    fn subvolumes(&self) -> Vec<Subvolume> {
+        self.subvols
+            .values()
+            .map(|(_, subvol)| subvol.clone())
+            .collect()
+    }
+
+    fn delta(&self, epoch: u64, since: u64) -> SubvolumeDelta {
+        if epoch != self.epoch || since < self.since || since > self.generation {
+            return SubvolumeDelta {
+                epoch: self.epoch,
+                generation: self.generation,
+                is_full: true,
+                changed: self.subvolumes(),
+                removed: Vec::new(),
+            };
+        }
+        SubvolumeDelta {
+            epoch: self.epoch,
+            generation: self.generation,
+            is_full: false,
+            changed: self
+                .subvols
+                .values()
+                .filter(|(changed_at, _)| *changed_at > since)
+                .map(|(_, subvol)| subvol.clone())
+                .collect(),
+            removed: self
+                .removed
+                .iter()
+                .filter(|(_, removed_at)| **removed_at > since)
+                .map(|(uuid, _)| (*uuid).into())
+                .collect(),
+        }
+    }
 }
 
 impl Filesystem {
+    pub(crate) fn new(probed: ProbedFilesystem, backend: Arc<dyn BtrfsBackend>) -> Self {
+        let mut devices: Vec<ZPathBuf> = probed.devices.into_iter().map(Into::into).collect();
+        devices.sort_unstable();
+        Self {
+            uuid: probed.uuid.into(),
+            label: probed.label,
+            devices,
+            mount_points_by_subvol_id: probed
+                .mount_points_by_subvol_id
+                .into_iter()
+                .map(|(id, paths)| (id, paths.into_iter().map(Into::into).collect()))
+                .collect(),
+            backend,
+            cache: Default::default(),
+        }
+    }
+
     pub(crate) async fn update(
         self,
         server: &zbus::ObjectServer,
@@ -41,6 +176,15 @@ impl Filesystem {
             iface.devices_changed(iface_ref.signal_context()).await?;
         }
 
+        if iface.mount_points_by_subvol_id != self.mount_points_by_subvol_id {
+            iface.mount_points_by_subvol_id = self.mount_points_by_subvol_id;
+            // paths of every subvolume may have changed without a new generation
+            let mut cache = iface.cache.lock().unwrap();
+            cache.subvols.clear();
+            cache.removed.clear();
+            cache.fs_generation = None;
+        }
+
         Ok(())
     }
 
@@ -53,9 +197,96 @@ impl Filesystem {
         Ok(())
     }
 
+    /// `true` if `path` is under one of the mount points of this filesystem
+    pub(crate) fn contains_path(&self, path: &Path) -> bool {
+        self.mount_points_by_subvol_id
+            .values()
+            .flatten()
+            .any(|mnt| path.starts_with(mnt.as_path()))
+    }
+
+    /// Find the subvolume whose root is exactly at `path`.
+    ///
+    /// Returns the subvolume and whether it is the default subvolume.
+    pub(crate) fn subvolume_at(&self, path: &Path) -> anyhow::Result<Option<(Subvolume, bool)>> {
+        if !self.contains_path(path) || !self.backend.is_subvolume(path).unwrap_or(false) {
+            return Ok(None);
+        }
+        let uuid = self
+            .backend
+            .subvolume_info(path)
+            .context("failed to get subvol info")?
+            .uuid;
+        let default_id = self
+            .backend
+            .default_subvolume(path)
+            .context("failed to get default subvol")?;
+
+        Ok(self
+            .subvolume_cache()?
+            .subvols
+            .get(&uuid)
+            .map(|(_, subvol)| (subvol.clone(), subvol.id == default_id)))
+    }
+
+    /// A path of the subvolume at `root_path` that is not a mount point and
+    /// thus can be moved away
+    pub(crate) fn movable_path(&self, root_path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let cache = self.subvolume_cache()?;
+        let Some((_, subvol)) = cache
+            .subvols
+            .values()
+            .find(|(_, subvol)| subvol.root_path.as_path() == root_path)
+        else {
+            return Ok(None);
+        };
+        Ok(subvol
+            .paths
+            .iter()
+            .find(|p| {
+                !self
+                    .mount_points_by_subvol_id
+                    .values()
+                    .flatten()
+                    .any(|mnt| mnt == *p)
+            })
+            .map(|p| p.as_path().to_owned()))
+    }
+
+    /// Every subvolume reachable through a mount point
+    pub(crate) fn subvolumes(&self) -> anyhow::Result<Vec<Subvolume>> {
+        Ok(self.subvolume_cache()?.subvolumes())
+    }
+
+    /// Rebuild the subvolume listing on the next request
+    pub(crate) fn invalidate_subvolumes(&self) {
+        self.cache.lock().unwrap().fs_generation = None;
+    }
+
+    /// An arbitary mount path of the filesystem
+    pub(crate) fn mnt_path(&self) -> anyhow::Result<&Path> {
+        Ok(self
+            .mount_points_by_subvol_id
+            .values()
+            .next()
+            .context("Filesystem must be mounted")?[0]
+            .as_path())
+    }
+
+    /// The cached subvolume listing, rebuilt if the filesystem has changed since
+    fn subvolume_cache(&self) -> anyhow::Result<MutexGuard<'_, SubvolumeCache>> {
+        let mut cache = self.cache.lock().unwrap();
+        // without a generation, the listing is rebuilt every time
+        let fs_generation = self.backend.generation(self.mnt_path()?).ok();
+        if fs_generation.is_none() || cache.fs_generation != fs_generation {
+            cache.apply(fs_generation, self.list_subvolumes_impl()?);
+        }
+        Ok(cache)
+    }
+
     fn list_subvolumes_impl(&self) -> anyhow::Result<Vec<Subvolume>> {
         struct PartialSubvol {
-            info: libbtrfsutil::SubvolumeInfo,
+            info: SubvolumeInfo,
             root_path: PathBuf,
             final_paths: OnceCell<Vec<ZPathBuf>>,
             start_paths: RefCell<Vec<ZPathBuf>>,
@@ -67,8 +298,8 @@ impl Filesystem {
 
                 if let Some((parent_root_path, parent_paths)) = self
                     .info
-                    .parent_id()
-                    .and_then(|parent_id| subvol_by_id.get(&parent_id.into()))
+                    .parent_id
+                    .and_then(|parent_id| subvol_by_id.get(&parent_id))
                     .map(|p| (&p.root_path, p.paths(subvol_by_id)))
                 {
                     if let Ok(relative_path) = self.root_path.strip_prefix(parent_root_path) {
@@ -92,27 +323,35 @@ impl Filesystem {
                 &self,
                 subvol_by_uuid: &HashMap<Uuid, &PartialSubvol>,
             ) -> Option<ZPathBuf> {
-                let created_from_uuid = self.info.parent_uuid()?;
+                let created_from_uuid = self.info.parent_uuid?;
                 subvol_by_uuid
                     .get(&created_from_uuid)
                     .map(|subvol| subvol.root_path.clone().into())
             }
+            fn group_uuid(
+                &self,
+                store: &HashMap<Uuid, SnapshotMetadata>,
+                paths: &[ZPathBuf],
+            ) -> Option<Uuid> {
+                self.info.parent_uuid?;
+                let metadata = SnapshotMetadata::read_from_store(
+                    store,
+                    paths.first()?.as_path(),
+                    self.info.uuid,
+                )?;
+                metadata.group.map(|group| group.uuid)
+            }
         }
 
-        // get an arbitary mount path of the filesystem
-        let mnt_path = self
-            .mount_points_by_subvol_id
-            .values()
-            .next()
-            .context("Filesystem must be mounted")?[0]
-            .as_path();
+        let mnt_path = self.mnt_path()?;
         let mut subvol_by_id = HashMap::new();
 
         // insert top level root subvolume
         {
-            let info =
-                libbtrfsutil::subvolume_info_with_id(mnt_path, libbtrfsutil::FS_TREE_OBJECTID)
-                    .context("failed to get top-level subvol info")?;
+            let info = self
+                .backend
+                .subvolume_info_with_id(mnt_path, libbtrfsutil::FS_TREE_OBJECTID)
+                .context("failed to get top-level subvol info")?;
             let paths = self
                 .mount_points_by_subvol_id
                 .get(&libbtrfsutil::FS_TREE_OBJECTID)
@@ -120,7 +359,7 @@ impl Filesystem {
                 .unwrap_or_default();
 
             subvol_by_id.insert(
-                info.id(),
+                info.id,
                 PartialSubvol {
                     is_mountpoint: !paths.is_empty(),
                     info,
@@ -133,12 +372,12 @@ impl Filesystem {
 
         // insert all other subvolumes
         {
-            let iter = libbtrfsutil::IterateSubvolume::new(mnt_path)
-                .all()
-                .iter_with_info()
+            let subvols = self
+                .backend
+                .list_subvolumes(mnt_path)
                 .context("failed to enumerate subvolumes")?;
-            for (root_path, info) in iter.flatten() {
-                let id = info.id();
+            for (root_path, info) in subvols {
+                let id = info.id;
                 let start_paths = self
                     .mount_points_by_subvol_id
                     .get(&id)
@@ -157,22 +396,38 @@ impl Filesystem {
             }
         }
 
+        // read once instead of for every snapshot
+        let store = read_metadata_store(self.backend.as_ref(), mnt_path);
+
         let subvol_by_uuid: HashMap<Uuid, &PartialSubvol> = subvol_by_id
             .values()
-            .map(|item| (item.info.uuid(), item))
+            .map(|item| (item.info.uuid, item))
             .collect();
 
         let ret = subvol_by_id
             .values()
-            .map(|subvol| Subvolume {
-                root_path: subvol.root_path.clone().into(),
-                created_from_root_path: subvol.created_from_root_path(&subvol_by_uuid),
-                paths: subvol.paths(&subvol_by_id).to_owned(),
-                is_mountpoint: subvol.is_mountpoint,
-                uuid: subvol.info.uuid().into(),
-                id: subvol.info.id(),
-                created_unix_secs: subvol.info.otime(),
-                snapshot_source_uuid: subvol.info.parent_uuid().map(Into::into).into(),
+            .map(|subvol| {
+                let paths = subvol.paths(&subvol_by_id);
+                Subvolume {
+                    root_path: subvol.root_path.clone().into(),
+                    created_from_root_path: subvol.created_from_root_path(&subvol_by_uuid),
+                    paths: paths.to_owned(),
+                    is_mountpoint: subvol.is_mountpoint,
+                    uuid: subvol.info.uuid.into(),
+                    id: subvol.info.id,
+                    created_unix_secs: subvol.info.otime,
+                    changed_unix_secs: subvol.info.ctime,
+                    snapshot_source_uuid: subvol.info.parent_uuid.map(Into::into).into(),
+                    received_uuid: subvol.info.received_uuid.map(Into::into).into(),
+                    is_read_only: subvol.info.is_read_only(),
+                    flags: subvol.info.flags,
+                    generation: subvol.info.generation,
+                    ctransid: subvol.info.ctransid,
+                    otransid: subvol.info.otransid,
+                    stransid: subvol.info.stransid,
+                    rtransid: subvol.info.rtransid,
+                    group_uuid: subvol.group_uuid(&store, paths).map(Into::into).into(),
+                }
             })
             .collect();
 
@@ -201,6 +456,48 @@ impl Filesystem {
     }
 
     fn list_subvolumes(&self) -> zbus::fdo::Result<Vec<Subvolume>> {
-        self.list_subvolumes_impl().to_fdo()
+        Ok(self.subvolume_cache().to_fdo()?.subvolumes())
+    }
+
+    /// Subvolumes matching `filter` ordered by ID, skipping the first `offset`
+    /// and returning at most `limit` of them unless `limit` is 0
+    fn list_subvolumes_filtered(
+        &self,
+        filter: SubvolumeFilter,
+        offset: u32,
+        limit: u32,
+    ) -> zbus::fdo::Result<Vec<Subvolume>> {
+        let cache = self.subvolume_cache().to_fdo()?;
+        let mut ret: Vec<&Subvolume> = cache
+            .subvols
+            .values()
+            .map(|(_, subvol)| subvol)
+            .filter(|subvol| filter.matches(subvol))
+            .collect();
+        ret.sort_unstable_by_key(|subvol| subvol.id);
+
+        let limit = if limit == 0 {
+            usize::MAX
+        } else {
+            limit as usize
+        };
+        Ok(ret
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    /// Subvolumes changed since `generation` of `epoch` returned by a
+    /// previous call.
+    ///
+    /// Pass 0 for both to get every subvolume.
+    fn list_subvolumes_since(
+        &self,
+        epoch: u64,
+        generation: u64,
+    ) -> zbus::fdo::Result<SubvolumeDelta> {
+        Ok(self.subvolume_cache().to_fdo()?.delta(epoch, generation))
     }
 }
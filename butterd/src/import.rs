@@ -0,0 +1,266 @@
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use chrono::{NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    create_snapper_container, snapper_snapshots, BtrfsBackend, RuleConfig, SnapperInfo,
+    SnapshotGroup, SnapshotLayout, SnapshotMetadata,
+};
+
+/// Other tools whose snapshots can be imported
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportTool {
+    /// `<subvolume>/.snapshots/<number>/snapshot` described by `info.xml`
+    Snapper,
+    /// `<backup>/timeshift-btrfs/snapshots/<date>/@...` described by `info.json`
+    Timeshift,
+}
+
+impl FromStr for ImportTool {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snapper" => Ok(Self::Snapper),
+            "timeshift" => Ok(Self::Timeshift),
+            _ => Err(anyhow::anyhow!("unknown tool '{}'", s)),
+        }
+    }
+}
+
+/// What the tool that took a snapshot recorded about it
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImportedInfo {
+    pub tool: ImportTool,
+    /// snapper's snapshot number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<u32>,
+    /// snapper's `single`, `pre` or `post`, or Timeshift's tags such as `D W`
+    pub kind: String,
+    /// number of the matching `pre` snapshot of a snapper `post` snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_number: Option<u32>,
+    pub description: String,
+    pub created_unix_secs: i64,
+}
+
+/// A snapshot taken by another tool
+#[derive(Clone, Debug)]
+struct ForeignSnapshot {
+    path: PathBuf,
+    /// directory of the tool's own files about the snapshot
+    container: PathBuf,
+    /// name in the target directory if adopted
+    name: String,
+    created_from: PathBuf,
+    group: Option<SnapshotGroup>,
+    info: ImportedInfo,
+}
+
+/// Snapshots in a snapper `.snapshots` directory
+fn find_snapper(backend: &dyn BtrfsBackend, dir: &Path) -> anyhow::Result<Vec<ForeignSnapshot>> {
+    let source = dir.parent().context("no subvolume above the directory")?;
+    let created_from = backend
+        .subvolume_path(source)
+        .context("failed to find source subvolume")?;
+
+    let mut ret = Vec::new();
+    for (path, container) in snapper_snapshots(backend, dir)? {
+        let Some(info) = SnapperInfo::read(&container) else {
+            continue;
+        };
+        ret.push(ForeignSnapshot {
+            path,
+            container,
+            name: format!("snapper-{}", info.number),
+            created_from: created_from.clone(),
+            group: None,
+            info: ImportedInfo {
+                tool: ImportTool::Snapper,
+                number: Some(info.number),
+                kind: info.kind,
+                pre_number: info.pre_number,
+                description: info.description,
+                created_unix_secs: info.created_unix_secs,
+            },
+        });
+    }
+    Ok(ret)
+}
+
+/// Snapshots in a Timeshift `snapshots` directory.
+///
+/// Timeshift only supports `@` and `@home` right under the top-level
+/// subvolume, which are snapshotted together as one group.
+fn find_timeshift(backend: &dyn BtrfsBackend, dir: &Path) -> anyhow::Result<Vec<ForeignSnapshot>> {
+    #[derive(Deserialize)]
+    struct Info {
+        #[serde(default)]
+        created: String,
+        #[serde(default)]
+        comments: String,
+        #[serde(default)]
+        tags: String,
+    }
+
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let container = entry.path();
+        let Ok(bytes) = fs::read(container.join("info.json")) else {
+            continue;
+        };
+        let Ok(info) = serde_json::from_slice::<Info>(&bytes) else {
+            continue;
+        };
+        let date = entry.file_name().to_string_lossy().into_owned();
+        // older versions only name the directory after the local time
+        let created_unix_secs = info.created.parse().ok().or_else(|| {
+            let dt = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d_%H-%M-%S").ok()?;
+            Some(
+                chrono::Local
+                    .from_local_datetime(&dt)
+                    .earliest()?
+                    .timestamp(),
+            )
+        });
+        let group = SnapshotGroup {
+            created_unix_secs: created_unix_secs.unwrap_or(0),
+            ..SnapshotGroup::new()
+        };
+
+        for subvol_entry in fs::read_dir(&container)? {
+            let subvol_entry = subvol_entry?;
+            let path = subvol_entry.path();
+            if !backend.is_subvolume(&path).unwrap_or(false) {
+                continue;
+            }
+            ret.push(ForeignSnapshot {
+                path,
+                container: container.clone(),
+                name: date.clone(),
+                created_from: PathBuf::from(subvol_entry.file_name()),
+                group: Some(group.clone()),
+                info: ImportedInfo {
+                    tool: ImportTool::Timeshift,
+                    number: None,
+                    kind: info.tags.clone(),
+                    pre_number: None,
+                    description: info.comments.clone(),
+                    created_unix_secs: group.created_unix_secs,
+                },
+            });
+        }
+    }
+    Ok(ret)
+}
+
+/// Record what `tool` knows about its snapshots in `dir` as Butter metadata.
+///
+/// With `rule_cfg`, snapshots of its subvolumes are also moved into their
+/// target directory so that the rule prunes them from now on, and the
+/// leftovers of `tool` about them are deleted. Returns the paths of the
+/// imported snapshots.
+pub fn import_snapshots(
+    backend: &dyn BtrfsBackend,
+    tool: ImportTool,
+    dir: &Path,
+    rule_cfg: Option<&RuleConfig>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let snapshots = match tool {
+        ImportTool::Snapper => find_snapper(backend, dir)?,
+        ImportTool::Timeshift => find_timeshift(backend, dir)?,
+    };
+
+    let mut targets = Vec::new();
+    if let Some(rule_cfg) = rule_cfg {
+        for subvol_cfg in &rule_cfg.subvolumes {
+            let root_path = backend
+                .subvolume_path(&subvol_cfg.path)
+                .with_context(|| format!("failed to find '{}'", subvol_cfg.path.display()))?;
+            targets.push((root_path, subvol_cfg));
+        }
+    }
+
+    for snapshot in &snapshots {
+        SnapshotMetadata::check_writable(backend, &snapshot.path)?;
+    }
+
+    let mut ret = Vec::with_capacity(snapshots.len());
+    let mut moved_from = BTreeSet::new();
+    for snapshot in snapshots {
+        let path = snapshot.path.as_path();
+        let info = backend.subvolume_info(path)?;
+        let metadata = SnapshotMetadata {
+            created_from: snapshot.created_from,
+            uuid: info.uuid,
+            group: snapshot.group,
+            imported: Some(snapshot.info.clone()),
+        };
+        metadata
+            .write(backend, path)
+            .with_context(|| format!("failed to write metadata of '{}'", path.display()))?;
+
+        let subvol_cfg = targets
+            .iter()
+            .find(|(root_path, _)| *root_path == metadata.created_from)
+            .map(|(_, subvol_cfg)| subvol_cfg);
+        let Some(subvol_cfg) = subvol_cfg else {
+            ret.push(snapshot.path);
+            continue;
+        };
+        let dst_path = match subvol_cfg.layout {
+            SnapshotLayout::Flat => {
+                let dst_path = subvol_cfg.target_dir.join(&snapshot.name);
+                if dst_path.exists() {
+                    anyhow::bail!("'{}' already exists", dst_path.display());
+                }
+                fs::create_dir_all(&subvol_cfg.target_dir)?;
+                dst_path
+            }
+            SnapshotLayout::Snapper => {
+                let (number, container) = create_snapper_container(&subvol_cfg.target_dir)?;
+                // pre and post pairs do not survive renumbering
+                SnapperInfo {
+                    number,
+                    kind: "single".to_owned(),
+                    pre_number: None,
+                    created_unix_secs: snapshot.info.created_unix_secs,
+                    description: snapshot.info.description,
+                }
+                .write(&container)?;
+                container.join("snapshot")
+            }
+        };
+        backend
+            .rename_subvolume(path, &dst_path)
+            .with_context(|| format!("failed to move '{}'", path.display()))?;
+        moved_from.insert(snapshot.container);
+        ret.push(dst_path);
+    }
+
+    for container in moved_from {
+        if !contains_subvolume(backend, &container)? {
+            fs::remove_dir_all(&container)?;
+        }
+    }
+
+    Ok(ret)
+}
+
+fn contains_subvolume(backend: &dyn BtrfsBackend, dir: &Path) -> io::Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if backend.is_subvolume(&entry?.path()).unwrap_or(false) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
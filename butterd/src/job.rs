@@ -0,0 +1,139 @@
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use zbus::{
+    interface,
+    zvariant::{self, ObjectPath, OwnedObjectPath},
+};
+
+use crate::scheduler::butter;
+
+/// How long a finished job stays on the bus for clients to read its outcome
+const FINISHED_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+static RUNNING: AtomicU32 = AtomicU32::new(0);
+
+/// Whether a job is still running, in which case butterd must keep running
+pub fn is_job_running() -> bool {
+    RUNNING.load(Ordering::SeqCst) > 0
+}
+
+/// Counts a job as running until dropped
+struct RunningGuard;
+
+impl RunningGuard {
+    fn new() -> Self {
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, zvariant::Type)]
+#[serde(rename_all = "lowercase")]
+#[zvariant(signature = "s")]
+pub enum JobState {
+    #[default]
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// A command of the `butter` CLI running in the background on behalf of a
+/// client
+pub struct Job {
+    description: String,
+    state: JobState,
+    error: String,
+}
+
+impl Job {
+    pub const PATH: ObjectPath<'static> =
+        ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/Job");
+
+    /// Run `butter` with `args` as a new job, removing it some time after it
+    /// finished
+    pub(crate) async fn spawn(
+        conn: &zbus::Connection,
+        description: String,
+        args: Vec<String>,
+    ) -> zbus::Result<OwnedObjectPath> {
+        let path = OwnedObjectPath::try_from(format!(
+            "{}/{}",
+            Self::PATH,
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ))?;
+        let job = Self {
+            description,
+            state: JobState::Running,
+            error: String::new(),
+        };
+        conn.object_server().at(&path, job).await?;
+
+        let conn = conn.clone();
+        let job_path = path.clone();
+        let running = RunningGuard::new();
+        tokio::spawn(async move {
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            let res = butter(&args).await;
+            if let Err(err) = Self::finish(&conn, &job_path, res).await {
+                warn!("failed to finish job {}: {}", job_path, err);
+            }
+            drop(running);
+            tokio::time::sleep(FINISHED_LIFETIME).await;
+            let _ = conn.object_server().remove::<Self, _>(&job_path).await;
+        });
+        Ok(path)
+    }
+
+    async fn finish(
+        conn: &zbus::Connection,
+        path: &ObjectPath<'_>,
+        res: std::io::Result<()>,
+    ) -> zbus::Result<()> {
+        let iface_ref = conn.object_server().interface::<_, Self>(path).await?;
+        let mut iface = iface_ref.get_mut().await;
+        let ctx = iface_ref.signal_context();
+        match res {
+            Ok(_) => iface.state = JobState::Succeeded,
+            Err(err) => {
+                iface.state = JobState::Failed;
+                iface.error = err.to_string();
+                iface.error_changed(ctx).await?;
+            }
+        }
+        iface.state_changed(ctx).await
+    }
+}
+
+#[interface(
+    name = "org.zhangyuannie.Butter1.Job",
+    proxy(gen_blocking = true, default_service = "org.zhangyuannie.Butter1")
+)]
+impl Job {
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+
+    #[zbus(property)]
+    fn state(&self) -> JobState {
+        self.state
+    }
+
+    /// why the job failed, empty otherwise
+    #[zbus(property)]
+    fn error(&self) -> String {
+        self.error.clone()
+    }
+}
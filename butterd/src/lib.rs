@@ -1,23 +1,53 @@
+mod audit;
+mod backend;
+mod calendar;
 pub mod config;
+mod convert;
+mod daemon;
 mod filesystem;
+mod import;
+mod job;
+mod metadata;
 mod mnt;
+mod name_template;
+mod retention;
 mod rule;
 mod rule_config;
+mod rule_history;
+mod rule_timer;
 mod schedule;
+mod scheduler;
+mod snapper;
 mod storage;
 mod subvolume;
+mod user_snapshot;
 mod zvariant;
 
 use std::collections::HashMap;
 use zbus_polkit::policykit1::{AuthorityProxy, CheckAuthorizationFlags, Subject};
 
+pub use audit::*;
+pub use backend::*;
+pub use calendar::*;
+pub use convert::*;
+pub use daemon::*;
 pub use filesystem::*;
+pub use import::*;
+pub use job::*;
+pub use metadata::*;
 pub use mnt::*;
+pub use name_template::*;
+pub use retention::*;
 pub use rule::*;
 pub use rule_config::*;
+pub use rule_history::*;
+pub use rule_timer::*;
 pub use schedule::*;
+pub use scheduler::*;
+pub use snapper::*;
 pub use storage::*;
 pub use subvolume::*;
+pub use user_snapshot::*;
 pub use zvariant::*;
 
 pub(crate) trait ToFdo<T> {
@@ -38,13 +68,22 @@ impl<T> ToFdo<T> for std::io::Result<T> {
 
 #[derive(Clone)]
 pub struct Polkit {
-    authority: AuthorityProxy<'static>,
+    /// `None` if every request is authorized
+    authority: Option<AuthorityProxy<'static>>,
 }
 
 impl Polkit {
     pub async fn new(conn: &zbus::Connection) -> zbus::Result<Self> {
         let authority = AuthorityProxy::new(conn).await?;
-        Ok(Self { authority })
+        Ok(Self {
+            authority: Some(authority),
+        })
+    }
+
+    /// Authorize every request without asking polkit. Only meant for tests.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn disabled() -> Self {
+        Self { authority: None }
     }
 
     pub async fn validate(
@@ -52,10 +91,12 @@ impl Polkit {
         header: &zbus::message::Header<'_>,
         action_id: &str,
     ) -> zbus::fdo::Result<()> {
+        let Some(authority) = &self.authority else {
+            return Ok(());
+        };
         let subject = Subject::new_for_message_header(header)
             .map_err(|_| zbus::fdo::Error::AuthFailed("Failed to create subject".to_owned()))?;
-        let auth = self
-            .authority
+        let auth = authority
             .check_authorization(
                 &subject,
                 action_id,
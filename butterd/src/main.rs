@@ -1,17 +1,56 @@
-use tracing::info;
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> zbus::Result<()> {
-    tracing_subscriber::fmt().init();
+    let level = butterd::DaemonConfig::read()
+        .ok()
+        .and_then(|cfg| cfg.log_level.parse().ok())
+        .unwrap_or(tracing::Level::INFO);
+    tracing_subscriber::fmt().with_max_level(level).init();
+    let config = butterd::DaemonConfig::load();
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if let Err(err) = butterd::DaemonConfig::reload() {
+                warn!("failed to reload config: {}", err);
+            }
+        }
+    });
+
     info!("Creating D-Bus connection");
     let conn = zbus::Connection::system().await?;
 
     let polkit = butterd::Polkit::new(&conn).await?;
+    let audit = butterd::AuditLog::new(&conn, polkit.clone()).await?;
+    let backend: Arc<dyn butterd::BtrfsBackend> = Arc::new(butterd::LibBtrfsutil);
+
+    conn.object_server()
+        .at(
+            butterd::Daemon::PATH,
+            butterd::Daemon::new(polkit.clone(), audit.clone()),
+        )
+        .await?;
+
+    conn.object_server()
+        .at(butterd::AuditLog::PATH, audit.clone())
+        .await?;
 
     conn.object_server()
         .at(
             butterd::Storage::PATH,
-            butterd::Storage::new(polkit.clone()).await?,
+            butterd::Storage::new(backend.clone(), polkit.clone(), audit.clone()).await?,
+        )
+        .await?;
+
+    conn.object_server()
+        .at(
+            butterd::UserSnapshots::PATH,
+            butterd::UserSnapshots::new(&conn, backend.clone(), polkit.clone(), audit.clone())
+                .await?,
         )
         .await?;
 
@@ -23,6 +62,18 @@ async fn main() -> zbus::Result<()> {
         .refresh(&conn.object_server())
         .await?;
 
+    if let Err(err) = conn
+        .object_server()
+        .interface::<_, butterd::Storage>(butterd::Storage::PATH)
+        .await?
+        .get()
+        .await
+        .empty_trash(&conn.object_server(), config.trash_retention_secs)
+        .await
+    {
+        warn!("failed to delete old restore backups: {}", err);
+    }
+
     conn.object_server()
         .at(butterd::Storage::PATH, zbus::fdo::ObjectManager)
         .await?;
@@ -30,7 +81,7 @@ async fn main() -> zbus::Result<()> {
     conn.object_server()
         .at(
             butterd::Schedule::PATH,
-            butterd::Schedule::new(conn.clone()).await?,
+            butterd::Schedule::new(conn.clone(), backend.clone(), polkit, audit).await?,
         )
         .await?;
 
@@ -49,13 +100,19 @@ async fn main() -> zbus::Result<()> {
         .at(butterd::Schedule::PATH, zbus::fdo::ObjectManager)
         .await?;
 
+    tokio::spawn(butterd::run_builtin_scheduler());
+
     info!("Registering well-known name");
     conn.request_name("org.zhangyuannie.Butter1").await?;
 
     loop {
         let listener = conn.monitor_activity();
-        let d_15min = std::time::Duration::from_secs(15 * 60);
-        if tokio::time::timeout(d_15min, listener).await.is_err() {
+        let idle_timeout =
+            std::time::Duration::from_secs(butterd::DaemonConfig::current().idle_timeout_secs);
+        if tokio::time::timeout(idle_timeout, listener).await.is_err() {
+            if butterd::is_builtin_scheduler_active() || butterd::is_job_running() {
+                continue;
+            }
             info!("Exiting due to inactivity");
             break;
         }
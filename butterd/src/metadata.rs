@@ -0,0 +1,213 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{BtrfsBackend, ImportedInfo};
+
+/// Directory in the top-level subvolume holding the metadata of every
+/// subvolume of the filesystem as `<uuid>.json`
+const STORE_DIR: &str = ".butter/metadata";
+/// Where metadata used to be kept, inside the snapshot itself
+const SNAPSHOT_FILE: &str = ".butter/info.json";
+
+/// Butter specific metadata for snapshot.
+///
+/// Kept in a per-filesystem store in the top-level subvolume, which is
+/// mounted privately if needed, so that snapshot contents stay untouched and
+/// metadata can change later. Snapshots created by older versions have it in
+/// `.butter/info.json` inside the snapshot until migrated.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SnapshotMetadata {
+    /// the path relative to the filesystem root of the subvolume this subvolume is a snapshot of
+    pub created_from: PathBuf,
+    /// subvolume's UUID
+    pub uuid: Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<SnapshotGroup>,
+    /// set if taken by another tool
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported: Option<ImportedInfo>,
+}
+
+/// Snapshots taken together, e.g. of every subvolume of a rule in one run
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotGroup {
+    pub uuid: Uuid,
+    pub created_unix_secs: i64,
+}
+
+impl SnapshotGroup {
+    pub fn new() -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            uuid: Uuid::new_v4(),
+            created_unix_secs: now.as_secs() as i64,
+        }
+    }
+}
+
+impl Default for SnapshotGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SnapshotMetadata {
+    pub fn read(backend: &dyn BtrfsBackend, subvol_path: &Path) -> Option<SnapshotMetadata> {
+        let raw = backend.subvolume_info(subvol_path).ok()?;
+        Self::read_with_uuid(backend, subvol_path, raw.uuid)
+    }
+
+    /// Like [`Self::read`] when the UUID of the subvolume is already known
+    pub fn read_with_uuid(
+        backend: &dyn BtrfsBackend,
+        subvol_path: &Path,
+        uuid: Uuid,
+    ) -> Option<SnapshotMetadata> {
+        store_path(backend, subvol_path, uuid)
+            .ok()
+            .and_then(|path| read_file(&path, uuid))
+            .or_else(|| read_file(&subvol_path.join(SNAPSHOT_FILE), uuid))
+    }
+
+    /// Like [`Self::read_with_uuid`] with the store already read by
+    /// [`read_metadata_store`]
+    pub fn read_from_store(
+        store: &HashMap<Uuid, SnapshotMetadata>,
+        subvol_path: &Path,
+        uuid: Uuid,
+    ) -> Option<SnapshotMetadata> {
+        store
+            .get(&uuid)
+            .cloned()
+            .or_else(|| read_file(&subvol_path.join(SNAPSHOT_FILE), uuid))
+    }
+
+    /// Fail if the metadata of the subvolume at `subvol_path` cannot be
+    /// written because the store is out of reach
+    pub fn check_writable(backend: &dyn BtrfsBackend, subvol_path: &Path) -> io::Result<()> {
+        backend.top_level_path(subvol_path).map(|_| ())
+    }
+
+    /// Save the metadata of the subvolume at `subvol_path` to the store
+    pub fn write(&self, backend: &dyn BtrfsBackend, subvol_path: &Path) -> io::Result<()> {
+        write_file(&store_path(backend, subvol_path, self.uuid)?, self)
+    }
+
+    /// Drop the stored metadata of the deleted subvolume `uuid` that was on
+    /// the filesystem containing `path`
+    pub fn remove(backend: &dyn BtrfsBackend, path: &Path, uuid: Uuid) -> io::Result<()> {
+        match fs::remove_file(store_path(backend, path, uuid)?) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Copy metadata kept inside snapshots on the filesystem containing `path`
+/// to the store, removing the old copies if `remove_old`.
+///
+/// Old copies are kept in received snapshots regardless, as making them
+/// writable would break incremental sends. Returns how many snapshots were
+/// migrated.
+pub fn migrate_metadata(
+    backend: &dyn BtrfsBackend,
+    path: &Path,
+    remove_old: bool,
+) -> io::Result<u32> {
+    let top_level = backend.top_level_path(path)?;
+
+    let mut ret = 0;
+    for (root_path, info) in backend.list_subvolumes(&top_level)? {
+        let subvol_path = top_level.join(root_path);
+        let old_path = subvol_path.join(SNAPSHOT_FILE);
+        let Some(metadata) = read_file(&old_path, info.uuid) else {
+            continue;
+        };
+        let new_path = top_level
+            .join(STORE_DIR)
+            .join(format!("{}.json", info.uuid));
+        if !new_path.exists() {
+            write_file(&new_path, &metadata)?;
+        }
+        ret += 1;
+
+        if !remove_old || info.received_uuid.is_some() {
+            continue;
+        }
+        if info.is_read_only() {
+            backend.set_read_only(&subvol_path, false)?;
+        }
+        let res = fs::remove_file(&old_path);
+        // other files may live there
+        let _ = fs::remove_dir(subvol_path.join(".butter"));
+        if info.is_read_only() {
+            backend.set_read_only(&subvol_path, true)?;
+        }
+        res?;
+    }
+    Ok(ret)
+}
+
+/// Every metadata in the store of the filesystem containing `path` by UUID,
+/// empty if the store cannot be read
+pub fn read_metadata_store(
+    backend: &dyn BtrfsBackend,
+    path: &Path,
+) -> HashMap<Uuid, SnapshotMetadata> {
+    let Ok(top_level) = backend.top_level_path(path) else {
+        return HashMap::new();
+    };
+    let Ok(entries) = fs::read_dir(top_level.join(STORE_DIR)) else {
+        return HashMap::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "json" {
+                return None;
+            }
+            let uuid: Uuid = path.file_stem()?.to_str()?.parse().ok()?;
+            read_file(&path, uuid).map(|metadata| (uuid, metadata))
+        })
+        .collect()
+}
+
+fn store_path(backend: &dyn BtrfsBackend, path: &Path, uuid: Uuid) -> io::Result<PathBuf> {
+    let top_level = backend.top_level_path(path)?;
+    Ok(top_level.join(STORE_DIR).join(format!("{}.json", uuid)))
+}
+
+fn read_file(path: &Path, uuid: Uuid) -> Option<SnapshotMetadata> {
+    let bytes = fs::read(path).ok()?;
+    let ret: SnapshotMetadata = serde_json::from_slice(&bytes).ok()?;
+    if ret.uuid == uuid {
+        Some(ret)
+    } else {
+        None
+    }
+}
+
+fn write_file(path: &Path, metadata: &SnapshotMetadata) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // write a whole new file so that readers never see partial metadata
+    let tmp_path = path.with_extension("json.tmp");
+    let mut f = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer_pretty(&mut f, metadata)?;
+    f.write_all(b"\n")?;
+    f.flush()?;
+    drop(f);
+    fs::rename(tmp_path, path)
+}
@@ -0,0 +1,176 @@
+use std::{fmt::Write, fs, path::Path};
+
+use chrono::{
+    format::{Item, StrftimeItems},
+    Local, NaiveDateTime,
+};
+
+/// Format of `{time}` without an explicit one, sorting by time
+pub const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Values of the placeholders of a [`crate::RuleSubvolumeConfig::name_template`]
+#[derive(Debug, Clone)]
+pub struct NameContext<'a> {
+    pub time: NaiveDateTime,
+    /// name of the rule, empty for manual snapshots
+    pub rule: &'a str,
+    /// path of the snapshotted subvolume
+    pub source: &'a Path,
+    pub hostname: &'a str,
+}
+
+impl<'a> NameContext<'a> {
+    /// Context for snapshotting `source` now, in local time
+    pub fn now(rule: &'a str, source: &'a Path, hostname: &'a str) -> Self {
+        Self {
+            time: Local::now().naive_local(),
+            rule,
+            source,
+            hostname,
+        }
+    }
+}
+
+/// Name of this machine, empty if unknown
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|s| s.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// Check `template` by expanding it with sample values
+pub fn validate_name_template(template: &str) -> Result<(), String> {
+    let ctx = NameContext {
+        time: NaiveDateTime::default(),
+        rule: "rule",
+        source: Path::new("/home"),
+        hostname: "host",
+    };
+    expand_name_template(template, &ctx, 1).map(|_| ())
+}
+
+/// Expand the snapshot name `template` for the `counter`th attempt, starting
+/// at 1.
+///
+/// Placeholders are `{time}` or `{time:FORMAT}` with a strftime `FORMAT`,
+/// `{rule}`, `{source}` for the basename of the source subvolume, `{hostname}`
+/// and `{counter}`, while `{{` and `}}` stand for braces. Templates without
+/// `{counter}` get `_<counter>` appended from the second attempt on, so
+/// retrying after a collision picks another name.
+pub fn expand_name_template(
+    template: &str,
+    ctx: &NameContext,
+    counter: u32,
+) -> Result<String, String> {
+    let mut ret = String::new();
+    let mut has_counter = false;
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        ret.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            ret.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        if tail.starts_with('}') {
+            return Err("unmatched '}'".to_owned());
+        }
+        let end = tail.find('}').ok_or_else(|| "unclosed '{'".to_owned())?;
+        let placeholder = &tail[1..end];
+        rest = &tail[end + 1..];
+
+        let (key, format) = match placeholder.split_once(':') {
+            Some((key, format)) => (key, Some(format)),
+            None => (placeholder, None),
+        };
+        match (key, format) {
+            ("time", format) => {
+                let items: Vec<Item> =
+                    StrftimeItems::new(format.unwrap_or(DEFAULT_TIME_FORMAT)).collect();
+                if items.contains(&Item::Error) {
+                    return Err(format!("invalid time format in '{{{}}}'", placeholder));
+                }
+                write!(ret, "{}", ctx.time.format_with_items(items.into_iter()))
+                    .map_err(|_| format!("invalid time format in '{{{}}}'", placeholder))?;
+            }
+            ("rule", None) => ret.push_str(ctx.rule),
+            ("source", None) => match ctx.source.file_name() {
+                Some(name) => ret.push_str(&name.to_string_lossy()),
+                None => ret.push_str("root"),
+            },
+            ("hostname", None) => ret.push_str(ctx.hostname),
+            ("counter", None) => {
+                has_counter = true;
+                ret.push_str(&counter.to_string());
+            }
+            _ => return Err(format!("unknown placeholder '{{{}}}'", placeholder)),
+        }
+    }
+    ret.push_str(rest);
+
+    if !has_counter && counter > 1 {
+        ret.push_str(&format!("_{}", counter));
+    }
+    if ret.is_empty() || ret == "." || ret == ".." || ret.contains('/') {
+        return Err(format!("invalid snapshot name '{}'", ret));
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::{RuleConfig, RuleSubvolumeConfig};
+
+    #[test]
+    fn test_name_template() {
+        let ctx = NameContext {
+            time: NaiveDate::from_ymd_opt(2024, 1, 10)
+                .unwrap()
+                .and_hms_opt(12, 34, 56)
+                .unwrap(),
+            rule: "daily",
+            source: Path::new("/home"),
+            hostname: "box",
+        };
+        let expand = |template: &str, counter| expand_name_template(template, &ctx, counter);
+
+        assert_eq!(
+            expand("{hostname}-{source}-{rule}-{time}", 1).unwrap(),
+            "box-home-daily-2024-01-10_12-34-56"
+        );
+        assert_eq!(expand("{time:%Y%m%d}_{counter}", 3).unwrap(), "20240110_3");
+        assert_eq!(expand("{{{source}}}", 1).unwrap(), "{home}");
+        // collisions without {counter} get a suffix
+        assert_eq!(expand("{source}", 2).unwrap(), "home_2");
+        let root = NameContext {
+            source: Path::new("/"),
+            ..ctx.clone()
+        };
+        assert_eq!(expand_name_template("{source}", &root, 1).unwrap(), "root");
+
+        for invalid in [
+            "{nope}",
+            "{time",
+            "a}b",
+            "{time:%}",
+            "{time:%Y/%m}",
+            "{rule:x}",
+            "..",
+        ] {
+            assert!(expand(invalid, 1).is_err(), "{}", invalid);
+        }
+
+        let rule_cfg = RuleConfig {
+            subvolumes: vec![RuleSubvolumeConfig {
+                name_template: "{source".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(rule_cfg.validate().is_err());
+    }
+}
@@ -0,0 +1,892 @@
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    fs, io,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Datelike, Months, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+use zbus::zvariant;
+
+use crate::{
+    delete_subvolume, read_metadata_store, snapper_snapshots, BtrfsBackend, FilesystemSpace,
+    RuleConfig, RuleSubvolumeConfig, SnapshotGroup, SnapshotLayout, SnapshotMetadata,
+};
+
+/// `true` if `c` keeps a limited number of snapshots
+pub fn should_prune(c: &RuleConfig) -> bool {
+    has_buckets(c) || c.keep_orphaned_days != 0 || has_space_limits(c)
+}
+
+fn has_space_limits(c: &RuleConfig) -> bool {
+    c.min_free_bytes != 0 || c.min_free_percent != 0 || c.max_snapshots_percent != 0
+}
+
+fn has_buckets(c: &RuleConfig) -> bool {
+    c.keep_last != 0
+        || c.keep_minutely != 0
+        || c.keep_hourly != 0
+        || c.keep_daily != 0
+        || c.keep_weekly != 0
+        || c.keep_monthly != 0
+        || c.keep_yearly != 0
+        || within_options(c).iter().any(|(_, s)| !s.is_empty())
+}
+
+/// Options of `c` taking a [`RetentionDuration`], with their names
+fn within_options(c: &RuleConfig) -> [(&'static str, &str); 7] {
+    [
+        ("keep_within", c.keep_within.as_str()),
+        ("keep_within_minutely", c.keep_within_minutely.as_str()),
+        ("keep_within_hourly", c.keep_within_hourly.as_str()),
+        ("keep_within_daily", c.keep_within_daily.as_str()),
+        ("keep_within_weekly", c.keep_within_weekly.as_str()),
+        ("keep_within_monthly", c.keep_within_monthly.as_str()),
+        ("keep_within_yearly", c.keep_within_yearly.as_str()),
+    ]
+}
+
+/// Check the durations and percentages of the retention policy of `c`
+pub fn validate_retention(c: &RuleConfig) -> Result<(), String> {
+    for (name, value) in within_options(c) {
+        if !value.is_empty() {
+            value
+                .parse::<RetentionDuration>()
+                .map_err(|err| format!("invalid {}: {}", name, err))?;
+        }
+    }
+    for (name, value) in [
+        ("min_free_percent", c.min_free_percent),
+        ("max_snapshots_percent", c.max_snapshots_percent),
+    ] {
+        if value > 100 {
+            return Err(format!("invalid {}: {} is over 100", name, value));
+        }
+    }
+    Ok(())
+}
+
+/// A duration such as `1y6m` or `2d12h` in years, months, days and hours,
+/// like the `--keep-within` options of restic
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct RetentionDuration {
+    pub months: u32,
+    pub days: u32,
+    pub hours: u32,
+}
+
+impl FromStr for RetentionDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid duration '{}'", s);
+        let mut ret = Self::default();
+        let mut num = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                num.push(c);
+                continue;
+            }
+            let n: u32 = num.parse().map_err(|_| invalid())?;
+            num.clear();
+            let field = match c {
+                'y' => {
+                    ret.months = n
+                        .checked_mul(12)
+                        .ok_or_else(invalid)?
+                        .checked_add(ret.months)
+                        .ok_or_else(invalid)?;
+                    continue;
+                }
+                'm' => &mut ret.months,
+                'd' => &mut ret.days,
+                'h' => &mut ret.hours,
+                _ => return Err(invalid()),
+            };
+            *field = field.checked_add(n).ok_or_else(invalid)?;
+        }
+        if !num.is_empty() || ret == Self::default() {
+            return Err(invalid());
+        }
+        Ok(ret)
+    }
+}
+
+impl RetentionDuration {
+    /// The time this long before `t`
+    pub fn before(&self, t: NaiveDateTime) -> Option<NaiveDateTime> {
+        t.checked_sub_months(Months::new(self.months))?
+            .checked_sub_signed(chrono::Duration::days(self.days.into()))?
+            .checked_sub_signed(chrono::Duration::hours(self.hours.into()))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Snapshot {
+    created: NaiveDateTime,
+    path: PathBuf,
+    /// numbered directory of [`SnapshotLayout::Snapper`]
+    container: Option<PathBuf>,
+    group: Option<SnapshotGroup>,
+    /// target directory it was found in
+    dir: PathBuf,
+}
+
+/// Subvolumes in `dir` arranged by `layout`, with their numbered directory
+/// if any
+fn list_target_dir(
+    backend: &dyn BtrfsBackend,
+    dir: &Path,
+    layout: SnapshotLayout,
+) -> io::Result<Vec<(PathBuf, Option<PathBuf>)>> {
+    if layout == SnapshotLayout::Snapper {
+        return Ok(snapper_snapshots(backend, dir)?
+            .into_iter()
+            .map(|(path, container)| (path, Some(container)))
+            .collect());
+    }
+    Ok(fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if entry.file_name().as_bytes()[0] == b'.' {
+                return None;
+            }
+            let path = entry.path();
+            backend
+                .is_subvolume(&path)
+                .unwrap_or(false)
+                .then_some((path, None))
+        })
+        .collect())
+}
+
+/// Snapshots of `subvol_cfg` found in its target directory
+fn find_snapshots(
+    backend: &dyn BtrfsBackend,
+    subvol_cfg: &RuleSubvolumeConfig,
+) -> anyhow::Result<Vec<Snapshot>> {
+    let source_subvol_path = backend.subvolume_path(&subvol_cfg.path)?;
+
+    Ok(
+        list_target_dir(backend, &subvol_cfg.target_dir, subvol_cfg.layout)?
+            .into_iter()
+            .filter_map(|(path, container)| {
+                let info = backend.subvolume_info(&path).ok()?;
+                let metadata = SnapshotMetadata::read_with_uuid(backend, &path, info.uuid)?;
+                if metadata.created_from != source_subvol_path {
+                    return None;
+                }
+                Some(Snapshot {
+                    path,
+                    container,
+                    created: DateTime::from_timestamp(info.otime, 0)?.naive_utc(),
+                    group: metadata.group,
+                    dir: subvol_cfg.target_dir.clone(),
+                })
+            })
+            .collect(),
+    )
+}
+
+/// Snapshots in `dir` whose source subvolume no longer exists
+fn find_orphans(
+    backend: &dyn BtrfsBackend,
+    dir: &Path,
+    layout: SnapshotLayout,
+) -> io::Result<Vec<Snapshot>> {
+    let mut uuids: HashSet<Uuid> = backend
+        .list_subvolumes(dir)?
+        .into_iter()
+        .map(|(_, info)| info.uuid)
+        .collect();
+    uuids.insert(
+        backend
+            .subvolume_info_with_id(dir, libbtrfsutil::FS_TREE_OBJECTID)?
+            .uuid,
+    );
+
+    let store = read_metadata_store(backend, dir);
+    Ok(list_target_dir(backend, dir, layout)?
+        .into_iter()
+        .filter_map(|(path, container)| {
+            let info = backend.subvolume_info(&path).ok()?;
+            if uuids.contains(&info.parent_uuid?) {
+                return None;
+            }
+            // leave alone whatever butter did not create
+            SnapshotMetadata::read_from_store(&store, &path, info.uuid)?;
+            Some(Snapshot {
+                path,
+                container,
+                created: DateTime::from_timestamp(info.otime, 0)?.naive_utc(),
+                group: None,
+                dir: dir.to_owned(),
+            })
+        })
+        .collect())
+}
+
+/// Option of the retention policy keeping a snapshot
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, zvariant::Type)]
+#[serde(rename_all = "kebab-case")]
+#[zvariant(signature = "s")]
+pub enum KeepReason {
+    /// one of the newest, within [`RuleConfig::keep_last`]
+    Last,
+    /// within [`RuleConfig::keep_within`] of the newest
+    Within,
+    /// newest of its minute, within [`RuleConfig::keep_minutely`]
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+    /// newest of its minute, within [`RuleConfig::keep_within_minutely`] of
+    /// the newest
+    WithinMinutely,
+    WithinHourly,
+    WithinDaily,
+    WithinWeekly,
+    WithinMonthly,
+    WithinYearly,
+    /// not deleted by the space limits, without any count-based option
+    Space,
+    /// orphaned snapshot younger than [`RuleConfig::keep_orphaned_days`]
+    OrphanedDays,
+}
+
+impl KeepReason {
+    /// The name used in JSON and on D-Bus
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Last => "last",
+            Self::Within => "within",
+            Self::Minutely => "minutely",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+            Self::Weekly => "weekly",
+            Self::Monthly => "monthly",
+            Self::Yearly => "yearly",
+            Self::WithinMinutely => "within-minutely",
+            Self::WithinHourly => "within-hourly",
+            Self::WithinDaily => "within-daily",
+            Self::WithinWeekly => "within-weekly",
+            Self::WithinMonthly => "within-monthly",
+            Self::WithinYearly => "within-yearly",
+            Self::Space => "space",
+            Self::OrphanedDays => "orphaned-days",
+        }
+    }
+}
+
+/// What pruning a rule does with one of its snapshots
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, zvariant::Type)]
+pub struct PruneDecision {
+    pub path: PathBuf,
+    pub created_unix_secs: i64,
+    /// options keeping the snapshot, empty if it is deleted
+    pub kept_by: Vec<KeepReason>,
+    /// deleted to meet the space limits of the rule
+    pub for_space: bool,
+}
+
+impl PruneDecision {
+    pub fn is_kept(&self) -> bool {
+        !self.kept_by.is_empty()
+    }
+}
+
+/// Decide which of `items` the retention policy of `rule_cfg` keeps.
+///
+/// Returns the items newest first, each with the options keeping it, empty
+/// for the ones to delete. Each bucket keeps the newest item of as many
+/// minutes, hours, days etc. as it is configured to, or of all of them
+/// within its duration of the newest item. Invalid durations keep nothing,
+/// see [`validate_retention`].
+pub fn retention_decisions<T>(
+    mut items: Vec<(NaiveDateTime, T)>,
+    rule_cfg: &RuleConfig,
+) -> Vec<(NaiveDateTime, T, Vec<KeepReason>)> {
+    struct Bucket {
+        reason: KeepReason,
+        keep: u32,
+        /// only items newer than this count
+        cutoff: Option<NaiveDateTime>,
+        last: Option<i64>,
+        algo: fn(&NaiveDateTime) -> i64,
+    }
+
+    items.sort_by_key(|(created, _)| cmp::Reverse(*created));
+    let newest = items.first().map(|(created, _)| *created);
+    let cutoff = |within: &str| {
+        within
+            .parse::<RetentionDuration>()
+            .ok()
+            .and_then(|d| d.before(newest?))
+    };
+
+    let periods: [(KeepReason, u32, KeepReason, &str, fn(&NaiveDateTime) -> i64); 6] = [
+        (
+            KeepReason::Minutely,
+            rule_cfg.keep_minutely,
+            KeepReason::WithinMinutely,
+            rule_cfg.keep_within_minutely.as_str(),
+            |dt| {
+                i64::from(dt.year()) * 1_000_000
+                    + i64::from(dt.ordinal()) * 1440
+                    + i64::from(dt.hour()) * 60
+                    + i64::from(dt.minute())
+            },
+        ),
+        (
+            KeepReason::Hourly,
+            rule_cfg.keep_hourly,
+            KeepReason::WithinHourly,
+            rule_cfg.keep_within_hourly.as_str(),
+            |dt| {
+                i64::from(dt.year()) * 100000 + i64::from(dt.ordinal()) * 100 + i64::from(dt.hour())
+            },
+        ),
+        (
+            KeepReason::Daily,
+            rule_cfg.keep_daily,
+            KeepReason::WithinDaily,
+            rule_cfg.keep_within_daily.as_str(),
+            |dt| i64::from(dt.year()) * 1000 + i64::from(dt.ordinal()),
+        ),
+        (
+            KeepReason::Weekly,
+            rule_cfg.keep_weekly,
+            KeepReason::WithinWeekly,
+            rule_cfg.keep_within_weekly.as_str(),
+            |dt| {
+                let week = dt.iso_week();
+                i64::from(week.year()) * 100 + i64::from(week.week())
+            },
+        ),
+        (
+            KeepReason::Monthly,
+            rule_cfg.keep_monthly,
+            KeepReason::WithinMonthly,
+            rule_cfg.keep_within_monthly.as_str(),
+            |dt| i64::from(dt.year()) * 100 + i64::from(dt.month()),
+        ),
+        (
+            KeepReason::Yearly,
+            rule_cfg.keep_yearly,
+            KeepReason::WithinYearly,
+            rule_cfg.keep_within_yearly.as_str(),
+            |dt| i64::from(dt.year()),
+        ),
+    ];
+
+    let mut buckets = Vec::new();
+    for (reason, keep, within_reason, within, algo) in periods {
+        buckets.push(Bucket {
+            reason,
+            keep,
+            cutoff: None,
+            last: None,
+            algo,
+        });
+        if let Some(cutoff) = cutoff(within) {
+            buckets.push(Bucket {
+                reason: within_reason,
+                keep: u32::MAX,
+                cutoff: Some(cutoff),
+                last: None,
+                algo,
+            });
+        }
+    }
+    let within = cutoff(&rule_cfg.keep_within);
+
+    items
+        .into_iter()
+        .enumerate()
+        .map(|(i, (created, item))| {
+            let mut kept_by = Vec::new();
+            if i < rule_cfg.keep_last as usize {
+                kept_by.push(KeepReason::Last);
+            }
+            if within.is_some_and(|cutoff| created > cutoff) {
+                kept_by.push(KeepReason::Within);
+            }
+            for bucket in &mut buckets {
+                if bucket.keep == 0 || bucket.cutoff.is_some_and(|cutoff| created <= cutoff) {
+                    continue;
+                }
+                let val = (bucket.algo)(&created);
+                if bucket.last != Some(val) {
+                    bucket.keep -= 1;
+                    bucket.last = Some(val);
+                    kept_by.push(bucket.reason);
+                }
+            }
+            (created, item, kept_by)
+        })
+        .collect()
+}
+
+/// Snapshots deleted by [`prune_rule`] and what failed, for the caller to
+/// report
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PruneOutcome {
+    pub pruned: Vec<PathBuf>,
+    /// part of `pruned` deleted to meet the space limits
+    pub pruned_for_space: Vec<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+impl PruneOutcome {
+    fn error(&mut self, msg: String) {
+        self.errors.push(msg);
+    }
+}
+
+fn delete_snapshot(backend: &dyn BtrfsBackend, snapshot: &Snapshot, out: &mut PruneOutcome) {
+    let path = snapshot.path.as_path();
+    let mut res = delete_subvolume(backend, path);
+    if let (Ok(_), Some(container)) = (&res, &snapshot.container) {
+        res = fs::remove_dir_all(container);
+    }
+    match res {
+        Ok(_) => out.pruned.push(path.to_owned()),
+        Err(err) => out.error(format!("failed to delete '{}': {}", path.display(), err)),
+    }
+}
+
+/// Snapshots of `rule_cfg` with the options keeping them, empty for the ones
+/// to delete, and the paths of those deleted for the space limits
+fn plan_prune(
+    backend: &dyn BtrfsBackend,
+    rule_cfg: &RuleConfig,
+    out: &mut PruneOutcome,
+) -> (Vec<(Snapshot, Vec<KeepReason>)>, HashSet<PathBuf>) {
+    let mut plan = plan_retention(backend, rule_cfg, out);
+    let for_space = if has_space_limits(rule_cfg) {
+        plan_space(backend, rule_cfg, &mut plan, out)
+    } else {
+        HashSet::new()
+    };
+    (plan, for_space)
+}
+
+/// Like [`plan_prune`] without the space limits
+fn plan_retention(
+    backend: &dyn BtrfsBackend,
+    rule_cfg: &RuleConfig,
+    out: &mut PruneOutcome,
+) -> Vec<(Snapshot, Vec<KeepReason>)> {
+    if let Err(err) = validate_retention(rule_cfg) {
+        out.error(err);
+        return Vec::new();
+    }
+    let mut orphans = if rule_cfg.keep_orphaned_days != 0 {
+        plan_orphans(backend, rule_cfg, out)
+    } else {
+        Vec::new()
+    };
+    if !has_buckets(rule_cfg) && !has_space_limits(rule_cfg) {
+        return orphans;
+    }
+    // deleted orphans are gone before the buckets are considered, while kept
+    // ones are still subject to them
+    let deleted_orphans: HashSet<PathBuf> = orphans
+        .iter()
+        .filter(|(_, kept_by)| kept_by.is_empty())
+        .map(|(s, _)| s.path.clone())
+        .collect();
+
+    let mut ret = Vec::new();
+    let mut groups: HashMap<Uuid, (NaiveDateTime, Vec<Snapshot>)> = HashMap::new();
+
+    for subvol_cfg in &rule_cfg.subvolumes {
+        let snapshots = match find_snapshots(backend, subvol_cfg) {
+            Ok(snapshots) => snapshots,
+            Err(err) => {
+                out.error(format!(
+                    "failed to prune '{}': {}",
+                    subvol_cfg.target_dir.display(),
+                    err
+                ));
+                continue;
+            }
+        };
+
+        let mut ungrouped = Vec::new();
+        for snapshot in snapshots {
+            if deleted_orphans.contains(&snapshot.path) {
+                continue;
+            }
+            orphans.retain(|(s, _)| s.path != snapshot.path);
+            let Some(group) = &snapshot.group else {
+                ungrouped.push((snapshot.created, snapshot));
+                continue;
+            };
+            let Some(created) = DateTime::from_timestamp(group.created_unix_secs, 0) else {
+                continue;
+            };
+            groups
+                .entry(group.uuid)
+                .or_insert_with(|| (created.naive_utc(), Vec::new()))
+                .1
+                .push(snapshot);
+        }
+
+        for (_, snapshot, kept_by) in decide(ungrouped, rule_cfg) {
+            ret.push((snapshot, kept_by));
+        }
+    }
+
+    for (_, snapshots, kept_by) in decide(groups.into_values().collect(), rule_cfg) {
+        for snapshot in snapshots {
+            ret.push((snapshot, kept_by.clone()));
+        }
+    }
+
+    orphans.extend(ret);
+    orphans
+}
+
+/// [`retention_decisions`], keeping everything for the space limits to decide
+/// without count-based options
+fn decide<T>(
+    items: Vec<(NaiveDateTime, T)>,
+    rule_cfg: &RuleConfig,
+) -> Vec<(NaiveDateTime, T, Vec<KeepReason>)> {
+    let mut decisions = retention_decisions(items, rule_cfg);
+    if !has_buckets(rule_cfg) {
+        for (_, _, kept_by) in &mut decisions {
+            kept_by.push(KeepReason::Space);
+        }
+    }
+    decisions
+}
+
+/// Space of a target directory while planning the space limits
+struct DirSpace {
+    space: FilesystemSpace,
+    /// exclusive bytes of the snapshots planned to be deleted
+    freed: u64,
+    /// exclusive bytes of the snapshots kept
+    used: u64,
+}
+
+impl DirSpace {
+    fn exceeds(&self, c: &RuleConfig) -> bool {
+        let free = self.space.free_bytes.saturating_add(self.freed);
+        let total = u128::from(self.space.total_bytes);
+        (c.min_free_bytes != 0 && free < c.min_free_bytes)
+            || (c.min_free_percent != 0
+                && u128::from(free) * 100 < total * u128::from(c.min_free_percent))
+            || (c.max_snapshots_percent != 0
+                && u128::from(self.used) * 100 > total * u128::from(c.max_snapshots_percent))
+    }
+}
+
+/// Delete the oldest snapshots in `plan` not kept by `keep_last` or
+/// `keep_within` until every target directory meets the space limits of
+/// `rule_cfg`. Returns the paths deleted this way.
+///
+/// Btrfs frees the space of deleted subvolumes in the background, so what a
+/// deletion frees is estimated from the exclusive size of the snapshot,
+/// which needs quotas. Snapshots of a group are deleted together.
+fn plan_space(
+    backend: &dyn BtrfsBackend,
+    rule_cfg: &RuleConfig,
+    plan: &mut [(Snapshot, Vec<KeepReason>)],
+    out: &mut PruneOutcome,
+) -> HashSet<PathBuf> {
+    let mut dirs: HashMap<PathBuf, Option<DirSpace>> = HashMap::new();
+    let mut sizes: HashMap<PathBuf, u64> = HashMap::new();
+    for (snapshot, kept_by) in plan.iter() {
+        let dir = dirs.entry(snapshot.dir.clone()).or_insert_with(|| {
+            match backend.space(&snapshot.dir) {
+                Ok(space) => Some(DirSpace {
+                    space,
+                    freed: 0,
+                    used: 0,
+                }),
+                Err(err) => {
+                    out.error(format!(
+                        "failed to measure '{}': {}",
+                        snapshot.dir.display(),
+                        err
+                    ));
+                    None
+                }
+            }
+        });
+        let Some(dir_space) = dir.as_mut() else {
+            continue;
+        };
+        match backend.exclusive_bytes(&snapshot.path) {
+            Ok(Some(bytes)) => {
+                sizes.insert(snapshot.path.clone(), bytes);
+                if kept_by.is_empty() {
+                    dir_space.freed += bytes;
+                } else {
+                    dir_space.used += bytes;
+                }
+            }
+            Ok(None) => {
+                out.error(format!(
+                    "space limits in '{}' need quotas enabled on its filesystem",
+                    snapshot.dir.display()
+                ));
+                *dir = None;
+            }
+            Err(err) => {
+                out.error(format!(
+                    "failed to measure '{}': {}",
+                    snapshot.path.display(),
+                    err
+                ));
+                *dir = None;
+            }
+        }
+    }
+
+    // snapshots of the same group go together, oldest first
+    let mut units: Vec<(NaiveDateTime, Vec<usize>)> = Vec::new();
+    let mut unit_by_group: HashMap<Uuid, usize> = HashMap::new();
+    for (i, (snapshot, _)) in plan.iter().enumerate() {
+        let Some(group) = &snapshot.group else {
+            units.push((snapshot.created, vec![i]));
+            continue;
+        };
+        let created = DateTime::from_timestamp(group.created_unix_secs, 0)
+            .map_or(snapshot.created, |t| t.naive_utc());
+        let unit = *unit_by_group.entry(group.uuid).or_insert_with(|| {
+            units.push((created, Vec::new()));
+            units.len() - 1
+        });
+        units[unit].1.push(i);
+    }
+    units.sort_by_key(|(created, _)| *created);
+
+    let mut for_space = HashSet::new();
+    for (_, members) in units {
+        let is_deletable = members.iter().all(|&i| {
+            let kept_by = &plan[i].1;
+            !kept_by.is_empty()
+                && !kept_by
+                    .iter()
+                    .any(|r| matches!(r, KeepReason::Last | KeepReason::Within))
+        });
+        let exceeds = members
+            .iter()
+            .any(|&i| matches!(dirs.get(&plan[i].0.dir), Some(Some(d)) if d.exceeds(rule_cfg)));
+        if !is_deletable || !exceeds {
+            continue;
+        }
+        for i in members {
+            let (snapshot, kept_by) = &mut plan[i];
+            kept_by.clear();
+            if let (Some(Some(d)), Some(bytes)) =
+                (dirs.get_mut(&snapshot.dir), sizes.get(&snapshot.path))
+            {
+                d.freed += bytes;
+                d.used -= bytes;
+            }
+            for_space.insert(snapshot.path.clone());
+        }
+    }
+    for_space
+}
+
+/// Delete snapshots of `rule_cfg` not kept by its retention policy.
+///
+/// Snapshots taken in the same [`SnapshotGroup`] are kept or deleted
+/// together, based on the time of the group. Older snapshots without a group
+/// are pruned for each subvolume on their own. Orphaned snapshots in the
+/// target directories follow [`RuleConfig::keep_orphaned_days`] instead.
+/// Then the oldest remaining snapshots go until the space limits such as
+/// [`RuleConfig::min_free_bytes`] are met.
+pub fn prune_rule(backend: &dyn BtrfsBackend, rule_cfg: &RuleConfig) -> PruneOutcome {
+    let mut out = PruneOutcome::default();
+    let (plan, for_space) = plan_prune(backend, rule_cfg, &mut out);
+    for (snapshot, kept_by) in plan {
+        if !kept_by.is_empty() {
+            continue;
+        }
+        delete_snapshot(backend, &snapshot, &mut out);
+        if for_space.contains(&snapshot.path) && out.pruned.last() == Some(&snapshot.path) {
+            out.pruned_for_space.push(snapshot.path);
+        }
+    }
+    out
+}
+
+/// What [`prune_rule`] would do with each snapshot of `rule_cfg`, without
+/// deleting anything
+pub fn preview_prune(
+    backend: &dyn BtrfsBackend,
+    rule_cfg: &RuleConfig,
+) -> anyhow::Result<Vec<PruneDecision>> {
+    let mut out = PruneOutcome::default();
+    let mut plan = plan_retention(backend, rule_cfg, &mut out);
+    if !out.errors.is_empty() {
+        return Err(anyhow::anyhow!(out.errors.join("\n")));
+    }
+    // like pruning, skip space limits that cannot be measured, e.g. without
+    // quotas
+    let for_space = if has_space_limits(rule_cfg) {
+        plan_space(backend, rule_cfg, &mut plan, &mut out)
+    } else {
+        HashSet::new()
+    };
+    for err in &out.errors {
+        warn!("{}", err);
+    }
+    Ok(plan
+        .into_iter()
+        .map(|(snapshot, kept_by)| PruneDecision {
+            for_space: for_space.contains(&snapshot.path),
+            path: snapshot.path,
+            created_unix_secs: snapshot.created.and_utc().timestamp(),
+            kept_by,
+        })
+        .collect())
+}
+
+/// Orphaned snapshots in the target directories of `rule_cfg`, kept if
+/// younger than [`RuleConfig::keep_orphaned_days`]
+fn plan_orphans(
+    backend: &dyn BtrfsBackend,
+    rule_cfg: &RuleConfig,
+    out: &mut PruneOutcome,
+) -> Vec<(Snapshot, Vec<KeepReason>)> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let deadline = now - i64::from(rule_cfg.keep_orphaned_days) * 24 * 60 * 60;
+
+    let mut dirs: Vec<(&Path, SnapshotLayout)> = rule_cfg
+        .subvolumes
+        .iter()
+        .map(|c| (c.target_dir.as_path(), c.layout))
+        .collect();
+    dirs.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    dirs.dedup();
+    let mut ret = Vec::new();
+    for (dir, layout) in dirs {
+        let orphans = match find_orphans(backend, dir, layout) {
+            Ok(orphans) => orphans,
+            Err(err) => {
+                out.error(format!(
+                    "failed to find orphans in '{}': {}",
+                    dir.display(),
+                    err
+                ));
+                continue;
+            }
+        };
+        for orphan in orphans {
+            let kept_by = if orphan.created.and_utc().timestamp() < deadline {
+                Vec::new()
+            } else {
+                vec![KeepReason::OrphanedDays]
+            };
+            ret.push((orphan, kept_by));
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate};
+
+    use super::*;
+
+    #[test]
+    fn test_retention_decisions() {
+        let newest = NaiveDate::from_ymd_opt(2024, 1, 10)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let items = [
+            ("1h", Duration::hours(1)),
+            ("now", Duration::zero()),
+            ("40d", Duration::days(40)),
+            ("30min", Duration::minutes(30)),
+            ("3d", Duration::days(3)),
+            ("1d", Duration::days(1)),
+        ]
+        .map(|(name, ago)| (newest - ago, name))
+        .to_vec();
+        let rule_cfg = RuleConfig {
+            keep_last: 1,
+            keep_within: "1h".to_owned(),
+            keep_minutely: 2,
+            keep_within_daily: "7d".to_owned(),
+            ..Default::default()
+        };
+
+        let kept_by: Vec<_> = retention_decisions(items, &rule_cfg)
+            .into_iter()
+            .map(|(_, name, kept_by)| (name, kept_by))
+            .collect();
+        assert_eq!(
+            kept_by,
+            [
+                (
+                    "now",
+                    vec![
+                        KeepReason::Last,
+                        KeepReason::Within,
+                        KeepReason::Minutely,
+                        KeepReason::WithinDaily
+                    ]
+                ),
+                ("30min", vec![KeepReason::Within, KeepReason::Minutely]),
+                ("1h", vec![]),
+                ("1d", vec![KeepReason::WithinDaily]),
+                ("3d", vec![KeepReason::WithinDaily]),
+                ("40d", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retention_duration() {
+        assert_eq!(
+            "1y6m2d12h".parse(),
+            Ok(RetentionDuration {
+                months: 18,
+                days: 2,
+                hours: 12
+            })
+        );
+        for invalid in ["", "5", "3w", "d", "0d", "1d2"] {
+            assert!(invalid.parse::<RetentionDuration>().is_err(), "{}", invalid);
+        }
+
+        let t = NaiveDate::from_ymd_opt(2024, 3, 31)
+            .unwrap()
+            .and_hms_opt(6, 0, 0)
+            .unwrap();
+        let d: RetentionDuration = "1m1d12h".parse().unwrap();
+        assert_eq!(
+            d.before(t),
+            NaiveDate::from_ymd_opt(2024, 2, 27)
+                .unwrap()
+                .and_hms_opt(18, 0, 0)
+        );
+
+        let rule_cfg = RuleConfig {
+            keep_within_weekly: "2x".to_owned(),
+            ..Default::default()
+        };
+        assert!(validate_retention(&rule_cfg).is_err());
+    }
+}
@@ -1,11 +1,24 @@
-use zbus::{interface, message::Header, object_server::SignalContext, zvariant::ObjectPath};
+use std::sync::Arc;
 
-use crate::{Polkit, RuleConfig, ToFdo};
+use zbus::{
+    interface,
+    message::Header,
+    object_server::SignalContext,
+    zvariant::{ObjectPath, OwnedObjectPath},
+};
+
+use crate::{
+    preview_prune, AuditLog, BtrfsBackend, Job, Polkit, PruneDecision, RuleConfig, RuleHistory,
+    RuleRun, RuleTimers, ToFdo,
+};
 
 pub struct Rule {
     pub(crate) name: String,
     pub(crate) is_enabled: bool,
+    pub(crate) timers: RuleTimers,
+    pub(crate) backend: Arc<dyn BtrfsBackend>,
     pub(crate) polkit: Polkit,
+    pub(crate) audit: AuditLog,
 }
 
 static ACTION_ID: &str = "org.zhangyuannie.butter.manage-schedule";
@@ -49,6 +62,57 @@ impl Rule {
         server.at(path, self).await?;
         Ok(())
     }
+
+    async fn set_is_enabled_impl(
+        &mut self,
+        header: &Header<'_>,
+        ctx: &SignalContext<'_>,
+        is_enabled: bool,
+    ) -> zbus::fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+
+        let mut cfg = RuleConfig::read(&self.name).to_fdo()?;
+        cfg.is_enabled = is_enabled;
+        cfg.write(&self.name, false).to_fdo()?;
+        self.timers.sync(&self.name, Some(&cfg)).await.to_fdo()?;
+
+        self.maybe_set_is_enabled(ctx, is_enabled).await?;
+        Ok(())
+    }
+
+    async fn set_config_impl(
+        &mut self,
+        header: &Header<'_>,
+        ctx: &SignalContext<'_>,
+        config: RuleConfig,
+    ) -> zbus::fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        config.validate().map_err(zbus::fdo::Error::InvalidArgs)?;
+
+        config.write(&self.name, false).to_fdo()?;
+        self.timers.sync(&self.name, Some(&config)).await.to_fdo()?;
+
+        self.maybe_set_is_enabled(ctx, config.is_enabled).await?;
+
+        Ok(())
+    }
+
+    /// Start `butter schedule <cmd> --rule <name>` as a job
+    async fn run_impl(
+        &self,
+        header: &Header<'_>,
+        conn: &zbus::Connection,
+        cmd: &str,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        // fail early instead of in the job
+        RuleConfig::read(&self.name).to_fdo()?;
+
+        let args = ["schedule", cmd, "--rule", self.name.as_str()]
+            .map(str::to_owned)
+            .to_vec();
+        Ok(Job::spawn(conn, format!("{} '{}'", cmd, self.name), args).await?)
+    }
 }
 
 #[interface(
@@ -66,6 +130,27 @@ impl Rule {
         self.is_enabled
     }
 
+    /// The latest run, with a start time of 0 if the rule never ran
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn last_run(&self) -> zbus::fdo::Result<RuleRun> {
+        Ok(RuleHistory::new(&self.name)
+            .last()
+            .to_fdo()?
+            .unwrap_or_default())
+    }
+
+    /// Errors of the latest run, one per line, empty if it succeeded
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn last_error(&self) -> zbus::fdo::Result<String> {
+        let run = RuleHistory::new(&self.name).last().to_fdo()?;
+        Ok(run.map(|r| r.errors.join("\n")).unwrap_or_default())
+    }
+
+    /// Recent runs, oldest first
+    fn history(&self) -> zbus::fdo::Result<Vec<RuleRun>> {
+        RuleHistory::new(&self.name).read().to_fdo()
+    }
+
     // can not use property: https://github.com/dbus2/zbus/issues/218
     async fn set_is_enabled(
         &mut self,
@@ -73,14 +158,12 @@ impl Rule {
         #[zbus(signal_context)] ctx: SignalContext<'_>,
         is_enabled: bool,
     ) -> zbus::fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-
-        let mut cfg = RuleConfig::read(&self.name).to_fdo()?;
-        cfg.is_enabled = is_enabled;
-        cfg.write(&self.name, false).to_fdo()?;
-
-        self.maybe_set_is_enabled(&ctx, is_enabled).await?;
-        Ok(())
+        let args = vec![self.name.clone(), is_enabled.to_string()];
+        let res = self.set_is_enabled_impl(&header, &ctx, is_enabled).await;
+        self.audit
+            .record(&header, "Rule.SetIsEnabled", args, &res)
+            .await;
+        res
     }
 
     async fn config(
@@ -97,18 +180,61 @@ impl Rule {
         Ok(config)
     }
 
+    /// What pruning would do with each snapshot of the rule, without deleting
+    /// anything. Uses `config` instead of the saved config if given.
+    async fn preview_prune(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        config: Option<RuleConfig>,
+    ) -> zbus::fdo::Result<Vec<PruneDecision>> {
+        // lists and reads arbitrary target directories as root
+        self.polkit.validate(&header, ACTION_ID).await?;
+        let config = match config {
+            Some(config) => config,
+            None => RuleConfig::read(&self.name).to_fdo()?,
+        };
+        preview_prune(self.backend.as_ref(), &config).to_fdo()
+    }
+
+    /// Snapshot the subvolumes of the rule now, returning the path of the
+    /// job doing it
+    async fn run_snapshot(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        let res = self.run_impl(&header, conn, "snapshot").await;
+        self.audit
+            .record(&header, "Rule.RunSnapshot", vec![self.name.clone()], &res)
+            .await;
+        res
+    }
+
+    /// Prune the snapshots of the rule now, returning the path of the job
+    /// doing it
+    async fn run_prune(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] conn: &zbus::Connection,
+    ) -> zbus::fdo::Result<OwnedObjectPath> {
+        let res = self.run_impl(&header, conn, "prune").await;
+        self.audit
+            .record(&header, "Rule.RunPrune", vec![self.name.clone()], &res)
+            .await;
+        res
+    }
+
     async fn set_config(
         &mut self,
         #[zbus(header)] header: Header<'_>,
         #[zbus(signal_context)] ctx: SignalContext<'_>,
         config: RuleConfig,
     ) -> zbus::fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-
-        config.write(&self.name, false).to_fdo()?;
-
-        self.maybe_set_is_enabled(&ctx, config.is_enabled).await?;
-
-        Ok(())
+        let args = vec![self.name.clone(), format!("{:?}", config)];
+        let res = self.set_config_impl(&header, &ctx, config).await;
+        self.audit
+            .record(&header, "Rule.SetConfig", args, &res)
+            .await;
+        res
     }
 }
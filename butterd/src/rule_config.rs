@@ -1,22 +1,52 @@
 use std::{
     fs,
     io::{self, Write},
-    path::{Path, PathBuf},
+    path::PathBuf,
 };
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use zbus::zvariant;
 
-use crate::config;
+use crate::{validate_name_template, validate_retention, DaemonConfig};
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize, zvariant::Type)]
 pub struct RuleConfig {
     pub is_enabled: bool,
+    /// when to snapshot in systemd `OnCalendar` syntax such as `*:0/15`,
+    /// empty to follow the shared hourly timer
+    pub schedule: String,
+    /// keep this many of the newest snapshots
+    pub keep_last: u32,
+    /// keep every snapshot within this [`crate::RetentionDuration`] of the newest
+    /// one, empty to disable
+    pub keep_within: String,
+    pub keep_minutely: u32,
     pub keep_hourly: u32,
     pub keep_daily: u32,
     pub keep_weekly: u32,
     pub keep_monthly: u32,
     pub keep_yearly: u32,
+    /// keep the newest snapshot of every minute within this
+    /// [`crate::RetentionDuration`] of the newest one, empty to disable
+    pub keep_within_minutely: String,
+    pub keep_within_hourly: String,
+    pub keep_within_daily: String,
+    pub keep_within_weekly: String,
+    pub keep_within_monthly: String,
+    pub keep_within_yearly: String,
+    /// delete orphaned snapshots in the target directories after this many
+    /// days, 0 keeps them
+    pub keep_orphaned_days: u32,
+    /// delete the oldest snapshots not kept by `keep_last` or `keep_within`
+    /// until the filesystem of each target directory has this many bytes
+    /// free, 0 to disable. Like the other space limits, this needs quotas to
+    /// tell how much deleting a snapshot frees.
+    pub min_free_bytes: u64,
+    /// like `min_free_bytes` in percent of the filesystem size
+    pub min_free_percent: u32,
+    /// like `min_free_bytes` until the snapshots in each target directory use
+    /// at most this percent of the filesystem size
+    pub max_snapshots_percent: u32,
     pub subvolumes: Vec<RuleSubvolumeConfig>,
 }
 
@@ -24,12 +54,48 @@ pub struct RuleConfig {
 pub struct RuleSubvolumeConfig {
     pub path: PathBuf,
     pub target_dir: PathBuf,
+    /// also snapshot subvolumes nested in `path`
+    #[serde(default)]
+    pub recursive: bool,
+    #[serde(default)]
+    pub layout: SnapshotLayout,
+    /// name of new snapshots as expanded by [`crate::expand_name_template`],
+    /// empty for a random name such as `jovial_koala`. Not used by
+    /// [`SnapshotLayout::Snapper`].
+    #[serde(default)]
+    pub name_template: String,
+}
+
+/// How snapshots are arranged in [`RuleSubvolumeConfig::target_dir`]
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize, Deserialize, zvariant::Type)]
+#[serde(rename_all = "lowercase")]
+#[zvariant(signature = "s")]
+pub enum SnapshotLayout {
+    /// `<target_dir>/<name>`, see [`RuleSubvolumeConfig::name_template`]
+    #[default]
+    Flat,
+    /// `<target_dir>/<N>/snapshot` with an `info.xml`, like snapper
+    Snapper,
 }
 
 impl RuleConfig {
     pub fn path(name: &str) -> PathBuf {
-        Path::new(config::SCHEDULE_DIR).join(name)
+        DaemonConfig::current().schedule_dir.join(name)
     }
+
+    /// Check the retention policy and the name templates
+    pub fn validate(&self) -> Result<(), String> {
+        validate_retention(self)?;
+        for subvol in &self.subvolumes {
+            if !subvol.name_template.is_empty() {
+                validate_name_template(&subvol.name_template).map_err(|err| {
+                    format!("invalid name template '{}': {}", subvol.name_template, err)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn read(name: &str) -> io::Result<Self> {
         let path = Self::path(name);
 
@@ -61,7 +127,9 @@ pub struct ReadScheduleDir(std::fs::ReadDir);
 
 impl ReadScheduleDir {
     pub fn new() -> io::Result<Self> {
-        Ok(Self(std::fs::read_dir(config::SCHEDULE_DIR)?))
+        Ok(Self(std::fs::read_dir(
+            &DaemonConfig::current().schedule_dir,
+        )?))
     }
 }
 
@@ -93,6 +161,14 @@ mod json {
     #[serde(remote = "super::RuleConfig")]
     pub struct RuleConfig {
         pub is_enabled: bool,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub schedule: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub keep_last: u32,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub keep_minutely: u32,
         #[serde(default, skip_serializing_if = "is_default")]
         pub keep_hourly: u32,
         #[serde(default, skip_serializing_if = "is_default")]
@@ -103,7 +179,96 @@ mod json {
         pub keep_monthly: u32,
         #[serde(default, skip_serializing_if = "is_default")]
         pub keep_yearly: u32,
-        #[serde(default, skip_serializing_if = "Vec::is_empty")]
-        pub subvolumes: Vec<RuleSubvolumeConfig>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_minutely: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_hourly: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_daily: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_weekly: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_monthly: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub keep_within_yearly: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub keep_orphaned_days: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub min_free_bytes: u64,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub min_free_percent: u32,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub max_snapshots_percent: u32,
+        #[serde(
+            default,
+            skip_serializing_if = "Vec::is_empty",
+            serialize_with = "serialize_subvolumes",
+            deserialize_with = "deserialize_subvolumes"
+        )]
+        pub subvolumes: Vec<super::RuleSubvolumeConfig>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(remote = "super::RuleSubvolumeConfig")]
+    pub struct RuleSubvolumeConfig {
+        pub path: PathBuf,
+        pub target_dir: PathBuf,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub recursive: bool,
+        #[serde(default, skip_serializing_if = "is_default")]
+        pub layout: SnapshotLayout,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub name_template: String,
+    }
+
+    fn serialize_subvolumes<S: Serializer>(
+        subvols: &[super::RuleSubvolumeConfig],
+        ser: S,
+    ) -> Result<S::Ok, S::Error> {
+        struct Subvol<'a>(&'a super::RuleSubvolumeConfig);
+
+        impl Serialize for Subvol<'_> {
+            fn serialize<T: Serializer>(&self, ser: T) -> Result<T::Ok, T::Error> {
+                RuleSubvolumeConfig::serialize(self.0, ser)
+            }
+        }
+
+        ser.collect_seq(subvols.iter().map(Subvol))
+    }
+
+    fn deserialize_subvolumes<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Vec<super::RuleSubvolumeConfig>, D::Error> {
+        #[derive(Deserialize)]
+        struct Subvol(#[serde(with = "RuleSubvolumeConfig")] super::RuleSubvolumeConfig);
+
+        let subvols = Vec::<Subvol>::deserialize(de)?;
+        Ok(subvols.into_iter().map(|s| s.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_config_json() {
+        let config = RuleConfig {
+            subvolumes: vec![RuleSubvolumeConfig {
+                path: "/home".into(),
+                target_dir: "/snapshots".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let mut bytes = Vec::new();
+        json::RuleConfig::serialize(&config, &mut serde_json::Serializer::new(&mut bytes)).unwrap();
+        // unset options are left out
+        assert_eq!(
+            String::from_utf8(bytes.clone()).unwrap(),
+            r#"{"is_enabled":false,"subvolumes":[{"path":"/home","target_dir":"/snapshots"}]}"#
+        );
+        let mut de = serde_json::Deserializer::from_slice(&bytes);
+        assert_eq!(json::RuleConfig::deserialize(&mut de).unwrap(), config);
     }
 }
@@ -0,0 +1,161 @@
+use std::{
+    fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use zbus::zvariant;
+
+use crate::config;
+
+/// Runs kept for each rule, older ones are dropped
+const MAX_RUNS: usize = 100;
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// What one run of a rule did
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize, zvariant::Type)]
+#[serde(default)]
+pub struct RuleRun {
+    /// seconds since the epoch, 0 if the rule never ran
+    pub started_unix_secs: i64,
+    pub finished_unix_secs: i64,
+    /// snapshots created
+    pub created: Vec<PathBuf>,
+    /// snapshots deleted by the retention policy
+    pub pruned: Vec<PathBuf>,
+    pub errors: Vec<String>,
+}
+
+impl RuleRun {
+    pub fn start() -> Self {
+        Self {
+            started_unix_secs: unix_now(),
+            ..Default::default()
+        }
+    }
+
+    pub fn finish(&mut self) {
+        self.finished_unix_secs = unix_now();
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Past runs of a rule, one JSON object per line, oldest first
+pub struct RuleHistory {
+    path: PathBuf,
+}
+
+impl RuleHistory {
+    /// History of the rule `name` in [`config::RULE_HISTORY_DIR`]
+    pub fn new(name: &str) -> Self {
+        Self::at(Path::new(config::RULE_HISTORY_DIR).join(name))
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Every run kept, oldest first. Empty if the rule never ran.
+    pub fn read(&self) -> io::Result<Vec<RuleRun>> {
+        let f = match fs::File::open(&self.path) {
+            Ok(f) => f,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        io::BufReader::new(f)
+            .lines()
+            .filter(|line| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+
+    pub fn last(&self) -> io::Result<Option<RuleRun>> {
+        Ok(self.read()?.pop())
+    }
+
+    /// Append `run`, dropping the oldest runs beyond the limit
+    pub fn push(&self, run: &RuleRun) -> io::Result<()> {
+        let mut runs = self.read()?;
+        runs.push(run.clone());
+        let skip = runs.len().saturating_sub(MAX_RUNS);
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut f = io::BufWriter::new(fs::File::create(&tmp)?);
+        for run in &runs[skip..] {
+            serde_json::to_writer(&mut f, run)?;
+            f.write_all(b"\n")?;
+        }
+        f.flush()?;
+        drop(f);
+        fs::rename(tmp, &self.path)
+    }
+
+    /// Move the history along with its renamed rule
+    pub fn rename(&self, next: &RuleHistory) -> io::Result<()> {
+        match fs::rename(&self.path, &next.path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    pub fn remove(&self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = RuleHistory::at(dir.path().join("history/db"));
+        assert!(history.read().unwrap().is_empty());
+        assert_eq!(history.last().unwrap(), None);
+
+        for i in 0..105 {
+            let mut run = RuleRun {
+                started_unix_secs: i,
+                created: vec![format!("/snapshots/{}", i).into()],
+                ..Default::default()
+            };
+            if i == 104 {
+                run.errors.push("no space left".to_owned());
+            }
+            run.finish();
+            history.push(&run).unwrap();
+        }
+        let runs = history.read().unwrap();
+        assert_eq!(runs.len(), 100);
+        assert_eq!(runs[0].started_unix_secs, 5);
+        let last = history.last().unwrap().unwrap();
+        assert!(!last.is_ok());
+        assert_eq!(last.created, [Path::new("/snapshots/104")]);
+
+        let renamed = RuleHistory::at(dir.path().join("history/database"));
+        history.rename(&renamed).unwrap();
+        assert!(history.read().unwrap().is_empty());
+        assert_eq!(renamed.read().unwrap().len(), 100);
+        renamed.remove().unwrap();
+        renamed.remove().unwrap();
+        assert!(renamed.read().unwrap().is_empty());
+    }
+}
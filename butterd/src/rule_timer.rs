@@ -0,0 +1,177 @@
+use std::{fs, io, path::PathBuf};
+
+use anyhow::Context;
+use zbus_systemd::systemd1;
+
+use crate::{CalendarSpec, DaemonConfig, RuleConfig, SchedulerKind, SchedulerState};
+
+/// Where administrators put their own units, so that the generated ones
+/// survive reboots and take precedence over packaged ones
+const UNIT_DIR: &str = "/etc/systemd/system";
+const DROP_IN_NAME: &str = "50-butter-schedule.conf";
+/// Shared timer of rules without their own schedule, also telling whether
+/// scheduling is enabled at all
+pub(crate) static SNAPSHOT_UNIT: &str = "butter-schedule-snapshot.timer";
+
+/// Escape `s` for use in a unit name like `systemd-escape` does
+pub fn unit_name_escape(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len());
+    for (i, b) in s.bytes().enumerate() {
+        match b {
+            b'/' => ret.push('-'),
+            b'.' if i == 0 => ret.push_str("\\x2e"),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => ret.push(b as char),
+            _ => ret.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    ret
+}
+
+/// Instance of `butter-rule@.timer` running the rule `name`
+pub fn rule_timer_unit(name: &str) -> String {
+    format!("butter-rule@{}.timer", unit_name_escape(name))
+}
+
+/// Refuse schedules that would break out of the `OnCalendar=` line. The
+/// syntax itself is checked by systemd when the timer is loaded.
+pub fn validate_calendar(calendar: &str) -> io::Result<()> {
+    if calendar.trim().is_empty()
+        || calendar
+            .chars()
+            .any(|c| c.is_control() || c == '\\' || c == '%')
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid schedule '{}'", calendar),
+        ));
+    }
+    Ok(())
+}
+
+/// Drop-in giving the timer of a rule its schedule
+pub fn rule_timer_drop_in(calendar: &str) -> io::Result<String> {
+    validate_calendar(calendar)?;
+    Ok(format!(
+        "# Generated by butterd, changes are overwritten\n[Timer]\nOnCalendar={}\n",
+        calendar.trim()
+    ))
+}
+
+/// Manages the timer of each rule with its own schedule.
+///
+/// The units are instances of the packaged `butter-rule@.timer` and
+/// `butter-rule@.service` templates, with the schedule in a generated
+/// drop-in. A timer only runs while its rule and scheduling are enabled.
+///
+/// With [`SchedulerKind::Builtin`] no units are managed, butterd running the
+/// rules itself.
+#[derive(Clone)]
+pub(crate) struct RuleTimers {
+    conn: zbus::Connection,
+}
+
+impl RuleTimers {
+    pub fn new(conn: zbus::Connection) -> Self {
+        Self { conn }
+    }
+
+    fn drop_in_dir(name: &str) -> PathBuf {
+        PathBuf::from(UNIT_DIR).join(format!("{}.d", rule_timer_unit(name)))
+    }
+
+    pub async fn is_schedule_enabled(&self) -> zbus::Result<bool> {
+        if DaemonConfig::current().scheduler == SchedulerKind::Builtin {
+            return Ok(SchedulerState::read()
+                .map_err(|err| zbus::Error::Failure(err.to_string()))?
+                .is_enabled);
+        }
+        let systemd = systemd1::ManagerProxy::new(&self.conn).await?;
+        let p = match systemd.get_unit(SNAPSHOT_UNIT.into()).await {
+            Ok(p) => p,
+            Err(_) => systemd.load_unit(SNAPSHOT_UNIT.into()).await?,
+        };
+
+        let unit = systemd1::UnitProxy::new(&self.conn, p).await?;
+        let state = unit.active_state().await?;
+
+        Ok(state == "active" || state == "reloading")
+    }
+
+    /// Bring the timer of the rule `name` in line with `config`, `None`
+    /// removing it
+    pub async fn sync(&self, name: &str, config: Option<&RuleConfig>) -> anyhow::Result<()> {
+        let systemd = systemd1::ManagerProxy::new(&self.conn).await?;
+        let unit = rule_timer_unit(name);
+        let dir = Self::drop_in_dir(name);
+        let calendar = config
+            .map(|c| c.schedule.as_str())
+            .filter(|s| !s.is_empty());
+
+        if DaemonConfig::current().scheduler == SchedulerKind::Builtin {
+            if let Some(calendar) = calendar {
+                calendar
+                    .parse::<CalendarSpec>()
+                    .map_err(|err| anyhow::anyhow!("invalid schedule '{}': {}", calendar, err))?;
+            }
+            return Ok(());
+        }
+
+        match calendar {
+            Some(calendar) => {
+                let drop_in = rule_timer_drop_in(calendar)?;
+                fs::create_dir_all(&dir)?;
+                fs::write(dir.join(DROP_IN_NAME), drop_in)
+                    .with_context(|| format!("failed to write drop-in of '{}'", unit))?;
+            }
+            None => {
+                if !dir.exists() {
+                    return Ok(());
+                }
+            }
+        }
+
+        let is_active = calendar.is_some()
+            && config.is_some_and(|c| c.is_enabled)
+            && self.is_schedule_enabled().await?;
+        if is_active {
+            systemd.reload().await?;
+            systemd
+                .enable_unit_files(vec![unit.clone()], false, true)
+                .await?;
+            // restart to pick up a changed schedule
+            systemd.restart_unit(unit, "replace".into()).await?;
+        } else {
+            // fails harmlessly if never started or enabled
+            let _ = systemd.stop_unit(unit.clone(), "replace".into()).await;
+            let _ = systemd.disable_unit_files(vec![unit], false).await;
+            if calendar.is_none() {
+                fs::remove_dir_all(&dir)?;
+            }
+            systemd.reload().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_timer_units() {
+        assert_eq!(rule_timer_unit("db"), "butter-rule@db.timer");
+        assert_eq!(
+            rule_timer_unit("my-rule/.x y"),
+            "butter-rule@my\\x2drule-.x\\x20y.timer"
+        );
+        assert_eq!(unit_name_escape(".hidden"), "\\x2ehidden");
+
+        assert_eq!(
+            rule_timer_drop_in(" *:0/15 ").unwrap(),
+            "# Generated by butterd, changes are overwritten\n[Timer]\nOnCalendar=*:0/15\n"
+        );
+        assert!(rule_timer_drop_in("daily\nExecStart=/bin/sh").is_err());
+        assert!(rule_timer_drop_in("%n").is_err());
+        assert!(rule_timer_drop_in(" ").is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 
 use anyhow::Context;
 use zbus::{
@@ -9,28 +9,42 @@ use zbus::{
 };
 use zbus_systemd::systemd1;
 
-use crate::{object_path_escape, Polkit, ReadScheduleDir, Rule, RuleConfig, ToFdo};
+use crate::{
+    object_path_escape, rule_timer::SNAPSHOT_UNIT, AuditLog, BtrfsBackend, DaemonConfig, Polkit,
+    ReadScheduleDir, Rule, RuleConfig, RuleHistory, RuleTimers, SchedulerKind, SchedulerState,
+    ToFdo,
+};
 
 pub struct Schedule {
     is_enabled: bool,
     rules: HashMap<String, OwnedObjectPath>,
     conn: zbus::Connection,
+    timers: RuleTimers,
+    backend: Arc<dyn BtrfsBackend>,
     polkit: Polkit,
+    audit: AuditLog,
 }
 
 static ACTION_ID: &str = "org.zhangyuannie.butter.manage-schedule";
-static SNAPSHOT_UNIT: &str = "butter-schedule-snapshot.timer";
 static PRUNE_UNIT: &str = "butter-schedule-prune.timer";
 
 impl Schedule {
     pub const PATH: ObjectPath<'static> =
         ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/Schedule");
 
-    pub async fn new(conn: zbus::Connection) -> zbus::Result<Self> {
+    pub async fn new(
+        conn: zbus::Connection,
+        backend: Arc<dyn BtrfsBackend>,
+        polkit: Polkit,
+        audit: AuditLog,
+    ) -> zbus::Result<Self> {
         Ok(Self {
             is_enabled: false,
             rules: Default::default(),
-            polkit: Polkit::new(&conn).await?,
+            audit,
+            polkit,
+            timers: RuleTimers::new(conn.clone()),
+            backend,
             conn,
         })
     }
@@ -46,16 +60,7 @@ impl Schedule {
     }
 
     async fn refresh_is_enabled(&mut self, ctx: &SignalContext<'_>) -> zbus::Result<()> {
-        let systemd = systemd1::ManagerProxy::new(&self.conn).await?;
-        let p = match systemd.get_unit(SNAPSHOT_UNIT.into()).await {
-            Ok(p) => p,
-            Err(_) => systemd.load_unit(SNAPSHOT_UNIT.into()).await?,
-        };
-
-        let unit = systemd1::UnitProxy::new(&self.conn, p).await?;
-        let state = unit.active_state().await?;
-
-        let is_enabled = state == "active" || state == "reloading";
+        let is_enabled = self.timers.is_schedule_enabled().await?;
 
         if self.is_enabled != is_enabled {
             self.is_enabled = is_enabled;
@@ -83,31 +88,20 @@ impl Schedule {
         self.rules.remove(name);
         Ok(())
     }
-}
-
-#[interface(
-    name = "org.zhangyuannie.Butter1.Schedule",
-    proxy(
-        gen_blocking = true,
-        default_service = "org.zhangyuannie.Butter1",
-        default_path = "/org/zhangyuannie/Butter1/Schedule",
-    )
-)]
-impl Schedule {
-    #[zbus(property)]
-    fn is_enabled(&self) -> bool {
-        self.is_enabled
-    }
 
-    // can not use property: https://github.com/dbus2/zbus/issues/218
-    async fn set_is_enabled(
+    async fn set_is_enabled_impl(
         &mut self,
-        #[zbus(header)] header: Header<'_>,
-        #[zbus(signal_context)] ctx: SignalContext<'_>,
-
+        header: &Header<'_>,
+        ctx: &SignalContext<'_>,
         is_enabled: bool,
     ) -> fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
+        self.polkit.validate(header, ACTION_ID).await?;
+
+        if DaemonConfig::current().scheduler == SchedulerKind::Builtin {
+            SchedulerState::update(|s| s.is_enabled = is_enabled).to_fdo()?;
+            self.refresh_is_enabled(ctx).await?;
+            return Ok(());
+        }
 
         let systemd = systemd1::ManagerProxy::new(&self.conn).await?;
 
@@ -129,11 +123,137 @@ impl Schedule {
             .await?;
         }
 
-        self.refresh_is_enabled(&ctx).await?;
+        self.refresh_is_enabled(ctx).await?;
+        self.sync_timers().await.to_fdo()?;
 
         Ok(())
     }
 
+    /// Bring the timer of every rule in line with its config
+    async fn sync_timers(&self) -> anyhow::Result<()> {
+        for (name, config) in ReadScheduleDir::new()?.flatten() {
+            self.timers
+                .sync(&name, Some(&config))
+                .await
+                .with_context(|| format!("failed to set up the timer of '{}'", name))?;
+        }
+        Ok(())
+    }
+
+    async fn create_rule_impl(
+        &mut self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        name: String,
+        config: RuleConfig,
+    ) -> fdo::Result<OwnedObjectPath> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        config.validate().map_err(fdo::Error::InvalidArgs)?;
+
+        config
+            .write(&name, true)
+            .context("failed to write")
+            .to_fdo()?;
+        self.timers.sync(&name, Some(&config)).await.to_fdo()?;
+
+        let rule = Rule {
+            name,
+            is_enabled: config.is_enabled,
+            timers: self.timers.clone(),
+            backend: self.backend.clone(),
+            polkit: self.polkit.clone(),
+            audit: self.audit.clone(),
+        };
+
+        self.up(server, rule).await.to_fdo()
+    }
+
+    async fn remove_rule_impl(
+        &mut self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        name: &str,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        tokio::fs::remove_file(RuleConfig::path(name))
+            .await
+            .to_fdo()?;
+        self.timers.sync(name, None).await.to_fdo()?;
+        RuleHistory::new(name).remove().to_fdo()?;
+
+        self.down(server, name).await.to_fdo()
+    }
+
+    async fn move_rule_impl(
+        &mut self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        prev: &str,
+        next: String,
+    ) -> fdo::Result<OwnedObjectPath> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let dst = RuleConfig::path(&next);
+        if dst.exists() {
+            // best efforts
+            return Err(fdo::Error::InvalidArgs("Target already exists".to_owned()));
+        }
+
+        std::fs::rename(RuleConfig::path(prev), dst).to_fdo()?;
+        RuleHistory::new(prev)
+            .rename(&RuleHistory::new(&next))
+            .to_fdo()?;
+        self.down(server, &next).await.to_fdo()?;
+
+        let config = RuleConfig::read(&next).to_fdo()?;
+        self.timers.sync(prev, None).await.to_fdo()?;
+        self.timers.sync(&next, Some(&config)).await.to_fdo()?;
+
+        let rule = Rule {
+            name: next,
+            is_enabled: config.is_enabled,
+            timers: self.timers.clone(),
+            backend: self.backend.clone(),
+            polkit: self.polkit.clone(),
+            audit: self.audit.clone(),
+        };
+        self.up(server, rule).await.to_fdo()
+    }
+}
+
+#[interface(
+    name = "org.zhangyuannie.Butter1.Schedule",
+    proxy(
+        gen_blocking = true,
+        default_service = "org.zhangyuannie.Butter1",
+        default_path = "/org/zhangyuannie/Butter1/Schedule",
+    )
+)]
+impl Schedule {
+    #[zbus(property)]
+    fn is_enabled(&self) -> bool {
+        self.is_enabled
+    }
+
+    // can not use property: https://github.com/dbus2/zbus/issues/218
+    async fn set_is_enabled(
+        &mut self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(signal_context)] ctx: SignalContext<'_>,
+
+        is_enabled: bool,
+    ) -> fdo::Result<()> {
+        let res = self.set_is_enabled_impl(&header, &ctx, is_enabled).await;
+        self.audit
+            .record(
+                &header,
+                "Schedule.SetIsEnabled",
+                vec![is_enabled.to_string()],
+                &res,
+            )
+            .await;
+        res
+    }
+
     pub async fn refresh(
         &mut self,
         #[zbus(object_server)] server: &zbus::ObjectServer,
@@ -164,7 +284,10 @@ impl Schedule {
             let rule = Rule {
                 name: name.clone(),
                 is_enabled: config.is_enabled,
+                timers: self.timers.clone(),
+                backend: self.backend.clone(),
                 polkit: self.polkit.clone(),
+                audit: self.audit.clone(),
             };
             if let Some(path) = self.rules.get(&name) {
                 rule.update(server, path).await.to_fdo()?;
@@ -186,20 +309,12 @@ impl Schedule {
         name: String,
         config: RuleConfig,
     ) -> fdo::Result<OwnedObjectPath> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-
-        config
-            .write(&name, true)
-            .context("failed to write")
-            .to_fdo()?;
-
-        let rule = Rule {
-            name,
-            is_enabled: config.is_enabled,
-            polkit: self.polkit.clone(),
-        };
-
-        self.up(server, rule).await.to_fdo()
+        let args = vec![name.clone(), format!("{:?}", config)];
+        let res = self.create_rule_impl(&header, server, name, config).await;
+        self.audit
+            .record(&header, "Schedule.CreateRule", args, &res)
+            .await;
+        res
     }
 
     async fn remove_rule(
@@ -208,12 +323,11 @@ impl Schedule {
         #[zbus(object_server)] server: &zbus::ObjectServer,
         name: String,
     ) -> zbus::fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-        tokio::fs::remove_file(RuleConfig::path(&name))
-            .await
-            .to_fdo()?;
-
-        self.down(server, &name).await.to_fdo()
+        let res = self.remove_rule_impl(&header, server, &name).await;
+        self.audit
+            .record(&header, "Schedule.RemoveRule", vec![name], &res)
+            .await;
+        res
     }
 
     async fn move_rule(
@@ -223,24 +337,12 @@ impl Schedule {
         prev: String,
         next: String,
     ) -> zbus::fdo::Result<OwnedObjectPath> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-        let dst = RuleConfig::path(&next);
-        if dst.exists() {
-            // best efforts
-            return Err(fdo::Error::InvalidArgs("Target already exists".to_owned()));
-        }
-
-        std::fs::rename(RuleConfig::path(&prev), dst).to_fdo()?;
-        self.down(server, &next).await.to_fdo()?;
-
-        let config = RuleConfig::read(&next).to_fdo()?;
-
-        let rule = Rule {
-            name: next,
-            is_enabled: config.is_enabled,
-            polkit: self.polkit.clone(),
-        };
-        self.up(server, rule).await.to_fdo()
+        let args = vec![prev.clone(), next.clone()];
+        let res = self.move_rule_impl(&header, server, &prev, next).await;
+        self.audit
+            .record(&header, "Schedule.MoveRule", args, &res)
+            .await;
+        res
     }
 
     async fn get_rule(
@@ -258,7 +360,10 @@ impl Schedule {
         let rule = Rule {
             name,
             is_enabled: config.is_enabled,
+            timers: self.timers.clone(),
+            backend: self.backend.clone(),
             polkit: self.polkit.clone(),
+            audit: self.audit.clone(),
         };
 
         self.up(server, rule).await.to_fdo()
@@ -0,0 +1,203 @@
+use std::{collections::HashMap, fs, io, path::Path, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::{config, CalendarSpec, DaemonConfig, ReadScheduleDir, SchedulerKind};
+
+/// Schedule of rules without their own, like `butter-schedule-snapshot.timer`
+const DEFAULT_CALENDAR: &str = "hourly";
+/// Seconds between pruning every rule, like `butter-schedule-prune.timer`
+const PRUNE_INTERVAL: i64 = 8 * 60 * 60;
+/// How often rules are checked. The wall clock is compared on every tick, so
+/// runs missed while suspended happen on the first tick after resuming.
+const TICK: Duration = Duration::from_secs(60);
+
+static STATE_LOCK: Mutex<()> = Mutex::new(());
+
+/// What the built-in scheduler keeps across restarts, at
+/// [`config::SCHEDULER_STATE`]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchedulerState {
+    /// whether scheduling is enabled, in place of the state of the systemd
+    /// timers
+    pub is_enabled: bool,
+    /// when each rule last ran, in seconds since the epoch
+    pub last_runs: HashMap<String, i64>,
+    /// when every rule was last pruned, in seconds since the epoch
+    pub last_prune: Option<i64>,
+}
+
+impl SchedulerState {
+    /// Read the state file, falling back to defaults if it does not exist
+    pub fn read() -> io::Result<Self> {
+        match fs::read(config::SCHEDULER_STATE) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let path = Path::new(config::SCHEDULER_STATE);
+        // unwrap: the path is absolute
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)
+    }
+
+    /// Change the state file with `f`
+    pub fn update(f: impl FnOnce(&mut Self)) -> io::Result<Self> {
+        let _guard = STATE_LOCK.lock().unwrap();
+        let mut state = Self::read()?;
+        f(&mut state);
+        state.write()?;
+        Ok(state)
+    }
+}
+
+/// Whether the built-in scheduler is selected and enabled, in which case
+/// butterd must keep running
+pub fn is_builtin_scheduler_active() -> bool {
+    DaemonConfig::current().scheduler == SchedulerKind::Builtin
+        && SchedulerState::read().is_ok_and(|s| s.is_enabled)
+}
+
+/// Whether `spec` elapsed between `last` and `now`, in seconds since the
+/// epoch. Missed runs are only caught up once, like `Persistent=true`.
+pub fn is_due(spec: &CalendarSpec, last: i64, now: i64) -> bool {
+    let (Some(last), Some(now)) = (
+        DateTime::from_timestamp(last, 0),
+        DateTime::from_timestamp(now, 0),
+    ) else {
+        return true;
+    };
+    spec.next_after(last.with_timezone(&Local).naive_local())
+        .is_some_and(|next| next <= now.with_timezone(&Local).naive_local())
+}
+
+/// Run the `butter` CLI like the systemd services do
+pub(crate) async fn butter(args: &[&str]) -> io::Result<()> {
+    let status = tokio::process::Command::new(Path::new(config::BINDIR).join("butter"))
+        .args(args)
+        .status()
+        .await?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "butter {} {}",
+            args.join(" "),
+            status
+        )))
+    }
+}
+
+async fn tick() -> io::Result<()> {
+    let state = SchedulerState::read()?;
+    if !state.is_enabled {
+        return Ok(());
+    }
+    let now = Local::now().timestamp();
+
+    let configs: Vec<_> = ReadScheduleDir::new()?.flatten().collect();
+    let mut ran = Vec::new();
+    for (name, config) in &configs {
+        if !config.is_enabled {
+            continue;
+        }
+        let calendar = match config.schedule.as_str() {
+            "" => DEFAULT_CALENDAR,
+            s => s,
+        };
+        let spec: CalendarSpec = match calendar.parse() {
+            Ok(spec) => spec,
+            Err(err) => {
+                warn!("invalid schedule of rule '{}': {}", name, err);
+                continue;
+            }
+        };
+        match state.last_runs.get(name) {
+            Some(&last) if !is_due(&spec, last, now) => continue,
+            Some(_) => {
+                info!("running rule '{}'", name);
+                if let Err(err) = butter(&["schedule", "run", name]).await {
+                    warn!("failed to run rule '{}': {}", name, err);
+                }
+            }
+            // first seen, wait for the next time it elapses
+            None => {}
+        }
+        ran.push(name.clone());
+    }
+
+    let prune_due = state.last_prune.map(|last| now - last >= PRUNE_INTERVAL);
+    if prune_due == Some(true) {
+        info!("pruning every rule");
+        if let Err(err) = butter(&["schedule", "prune"]).await {
+            warn!("failed to prune: {}", err);
+        }
+    }
+
+    SchedulerState::update(|s| {
+        s.last_runs
+            .retain(|name, _| configs.iter().any(|(n, _)| n == name));
+        for name in ran {
+            s.last_runs.insert(name, now);
+        }
+        if prune_due != Some(false) {
+            s.last_prune = Some(now);
+        }
+    })?;
+    Ok(())
+}
+
+/// Run rules on their schedule while [`DaemonConfig::scheduler`] is
+/// [`SchedulerKind::Builtin`] and scheduling is enabled. Never returns.
+///
+/// Rules run with `butter schedule run`, the same command as their systemd
+/// service. Without systemd, butterd must be started on boot for rules to run.
+pub async fn run_builtin_scheduler() {
+    let mut interval = tokio::time::interval(TICK);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if DaemonConfig::current().scheduler != SchedulerKind::Builtin {
+            continue;
+        }
+        if let Err(err) = tick().await {
+            warn!("built-in scheduler failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn test_builtin_scheduler_is_due() {
+        let at = |d, h, m| {
+            Local
+                .with_ymd_and_hms(2024, 6, d, h, m, 0)
+                .unwrap()
+                .timestamp()
+        };
+        let hourly: CalendarSpec = "hourly".parse().unwrap();
+        assert!(!is_due(&hourly, at(12, 10, 30), at(12, 10, 59)));
+        assert!(is_due(&hourly, at(12, 10, 30), at(12, 11, 0)));
+        // missed while suspended
+        assert!(is_due(&hourly, at(12, 10, 30), at(15, 9, 0)));
+
+        let nightly: CalendarSpec = "Mon..Fri 02:00".parse().unwrap();
+        // 2024-06-14 is a Friday
+        assert!(!is_due(&nightly, at(14, 2, 0), at(16, 23, 0)));
+        assert!(is_due(&nightly, at(14, 2, 0), at(17, 2, 0)));
+    }
+}
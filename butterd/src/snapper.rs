@@ -0,0 +1,175 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, NaiveDateTime};
+
+use crate::{create_snapshot, BtrfsBackend, SnapshotGroup};
+
+/// Name of the subvolume in each numbered directory
+const SNAPSHOT_NAME: &str = "snapshot";
+const INFO_NAME: &str = "info.xml";
+const DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// What snapper keeps about a snapshot in `<N>/info.xml`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SnapperInfo {
+    pub number: u32,
+    /// `single`, `pre` or `post`
+    pub kind: String,
+    /// number of the matching `pre` snapshot of a `post` snapshot
+    pub pre_number: Option<u32>,
+    /// in UTC, as snapper records it
+    pub created_unix_secs: i64,
+    pub description: String,
+}
+
+/// Text of the first `<tag>` element of a flat XML document
+fn xml_text(xml: &str, tag: &str) -> Option<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = start + xml[start..].find(&close)?;
+    Some(
+        xml[start..end]
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&"),
+    )
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl SnapperInfo {
+    /// Parse the `info.xml` in the numbered directory `container`
+    pub fn read(container: &Path) -> Option<Self> {
+        let xml = fs::read_to_string(container.join(INFO_NAME)).ok()?;
+        let number = xml_text(&xml, "num")?.trim().parse().ok()?;
+        let created_unix_secs = xml_text(&xml, "date")
+            .and_then(|s| NaiveDateTime::parse_from_str(s.trim(), DATE_FORMAT).ok())
+            .map_or(0, |dt| dt.and_utc().timestamp());
+        Some(Self {
+            number,
+            kind: xml_text(&xml, "type").unwrap_or_default(),
+            pre_number: xml_text(&xml, "pre_num").and_then(|s| s.trim().parse().ok()),
+            created_unix_secs,
+            description: xml_text(&xml, "description").unwrap_or_default(),
+        })
+    }
+
+    /// Save as the `info.xml` in the numbered directory `container`
+    pub fn write(&self, container: &Path) -> io::Result<()> {
+        let date = DateTime::from_timestamp(self.created_unix_secs, 0)
+            .unwrap_or_default()
+            .format(DATE_FORMAT);
+        let mut f = BufWriter::new(File::create(container.join(INFO_NAME))?);
+        writeln!(f, "<?xml version=\"1.0\"?>")?;
+        writeln!(f, "<snapshot>")?;
+        writeln!(f, "  <type>{}</type>", xml_escape(&self.kind))?;
+        writeln!(f, "  <num>{}</num>", self.number)?;
+        writeln!(f, "  <date>{}</date>", date)?;
+        if let Some(pre_number) = self.pre_number {
+            writeln!(f, "  <pre_num>{}</pre_num>", pre_number)?;
+        }
+        if !self.description.is_empty() {
+            writeln!(
+                f,
+                "  <description>{}</description>",
+                xml_escape(&self.description)
+            )?;
+        }
+        writeln!(f, "</snapshot>")?;
+        f.flush()
+    }
+}
+
+/// Subvolumes laid out as `<dir>/<N>/snapshot` like snapper does, with the
+/// numbered directory of each
+pub fn snapper_snapshots(
+    backend: &dyn BtrfsBackend,
+    dir: &Path,
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let container = entry?.path();
+        let path = container.join(SNAPSHOT_NAME);
+        if snapper_number(&path).is_some() && backend.is_subvolume(&path).unwrap_or(false) {
+            ret.push((path, container));
+        }
+    }
+    Ok(ret)
+}
+
+/// The number of the snapshot at `path` if it is laid out like snapper does
+pub fn snapper_number(path: &Path) -> Option<u32> {
+    if path.file_name()? != SNAPSHOT_NAME {
+        return None;
+    }
+    path.parent()?.file_name()?.to_str()?.parse().ok()
+}
+
+/// Create the next numbered directory in `dir`
+pub fn create_snapper_container(dir: &Path) -> io::Result<(u32, PathBuf)> {
+    fs::create_dir_all(dir)?;
+    let mut number = 1;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(n) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        {
+            number = number.max(n + 1);
+        }
+    }
+    loop {
+        let container = dir.join(number.to_string());
+        match fs::create_dir(&container) {
+            Ok(_) => return Ok((number, container)),
+            // taken in the meantime
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => number += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Snapshot `src_path` as the next `<target_dir>/<N>/snapshot`, described by
+/// an `info.xml` for tools expecting snapper's layout.
+///
+/// See [`create_snapshot`] for the other arguments. Returns the path of the
+/// snapshot.
+pub fn create_snapper_snapshot(
+    backend: &dyn BtrfsBackend,
+    src_path: &Path,
+    target_dir: &Path,
+    recursive: bool,
+    group: Option<&SnapshotGroup>,
+) -> io::Result<PathBuf> {
+    let (number, container) = create_snapper_container(target_dir)?;
+    let path = container.join(SNAPSHOT_NAME);
+    let res = create_snapshot(backend, src_path, &path, true, recursive, group).and_then(|_| {
+        SnapperInfo {
+            number,
+            kind: "single".to_owned(),
+            created_unix_secs: backend.subvolume_info(&path)?.otime,
+            description: "butter".to_owned(),
+            ..Default::default()
+        }
+        .write(&container)
+    });
+    if let Err(err) = res {
+        if !path.exists() {
+            let _ = fs::remove_dir_all(&container);
+        }
+        return Err(err);
+    }
+    Ok(path)
+}
@@ -1,7 +1,12 @@
-use std::{collections::HashMap, fs, io};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use anyhow::Context;
-use libblkid_rs::{evaluate_spec, BlkidCache};
+use tracing::{info, warn};
 use uuid::Uuid;
 use zbus::{
     fdo, interface,
@@ -9,120 +14,700 @@ use zbus::{
     zvariant::{ObjectPath, OwnedObjectPath},
 };
 
-use crate::{create_snapshot, Filesystem, MntEntries, Polkit, ToFdo, ZPathBuf};
+use crate::{
+    convert_to_subvolume, create_snapshot, create_subvolume, delete_subvolume, import_snapshots,
+    migrate_metadata, AuditLog, BtrfsBackend, DaemonConfig, Filesystem, ImportTool, Polkit,
+    RuleConfig, SnapshotMetadata, SubvolumeCreateOptions, SubvolumeProperties, ToFdo, ZPathBuf,
+    ZUuid, BTRFS_LABEL_MAX,
+};
 
 pub struct Storage {
     filesystems: HashMap<Uuid, OwnedObjectPath>,
+    backend: Arc<dyn BtrfsBackend>,
     polkit: Polkit,
+    audit: AuditLog,
 }
 
 static ACTION_ID: &str = "org.zhangyuannie.butter.manage-subvolume";
+static FORCE_ACTION_ID: &str = "org.zhangyuannie.butter.manage-subvolume-force";
+
+/// Suffix of subvolumes moved aside by restoring a snapshot, followed by the
+/// Unix time they were moved at
+const RESTORE_BACKUP_INFIX: &str = ".pre-restore-";
 
 impl Storage {
     pub const PATH: ObjectPath<'static> =
         ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/Storage");
 
-    pub async fn new(polkit: Polkit) -> zbus::Result<Self> {
+    pub async fn new(
+        backend: Arc<dyn BtrfsBackend>,
+        polkit: Polkit,
+        audit: AuditLog,
+    ) -> zbus::Result<Self> {
         Ok(Self {
             filesystems: Default::default(),
+            backend,
             polkit,
+            audit,
         })
     }
 
-    fn subvol_id_from_mnt_options(options: &str) -> Option<u64> {
-        for seg in options.split(',') {
-            if let Some(id) = seg.strip_prefix("subvolid=") {
-                return id.parse::<u64>().ok();
+    fn probe_btrfs_devices(&self) -> anyhow::Result<HashMap<Uuid, Filesystem>> {
+        Ok(self
+            .backend
+            .probe_filesystems()?
+            .into_iter()
+            .map(|probed| (probed.uuid, Filesystem::new(probed, self.backend.clone())))
+            .collect())
+    }
+
+    async fn refresh_impl(&mut self, server: &zbus::ObjectServer) -> anyhow::Result<()> {
+        let next_filesystems = self.probe_btrfs_devices()?;
+
+        let mut to_remove = Vec::new();
+        for (uuid, path) in &self.filesystems {
+            if !next_filesystems.contains_key(uuid) {
+                to_remove.push((*uuid, path.clone()));
+            }
+        }
+        let remove_futs = to_remove
+            .iter()
+            .map(|(_, path)| server.remove::<Filesystem, _>(path));
+        futures::future::join_all(remove_futs).await;
+
+        for (uuid, _) in to_remove {
+            self.filesystems.remove(&uuid);
+        }
+
+        for (uuid, fs) in next_filesystems {
+            if let Some(path) = self.filesystems.get(&uuid) {
+                fs.update(server, path).await?;
+            } else {
+                let path = OwnedObjectPath::try_from(format!("{}/{}", Self::PATH, uuid.simple()))?;
+                fs.create(server, &path).await?;
+                self.filesystems.insert(uuid, path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Make every filesystem list its subvolumes again, as changes made within
+    /// the running transaction do not advance the generation
+    async fn invalidate_subvolumes(&self, server: &zbus::ObjectServer) {
+        for fs_path in self.filesystems.values() {
+            if let Ok(iface_ref) = server.interface::<_, Filesystem>(fs_path).await {
+                iface_ref.get().await.invalidate_subvolumes();
             }
         }
-        None
     }
 
-    fn probe_btrfs_devices() -> anyhow::Result<HashMap<Uuid, Filesystem>> {
-        let mut ret = HashMap::new();
+    /// Delete subvolumes moved aside by restoring a snapshot more than
+    /// `retention_secs` ago, returning how many were deleted
+    pub async fn empty_trash(
+        &self,
+        server: &zbus::ObjectServer,
+        retention_secs: u64,
+    ) -> anyhow::Result<u32> {
+        if retention_secs == 0 {
+            return Ok(0);
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let config = DaemonConfig::current();
+        let mut ret = 0;
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            let mut deleted = 0;
+            for subvol in fs.subvolumes()? {
+                let moved_secs = subvol
+                    .root_path
+                    .as_path()
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.rsplit_once(RESTORE_BACKUP_INFIX))
+                    .and_then(|(_, secs)| secs.parse::<u64>().ok());
+                let Some(moved_secs) = moved_secs else {
+                    continue;
+                };
+                let Some(path) = subvol.paths.first() else {
+                    continue;
+                };
+                let path = path.as_path();
+                // backups are plain subvolumes, so only what is in use is kept
+                if now.saturating_sub(moved_secs) < retention_secs
+                    || subvol.is_mountpoint
+                    || subvol
+                        .paths
+                        .iter()
+                        .any(|p| config.is_protected_path(p.as_path()))
+                    || self.backend.default_subvolume(path)? == subvol.id
+                {
+                    continue;
+                }
+                info!("deleting old restore backup '{}'", path.display());
+                delete_subvolume(self.backend.as_ref(), path)
+                    .with_context(|| format!("Failed to delete '{}'", path.display()))?;
+                deleted += 1;
+            }
+            if deleted > 0 {
+                fs.invalidate_subvolumes();
+            }
+            ret += deleted;
+        }
+        Ok(ret)
+    }
 
-        let mut cache = BlkidCache::get_cache(None)?;
-        cache.probe_all()?;
+    /// Refuse to touch anything that is not a known, non-primary subvolume.
+    ///
+    /// Returns the canonical path of the subvolume, which is what callers
+    /// should operate on, so that `..` components and symlinks cannot lead a
+    /// checked path to a protected subvolume.
+    async fn ensure_unprotected(
+        &self,
+        server: &zbus::ObjectServer,
+        path: &Path,
+    ) -> fdo::Result<PathBuf> {
+        let path = &canonicalize(path)?;
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            if !fs.contains_path(path) {
+                continue;
+            }
+            if let Some((subvol, is_default)) = fs.subvolume_at(path).to_fdo()? {
+                if is_default || subvol.is_likely_primary() {
+                    return Err(fdo::Error::AccessDenied(format!(
+                        "'{}' is a protected subvolume",
+                        path.display()
+                    )));
+                }
+                return Ok(path.clone());
+            }
+        }
 
-        let mut uuid_by_devname = HashMap::new();
+        Err(fdo::Error::InvalidArgs(format!(
+            "'{}' is not a subvolume on a known Btrfs filesystem",
+            path.display()
+        )))
+    }
 
-        for dev in cache.iter().search("TYPE", "btrfs")? {
-            if let Some(dev) = cache.verify(dev) {
-                let devname = dev.devname()?;
-                let uuid = cache.get_tag_value("UUID", &devname)?;
-                let uuid = Uuid::try_parse(&uuid)?;
-                let label = cache.get_tag_value("LABEL", &devname)?;
-                let vref = ret.entry(uuid).or_insert(Filesystem {
-                    uuid: uuid.into(),
-                    label,
-                    devices: Vec::new(),
-                    mount_points_by_subvol_id: Default::default(),
-                });
+    /// A path through which the subvolume at `root_path`, on the same
+    /// filesystem as `path`, can be moved
+    async fn movable_path(
+        &self,
+        server: &zbus::ObjectServer,
+        path: &Path,
+        root_path: &Path,
+    ) -> fdo::Result<PathBuf> {
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            if !fs.contains_path(path) {
+                continue;
+            }
+            if let Some(movable) = fs.movable_path(root_path).to_fdo()? {
+                return Ok(movable);
+            }
+        }
 
-                uuid_by_devname.insert(devname.clone(), uuid);
+        Err(fdo::Error::InvalidArgs(format!(
+            "'{}' is missing or only reachable through its mount point",
+            root_path.display()
+        )))
+    }
 
-                vref.devices.push(devname.into())
+    /// Refuse to convert protected paths, mount points and unknown filesystems
+    async fn ensure_convertible(
+        &self,
+        server: &zbus::ObjectServer,
+        path: &Path,
+    ) -> fdo::Result<PathBuf> {
+        let path = canonicalize(path)?;
+        if DaemonConfig::current().is_protected_path(&path) {
+            return Err(fdo::Error::AccessDenied(format!(
+                "'{}' is a protected path",
+                path.display()
+            )));
+        }
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            if !fs.contains_path(&path) {
+                continue;
+            }
+            let is_mount_point = fs
+                .mount_points_by_subvol_id
+                .values()
+                .flatten()
+                .any(|mnt| mnt.as_path() == path);
+            if is_mount_point {
+                return Err(fdo::Error::AccessDenied(format!(
+                    "'{}' is a mount point",
+                    path.display()
+                )));
             }
+            return Ok(path);
         }
 
-        let f = fs::File::open("/proc/self/mounts")?;
-        let entries = MntEntries::new(io::BufReader::new(f));
-        for entry in entries.flatten() {
-            if entry.fs_type != "btrfs" {
+        Err(fdo::Error::InvalidArgs(format!(
+            "'{}' is not on a known Btrfs filesystem",
+            path.display()
+        )))
+    }
+
+    /// Refuse to adopt anything but snapshots whose source is gone.
+    ///
+    /// Returns the canonical path of the snapshot.
+    async fn ensure_orphaned(
+        &self,
+        server: &zbus::ObjectServer,
+        path: &Path,
+    ) -> fdo::Result<PathBuf> {
+        let path = &canonicalize(path)?;
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            if !fs.contains_path(path) {
                 continue;
             }
-            let mnt_path = entry.target.context("failed to read target")?;
-            let devname = evaluate_spec(&entry.spec, Some(&mut cache))?;
-            if let Some(uuid) = uuid_by_devname.get(&devname) {
-                let subvol_id = Self::subvol_id_from_mnt_options(&entry.options)
-                    .ok_or(io::Error::from(io::ErrorKind::InvalidData))?;
-                // unwrap: if uuid in uuid_by_devname, then it must be in ret
-                ret.get_mut(uuid)
-                    .unwrap()
-                    .mount_points_by_subvol_id
-                    .entry(subvol_id)
-                    .or_default()
-                    .push(mnt_path.into());
+            if let Some((subvol, _)) = fs.subvolume_at(path).to_fdo()? {
+                if subvol.is_orphaned() {
+                    return Ok(path.clone());
+                }
+                return Err(fdo::Error::InvalidArgs(format!(
+                    "'{}' is not an orphaned snapshot",
+                    path.display()
+                )));
             }
         }
 
-        for fs in ret.values_mut() {
-            fs.devices.sort_unstable();
+        Err(fdo::Error::InvalidArgs(format!(
+            "'{}' is not a subvolume on a known Btrfs filesystem",
+            path.display()
+        )))
+    }
+
+    async fn remove_subvolumes_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        paths: Vec<ZPathBuf>,
+        force: bool,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        if force {
+            self.polkit.validate(header, FORCE_ACTION_ID).await?;
         }
 
-        Ok(ret)
+        let mut checked = Vec::with_capacity(paths.len());
+        for p in paths {
+            if p.as_path().is_relative() {
+                return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+            }
+            if force {
+                checked.push(p.into());
+            } else {
+                checked.push(self.ensure_unprotected(server, p.as_path()).await?);
+            }
+        }
+
+        // nested snapshots go away together with the snapshot containing them
+        let mut paths: Vec<PathBuf> = checked;
+        paths.sort_unstable();
+        let mut deleted: Vec<PathBuf> = Vec::new();
+        for p in paths {
+            if deleted.iter().any(|d| p.starts_with(d)) {
+                continue;
+            }
+            delete_subvolume(self.backend.as_ref(), &p)
+                .context("Failed to delete subvolume")
+                .to_fdo()?;
+            deleted.push(p);
+        }
+
+        Ok(())
     }
 
-    async fn refresh_impl(&mut self, server: &zbus::ObjectServer) -> anyhow::Result<()> {
-        let next_filesystems = Self::probe_btrfs_devices()?;
+    async fn move_subvolume_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        src_path: ZPathBuf,
+        dst_path: ZPathBuf,
+        force: bool,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        if force {
+            self.polkit.validate(header, FORCE_ACTION_ID).await?;
+        }
+        if src_path.as_path().is_relative() || dst_path.as_path().is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+        let src_path = if force {
+            src_path.into()
+        } else {
+            self.ensure_unprotected(server, src_path.as_path()).await?
+        };
 
-        let mut to_remove = Vec::new();
-        for (uuid, path) in &self.filesystems {
-            if !next_filesystems.contains_key(uuid) {
-                to_remove.push((*uuid, path.clone()));
+        if dst_path.as_path().exists() {
+            // best efforts
+            return Err(fdo::Error::InvalidArgs("Target already exists".to_owned()));
+        }
+
+        self.backend
+            .rename_subvolume(&src_path, dst_path.as_path())
+            .context("Failed to move subvolume")
+            .to_fdo()?;
+
+        Ok(())
+    }
+
+    async fn restore_snapshots_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        paths: Vec<ZPathBuf>,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        self.polkit.validate(header, FORCE_ACTION_ID).await?;
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        // check every snapshot before touching anything
+        let mut plan = Vec::with_capacity(paths.len());
+        for p in paths {
+            if p.as_path().is_relative() {
+                return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
             }
+            let snapshot_path = &self.ensure_unprotected(server, p.as_path()).await?;
+            let metadata = SnapshotMetadata::read(self.backend.as_ref(), snapshot_path)
+                .ok_or_else(|| {
+                    fdo::Error::InvalidArgs(format!(
+                        "'{}' was not created by butter",
+                        snapshot_path.display()
+                    ))
+                })?;
+            let source_path = self
+                .movable_path(server, snapshot_path, &metadata.created_from)
+                .await?;
+            let mut backup_name = source_path.file_name().unwrap_or_default().to_owned();
+            backup_name.push(format!("{}{}", RESTORE_BACKUP_INFIX, now));
+            let backup_path = source_path.with_file_name(backup_name);
+            plan.push((snapshot_path.clone(), source_path, backup_path));
         }
-        let remove_futs = to_remove
-            .iter()
-            .map(|(_, path)| server.remove::<Filesystem, _>(path));
-        futures::future::join_all(remove_futs).await;
 
-        for (uuid, _) in to_remove {
-            self.filesystems.remove(&uuid);
+        // sources moved aside and where to
+        let mut moved = Vec::with_capacity(plan.len());
+        let mut res = Ok(());
+        for (snapshot_path, source_path, backup_path) in plan {
+            res = self
+                .backend
+                .rename_subvolume(&source_path, &backup_path)
+                .context("Failed to move subvolume aside")
+                .and_then(|_| {
+                    moved.push((source_path.clone(), backup_path));
+                    create_snapshot(
+                        self.backend.as_ref(),
+                        &snapshot_path,
+                        &source_path,
+                        false,
+                        true,
+                        None,
+                    )
+                    .context("Failed to restore snapshot")
+                });
+            if res.is_err() {
+                break;
+            }
+        }
+        if let Err(err) = res {
+            self.roll_back_restore(&moved);
+            return Err(err).to_fdo();
         }
 
-        for (uuid, fs) in next_filesystems {
-            if let Some(path) = self.filesystems.get(&uuid) {
-                fs.update(server, path).await?;
-            } else {
-                let path = OwnedObjectPath::try_from(format!("{}/{}", Self::PATH, uuid.simple()))?;
-                fs.create(server, &path).await?;
-                self.filesystems.insert(uuid, path);
+        Ok(moved
+            .into_iter()
+            .map(|(_, backup_path)| backup_path.into())
+            .collect())
+    }
+
+    /// Put the subvolumes moved aside by a failed restore back in place,
+    /// last first, deleting whatever was restored there
+    fn roll_back_restore(&self, moved: &[(PathBuf, PathBuf)]) {
+        for (source_path, backup_path) in moved.iter().rev() {
+            if self.backend.is_subvolume(source_path).unwrap_or(false) {
+                if let Err(err) = delete_subvolume(self.backend.as_ref(), source_path) {
+                    warn!(
+                        "failed to delete partially restored '{}': {}",
+                        source_path.display(),
+                        err
+                    );
+                    continue;
+                }
+            }
+            if let Err(err) = self.backend.rename_subvolume(backup_path, source_path) {
+                warn!(
+                    "failed to move '{}' back to '{}': {}",
+                    backup_path.display(),
+                    source_path.display(),
+                    err
+                );
+            }
+        }
+    }
+
+    async fn adopt_snapshots_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        paths: Vec<ZPathBuf>,
+        src_path: ZPathBuf,
+        target_dir: ZPathBuf,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let target_dir = target_dir.as_path();
+        if src_path.as_path().is_relative() || target_dir.is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+        let created_from = self
+            .backend
+            .subvolume_path(src_path.as_path())
+            .context("Failed to find source subvolume")
+            .to_fdo()?;
+
+        // check every snapshot and destination before touching anything
+        let mut plan: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(paths.len());
+        for p in paths {
+            if p.as_path().is_relative() {
+                return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+            }
+            let path = self.ensure_orphaned(server, p.as_path()).await?;
+            SnapshotMetadata::check_writable(self.backend.as_ref(), &path).to_fdo()?;
+            let dst_path = match path.file_name() {
+                Some(name) if path.parent() != Some(target_dir) => target_dir.join(name),
+                _ => path.clone(),
+            };
+            if dst_path != path && dst_path.exists() {
+                return Err(fdo::Error::InvalidArgs(format!(
+                    "'{}' already exists",
+                    dst_path.display()
+                )));
+            }
+            if plan.iter().any(|(_, dst)| *dst == dst_path) {
+                return Err(fdo::Error::InvalidArgs(format!(
+                    "More than one snapshot would be moved to '{}'",
+                    dst_path.display()
+                )));
+            }
+            plan.push((path, dst_path));
+        }
+
+        let mut ret = Vec::with_capacity(plan.len());
+        for (path, dst_path) in plan {
+            if dst_path != path {
+                std::fs::create_dir_all(target_dir).to_fdo()?;
+                self.backend
+                    .rename_subvolume(&path, &dst_path)
+                    .context("Failed to move snapshot")
+                    .to_fdo()?;
             }
+
+            let info = self.backend.subvolume_info(&dst_path).to_fdo()?;
+            let old = SnapshotMetadata::read_with_uuid(self.backend.as_ref(), &dst_path, info.uuid);
+            let metadata = SnapshotMetadata {
+                created_from: created_from.clone(),
+                uuid: info.uuid,
+                group: old.as_ref().and_then(|m| m.group.clone()),
+                imported: old.and_then(|m| m.imported),
+            };
+            metadata
+                .write(self.backend.as_ref(), &dst_path)
+                .context("Failed to write snapshot metadata")
+                .to_fdo()?;
+            ret.push(dst_path.into());
+        }
+
+        Ok(ret)
+    }
+
+    async fn import_snapshots_impl(
+        &self,
+        header: &Header<'_>,
+        tool: String,
+        dir: ZPathBuf,
+        rule_name: String,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let tool: ImportTool = tool
+            .parse()
+            .map_err(|err: anyhow::Error| fdo::Error::InvalidArgs(err.to_string()))?;
+        if dir.as_path().is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+        let rule_cfg = if rule_name.is_empty() {
+            None
+        } else {
+            Some(RuleConfig::read(&rule_name).to_fdo()?)
+        };
+
+        let paths = import_snapshots(
+            self.backend.as_ref(),
+            tool,
+            dir.as_path(),
+            rule_cfg.as_ref(),
+        )
+        .context("Failed to import snapshots")
+        .to_fdo()?;
+        Ok(paths.into_iter().map(Into::into).collect())
+    }
+
+    async fn migrate_metadata_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        remove_old: bool,
+    ) -> fdo::Result<u32> {
+        self.polkit.validate(header, ACTION_ID).await?;
+
+        let mut ret = 0;
+        for fs_path in self.filesystems.values() {
+            let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+            let fs = iface_ref.get().await;
+            let Ok(mnt_path) = fs.mnt_path() else {
+                continue;
+            };
+            ret += migrate_metadata(self.backend.as_ref(), mnt_path, remove_old)
+                .with_context(|| format!("Failed to migrate '{}'", mnt_path.display()))
+                .to_fdo()?;
+        }
+        Ok(ret)
+    }
+
+    async fn set_subvolume_properties_impl(
+        &self,
+        header: &Header<'_>,
+        path: ZPathBuf,
+        properties: SubvolumeProperties,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let path = path.as_path();
+        if path.is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+        if !self.backend.is_subvolume(path).to_fdo()? {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "'{}' is not a subvolume",
+                path.display()
+            )));
+        }
+        properties.apply(self.backend.as_ref(), path).to_fdo()
+    }
+
+    async fn set_filesystem_label_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        uuid: ZUuid,
+        label: String,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        if label.len() > BTRFS_LABEL_MAX {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "Label must be at most {} bytes",
+                BTRFS_LABEL_MAX
+            )));
+        }
+        let fs_path = self
+            .filesystems
+            .get(uuid.as_uuid())
+            .ok_or_else(|| fdo::Error::InvalidArgs("Unknown filesystem".to_owned()))?;
+        let iface_ref = server.interface::<_, Filesystem>(fs_path).await?;
+        let mut fs = iface_ref.get_mut().await;
+        self.backend
+            .set_label(fs.mnt_path().to_fdo()?, &label)
+            .context("Failed to set label")
+            .to_fdo()?;
+        fs.label = label;
+        fs.label_changed(iface_ref.signal_context()).await?;
+        Ok(())
+    }
+
+    async fn create_snapshot_impl(
+        &self,
+        header: &Header<'_>,
+        src_path: ZPathBuf,
+        dst_path: ZPathBuf,
+        readonly: bool,
+        recursive: bool,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        if src_path.as_path().is_relative() || dst_path.as_path().is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
         }
 
+        create_snapshot(
+            self.backend.as_ref(),
+            src_path.as_path(),
+            dst_path.as_path(),
+            readonly,
+            recursive,
+            None,
+        )
+        .context("Failed to create snapshot")
+        .to_fdo()?;
+
         Ok(())
     }
+
+    async fn create_subvolume_impl(
+        &self,
+        header: &Header<'_>,
+        path: ZPathBuf,
+        options: SubvolumeCreateOptions,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let path = path.as_path();
+        if path.is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+
+        create_subvolume(self.backend.as_ref(), path, &options)
+            .context("Failed to create subvolume")
+            .to_fdo()
+    }
+
+    async fn convert_to_subvolume_impl(
+        &self,
+        header: &Header<'_>,
+        server: &zbus::ObjectServer,
+        path: ZPathBuf,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let path = path.as_path();
+        if path.is_relative() {
+            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        }
+        let path = &self.ensure_convertible(server, path).await?;
+
+        convert_to_subvolume(self.backend.as_ref(), path)
+            .with_context(|| format!("Failed to convert '{}'", path.display()))
+            .to_fdo()
+    }
+}
+
+/// Resolve `..` components and symlinks in `path`, which must exist
+fn canonicalize(path: &Path) -> fdo::Result<PathBuf> {
+    path.canonicalize().map_err(|err| {
+        fdo::Error::InvalidArgs(format!("Failed to resolve '{}': {}", path.display(), err))
+    })
 }
 
 #[interface(
@@ -141,66 +726,293 @@ impl Storage {
         self.refresh_impl(server).await.to_fdo()
     }
 
+    /// Delete subvolumes at `paths`.
+    ///
+    /// Primary subvolumes and paths unknown to butterd are refused unless
+    /// `force` is set, which requires a separate authorization.
     pub async fn remove_subvolumes(
         &self,
         #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
         paths: Vec<ZPathBuf>,
+        force: bool,
     ) -> fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-
-        for p in paths {
-            if p.as_path().is_relative() {
-                return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
-            }
-            libbtrfsutil::DeleteSubvolumeOptions::new()
-                .recursive(true)
-                .delete(p.as_path())
-                .context("Failed to delete subvolume")
-                .to_fdo()?;
-        }
-
-        Ok(())
+        let mut args: Vec<String> = paths
+            .iter()
+            .map(|p| p.as_path().display().to_string())
+            .collect();
+        args.push(format!("force={}", force));
+
+        let res = self
+            .remove_subvolumes_impl(&header, server, paths, force)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.RemoveSubvolumes", args, &res)
+            .await;
+        res
     }
 
+    /// Move the subvolume at `src_path` to `dst_path`.
+    ///
+    /// See [`Self::remove_subvolumes`] for the meaning of `force`.
     pub async fn move_subvolume(
         &self,
         #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
         src_path: ZPathBuf,
         dst_path: ZPathBuf,
+        force: bool,
     ) -> fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-        if src_path.as_path().is_relative() || dst_path.as_path().is_relative() {
-            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        let args = vec![
+            src_path.as_path().display().to_string(),
+            dst_path.as_path().display().to_string(),
+            format!("force={}", force),
+        ];
+
+        let res = self
+            .move_subvolume_impl(&header, server, src_path, dst_path, force)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.MoveSubvolume", args, &res)
+            .await;
+        res
+    }
+
+    /// Put each snapshot in `paths` back in place of the subvolume it was
+    /// taken from, e.g. all snapshots of a group.
+    ///
+    /// The current subvolume is kept next to it with a `.pre-restore-<time>`
+    /// suffix. Returns the paths of these subvolumes. Subvolumes mounted by
+    /// path pick up the restored copy on the next mount.
+    pub async fn restore_snapshots(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        paths: Vec<ZPathBuf>,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        let args = paths
+            .iter()
+            .map(|p| p.as_path().display().to_string())
+            .collect();
+
+        let res = self.restore_snapshots_impl(&header, server, paths).await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.RestoreSnapshots", args, &res)
+            .await;
+        res
+    }
+
+    /// Make orphaned snapshots at `paths` snapshots of `src_path`, as if
+    /// taken by a rule snapshotting it into `target_dir`.
+    ///
+    /// Snapshots are moved into `target_dir` if needed. Returns their new
+    /// paths.
+    pub async fn adopt_snapshots(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        paths: Vec<ZPathBuf>,
+        src_path: ZPathBuf,
+        target_dir: ZPathBuf,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        let mut args: Vec<String> = paths
+            .iter()
+            .map(|p| p.as_path().display().to_string())
+            .collect();
+        args.push(format!("src={}", src_path.as_path().display()));
+        args.push(format!("target={}", target_dir.as_path().display()));
+
+        let res = self
+            .adopt_snapshots_impl(&header, server, paths, src_path, target_dir)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.AdoptSnapshots", args, &res)
+            .await;
+        res
+    }
+
+    /// Record what `tool`, `snapper` or `timeshift`, knows about its
+    /// snapshots in `dir` as Butter metadata.
+    ///
+    /// Unless `rule_name` is empty, snapshots of subvolumes of that rule are
+    /// moved into its target directories so that it prunes them. Returns the
+    /// paths of the imported snapshots.
+    pub async fn import_snapshots(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        tool: String,
+        dir: ZPathBuf,
+        rule_name: String,
+    ) -> fdo::Result<Vec<ZPathBuf>> {
+        let args = vec![
+            tool.clone(),
+            dir.as_path().display().to_string(),
+            format!("rule={}", rule_name),
+        ];
+
+        let res = self
+            .import_snapshots_impl(&header, tool, dir, rule_name)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.ImportSnapshots", args, &res)
+            .await;
+        res
+    }
+
+    /// Move snapshot metadata kept inside snapshots by older versions to the
+    /// store of each filesystem.
+    ///
+    /// The old copies are deleted if `remove_old`, except in received
+    /// snapshots. Returns how many snapshots were migrated.
+    pub async fn migrate_metadata(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        remove_old: bool,
+    ) -> fdo::Result<u32> {
+        let args = vec![format!("remove_old={}", remove_old)];
+
+        let res = self
+            .migrate_metadata_impl(&header, server, remove_old)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.MigrateMetadata", args, &res)
+            .await;
+        res
+    }
+
+    /// Properties of the subvolume at `path`, with every field set
+    pub async fn subvolume_properties(&self, path: ZPathBuf) -> fdo::Result<SubvolumeProperties> {
+        let path = path.as_path();
+        if !self.backend.is_subvolume(path).to_fdo()? {
+            return Err(fdo::Error::InvalidArgs(format!(
+                "'{}' is not a subvolume",
+                path.display()
+            )));
         }
+        SubvolumeProperties::read(self.backend.as_ref(), path).to_fdo()
+    }
 
-        if dst_path.as_path().exists() {
-            // best efforts
-            return Err(fdo::Error::InvalidArgs("Target already exists".to_owned()));
+    /// Set the fields of `properties` that are set on the subvolume at `path`
+    pub async fn set_subvolume_properties(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        path: ZPathBuf,
+        properties: SubvolumeProperties,
+    ) -> fdo::Result<()> {
+        let mut args = vec![path.as_path().display().to_string()];
+        if let Some(compression) = &properties.compression {
+            args.push(format!("compression={}", compression));
+        }
+        if let Some(nodatacow) = properties.nodatacow {
+            args.push(format!("nodatacow={}", nodatacow));
         }
 
-        fs::rename(src_path.as_path(), dst_path.as_path())
-            .context("Failed to move subvolume")
-            .to_fdo()?;
+        let res = self
+            .set_subvolume_properties_impl(&header, path, properties)
+            .await;
+        self.audit
+            .record(&header, "Storage.SetSubvolumeProperties", args, &res)
+            .await;
+        res
+    }
 
-        Ok(())
+    /// Change the label of the filesystem with `uuid`
+    pub async fn set_filesystem_label(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        uuid: ZUuid,
+        label: String,
+    ) -> fdo::Result<()> {
+        let args = vec![uuid.as_uuid().to_string(), label.clone()];
+
+        let res = self
+            .set_filesystem_label_impl(&header, server, uuid, label)
+            .await;
+        self.audit
+            .record(&header, "Storage.SetFilesystemLabel", args, &res)
+            .await;
+        res
     }
 
+    /// Snapshot `src_path` at `dst_path`, including nested subvolumes if `recursive`
     pub async fn create_snapshot(
         &self,
         #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
         src_path: ZPathBuf,
         dst_path: ZPathBuf,
         readonly: bool,
+        recursive: bool,
     ) -> fdo::Result<()> {
-        self.polkit.validate(&header, ACTION_ID).await?;
-        if src_path.as_path().is_relative() || dst_path.as_path().is_relative() {
-            return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+        let args = vec![
+            src_path.as_path().display().to_string(),
+            dst_path.as_path().display().to_string(),
+            format!("readonly={}", readonly),
+            format!("recursive={}", recursive),
+        ];
+
+        let res = self
+            .create_snapshot_impl(&header, src_path, dst_path, readonly, recursive)
+            .await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.CreateSnapshot", args, &res)
+            .await;
+        res
+    }
+
+    /// Create an empty subvolume at `path`
+    pub async fn create_subvolume(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        path: ZPathBuf,
+        options: SubvolumeCreateOptions,
+    ) -> fdo::Result<()> {
+        let mut args = vec![path.as_path().display().to_string()];
+        for qgroup in options.inherit_qgroups.iter().flatten() {
+            args.push(format!("inherit-qgroup={}", qgroup));
+        }
+        if let Some(compression) = &options.compression {
+            args.push(format!("compression={}", compression));
+        }
+        if let Some(nodatacow) = options.nodatacow {
+            args.push(format!("nodatacow={}", nodatacow));
         }
 
-        create_snapshot(src_path.as_path(), dst_path.as_path(), readonly)
-            .context("Failed to create snapshot")
-            .to_fdo()?;
+        let res = self.create_subvolume_impl(&header, path, options).await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.CreateSubvolume", args, &res)
+            .await;
+        res
+    }
 
-        Ok(())
+    /// Turn the directory at `path` into a subvolume in place, which leaves
+    /// it out of snapshots of the subvolume containing it unless they are
+    /// recursive
+    pub async fn convert_to_subvolume(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(object_server)] server: &zbus::ObjectServer,
+        path: ZPathBuf,
+    ) -> fdo::Result<()> {
+        let args = vec![path.as_path().display().to_string()];
+
+        let res = self.convert_to_subvolume_impl(&header, server, path).await;
+        self.invalidate_subvolumes(server).await;
+        self.audit
+            .record(&header, "Storage.ConvertToSubvolume", args, &res)
+            .await;
+        res
     }
 }
@@ -1,18 +1,11 @@
-use std::{
-    collections::HashSet,
-    fs::File,
-    io::{self, BufWriter, Write},
-    path::{Path, PathBuf},
-    sync::LazyLock,
-};
+use std::{io, path::Path};
 
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
-use zbus::zvariant::{Optional, Type};
+use zbus::zvariant::{DeserializeDict, Optional, SerializeDict, Type};
 
-use crate::{ZPathBuf, ZUuid};
+use crate::{BtrfsBackend, DaemonConfig, SnapshotGroup, SnapshotMetadata, ZPathBuf, ZUuid};
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize, Type)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize, Type)]
 pub struct Subvolume {
     /// relative path from the root subvol
     pub root_path: ZPathBuf,
@@ -27,7 +20,26 @@ pub struct Subvolume {
     pub uuid: ZUuid,
     pub id: u64,
     pub created_unix_secs: i64,
+    /// last time the subvolume itself, not its content, was changed
+    pub changed_unix_secs: i64,
     pub snapshot_source_uuid: Optional<ZUuid>,
+    /// UUID of the subvolume this was received from by `btrfs receive`
+    pub received_uuid: Optional<ZUuid>,
+    pub is_read_only: bool,
+    /// `BTRFS_ROOT_SUBVOL_*` flags
+    pub flags: u64,
+    /// transaction of the last change to the content
+    pub generation: u64,
+    /// transaction of the last change to the subvolume itself
+    pub ctransid: u64,
+    /// transaction the subvolume was created in
+    pub otransid: u64,
+    /// `ctransid` of the sent subvolume if received, otherwise 0
+    pub stransid: u64,
+    /// transaction the subvolume was received in, otherwise 0
+    pub rtransid: u64,
+    /// ID of the [`SnapshotGroup`] this snapshot was taken in
+    pub group_uuid: Optional<ZUuid>,
 }
 
 impl Subvolume {
@@ -36,82 +48,311 @@ impl Subvolume {
     /// What BTRFS technically considers as snapshot may be mounted as /home
     /// thus "acts" as a main subvolume.
     pub fn is_likely_primary(&self) -> bool {
-        // Some hardcoded paths that are extremly often to be their own subvolume
-        // and important for the system.
-        static USUALS: LazyLock<HashSet<PathBuf>> = LazyLock::new(|| {
-            HashSet::from([
-                PathBuf::from("/"),
-                PathBuf::from("/boot"),
-                PathBuf::from("/home"),
-                PathBuf::from("/mnt"),
-                PathBuf::from("/opt"),
-                PathBuf::from("/root"),
-                PathBuf::from("/srv"),
-                PathBuf::from("/tmp"),
-                PathBuf::from("/usr"),
-                PathBuf::from("/usr/local"),
-                PathBuf::from("/var"),
-                PathBuf::from("/var/lib"),
-                PathBuf::from("/var/lib/machines"),
-                PathBuf::from("/var/log"),
-                PathBuf::from("/var"),
-            ])
-        });
+        let config = DaemonConfig::current();
 
         self.is_mountpoint
             || self.snapshot_source_uuid.is_none()
-            || self.paths.iter().any(|p| USUALS.contains(p.as_path()))
+            || self
+                .paths
+                .iter()
+                .any(|p| config.is_protected_path(p.as_path()))
+    }
+
+    /// `true` if this is a snapshot whose source subvolume no longer exists,
+    /// e.g. because it was deleted or recreated
+    pub fn is_orphaned(&self) -> bool {
+        self.snapshot_source_uuid.is_some()
+            && self.created_from_root_path.is_none()
+            && !self.is_likely_primary()
+    }
+
+    /// `true` if the content of this subvolume changed after `snapshot` was
+    /// taken, e.g. of it
+    pub fn is_changed_since(&self, snapshot: &Subvolume) -> bool {
+        self.generation > snapshot.otransid
+    }
+}
+
+/// Criteria of `ListSubvolumesFiltered`, unset fields match everything
+#[derive(Clone, Debug, Default, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "a{sv}", rename_all = "kebab-case")]
+pub struct SubvolumeFilter {
+    /// only snapshots of the subvolume with this UUID
+    pub source_uuid: Option<ZUuid>,
+    /// only subvolumes under this path, absolute paths are matched against
+    /// [`Subvolume::paths`] and relative ones against [`Subvolume::root_path`]
+    pub path_prefix: Option<ZPathBuf>,
+    /// only subvolumes created at or after this unix time
+    pub created_after: Option<i64>,
+    /// only subvolumes created before this unix time
+    pub created_before: Option<i64>,
+    pub is_snapshot: Option<bool>,
+    /// see [`Subvolume::is_likely_primary`]
+    pub is_primary: Option<bool>,
+    pub read_only: Option<bool>,
+    /// only snapshots of the [`SnapshotGroup`] with this ID
+    pub group_uuid: Option<ZUuid>,
+    /// see [`Subvolume::is_orphaned`]
+    pub is_orphaned: Option<bool>,
+}
+
+impl SubvolumeFilter {
+    pub fn matches(&self, subvol: &Subvolume) -> bool {
+        if let Some(source_uuid) = self.source_uuid {
+            if *subvol.snapshot_source_uuid != Some(source_uuid) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.path_prefix {
+            let prefix = prefix.as_path();
+            let is_under = if prefix.is_absolute() {
+                subvol.paths.iter().any(|p| p.as_path().starts_with(prefix))
+            } else {
+                subvol.root_path.as_path().starts_with(prefix)
+            };
+            if !is_under {
+                return false;
+            }
+        }
+        if self
+            .created_after
+            .is_some_and(|after| subvol.created_unix_secs < after)
+            || self
+                .created_before
+                .is_some_and(|before| subvol.created_unix_secs >= before)
+        {
+            return false;
+        }
+        if self
+            .is_snapshot
+            .is_some_and(|v| v != subvol.snapshot_source_uuid.is_some())
+            || self
+                .is_primary
+                .is_some_and(|v| v != subvol.is_likely_primary())
+            || self.read_only.is_some_and(|v| v != subvol.is_read_only)
+            || self
+                .group_uuid
+                .is_some_and(|v| *subvol.group_uuid != Some(v))
+            || self.is_orphaned.is_some_and(|v| v != subvol.is_orphaned())
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// Changes to the subvolumes of a filesystem since a generation
+#[derive(Clone, Debug, Default, Deserialize, Serialize, Type)]
+pub struct SubvolumeDelta {
+    /// identifies the listing `generation` belongs to, which is new whenever
+    /// the daemon restarts. Pass both to the next `ListSubvolumesSince` call.
+    pub epoch: u64,
+    pub generation: u64,
+    /// `true` if the requested epoch or generation is unknown and `changed`
+    /// holds every subvolume instead
+    pub is_full: bool,
+    /// subvolumes created or changed since the requested generation
+    pub changed: Vec<Subvolume>,
+    /// UUIDs of subvolumes removed since the requested generation
+    pub removed: Vec<ZUuid>,
+}
+
+/// Properties of a subvolume applying to files created in it afterwards,
+/// unset fields are left alone when applied
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "a{sv}", rename_all = "kebab-case")]
+pub struct SubvolumeProperties {
+    /// `zlib`, `lzo` or `zstd`, optionally followed by `:<level>`, empty to
+    /// follow the mount options
+    pub compression: Option<String>,
+    /// neither copy-on-write, checksum nor compress new files
+    pub nodatacow: Option<bool>,
+}
+
+impl SubvolumeProperties {
+    /// Current properties of the subvolume at `path`
+    pub fn read(backend: &dyn BtrfsBackend, path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            compression: Some(backend.compression(path)?),
+            nodatacow: Some(backend.is_nodatacow(path)?),
+        })
+    }
+
+    /// Set the fields that are set on the subvolume at `path`
+    pub fn apply(&self, backend: &dyn BtrfsBackend, path: &Path) -> io::Result<()> {
+        if let Some(compression) = &self.compression {
+            validate_compression(compression)?;
+        }
+        // compression is refused on nodatacow inodes
+        if self.nodatacow == Some(false) {
+            backend.set_nodatacow(path, false)?;
+        }
+        if let Some(compression) = &self.compression {
+            backend.set_compression(path, compression)?;
+        }
+        if self.nodatacow == Some(true) {
+            backend.set_nodatacow(path, true)?;
+        }
+        Ok(())
     }
 }
 
-/// Butter specific metadata for snapshot
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct SnapshotMetadata {
-    /// the path relative to the filesystem root of the subvolume this subvolume is a snapshot of
-    pub created_from: PathBuf,
-    /// subvolume's UUID
-    pub uuid: Uuid,
+/// How `CreateSubvolume` sets up a new subvolume, unset fields are left at
+/// their defaults
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeserializeDict, SerializeDict, Type)]
+#[zvariant(signature = "a{sv}", rename_all = "kebab-case")]
+pub struct SubvolumeCreateOptions {
+    /// qgroups such as `1/100` to add the subvolume to, usually to share
+    /// a limit with other subvolumes
+    pub inherit_qgroups: Option<Vec<String>>,
+    /// see [`SubvolumeProperties::compression`]
+    pub compression: Option<String>,
+    /// see [`SubvolumeProperties::nodatacow`]
+    pub nodatacow: Option<bool>,
 }
 
-impl SnapshotMetadata {
-    pub fn read(subvol_path: &Path) -> Option<SnapshotMetadata> {
-        let raw = libbtrfsutil::subvolume_info(subvol_path).ok()?;
-        let metadata_path = subvol_path.join(".butter/info.json");
-        let metadata_bytes = std::fs::read(&metadata_path).ok()?;
-        let ret: SnapshotMetadata = serde_json::from_slice(&metadata_bytes).ok()?;
-        if ret.uuid == raw.uuid() {
-            Some(ret)
-        } else {
-            None
+impl SubvolumeCreateOptions {
+    pub fn properties(&self) -> SubvolumeProperties {
+        SubvolumeProperties {
+            compression: self.compression.clone(),
+            nodatacow: self.nodatacow,
         }
     }
 }
 
-/// Create a regular snapshot, save butter specific metadata, conditionally make it read-only
-pub fn create_snapshot(src_path: &Path, dst_path: &Path, readonly: bool) -> io::Result<()> {
-    let src_subvol_path = libbtrfsutil::subvolume_path(src_path).map_err(|e| e.os_error())?;
+/// Parse a qgroup ID written as `<level>/<id>`, or `<id>` for level 0
+pub fn parse_qgroup_id(value: &str) -> io::Result<u64> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid qgroup '{}'", value),
+        )
+    };
+    let (level, id) = value.trim().split_once('/').unwrap_or(("0", value.trim()));
+    let level: u16 = level.parse().map_err(|_| invalid())?;
+    let id: u64 = id.parse().map_err(|_| invalid())?;
+    if id >= 1 << 48 {
+        return Err(invalid());
+    }
+    Ok((u64::from(level) << 48) | id)
+}
+
+/// Create an empty subvolume at `path` set up as `options` asks.
+///
+/// The subvolume is deleted again if its properties cannot be set.
+pub fn create_subvolume(
+    backend: &dyn BtrfsBackend,
+    path: &Path,
+    options: &SubvolumeCreateOptions,
+) -> io::Result<()> {
+    let qgroups = options
+        .inherit_qgroups
+        .iter()
+        .flatten()
+        .map(|s| parse_qgroup_id(s))
+        .collect::<io::Result<Vec<_>>>()?;
+    if let Some(compression) = &options.compression {
+        validate_compression(compression)?;
+    }
+
+    backend.create_subvolume(path, &qgroups)?;
+    if let Err(err) = options.properties().apply(backend, path) {
+        let _ = backend.delete_subvolume(path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn validate_compression(value: &str) -> io::Result<()> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid compression '{}'", value),
+        )
+    };
+    if value.is_empty() {
+        return Ok(());
+    }
+    let (algo, level) = match value.split_once(':') {
+        Some((algo, level)) => (algo, Some(level.parse::<u32>().map_err(|_| invalid())?)),
+        None => (value, None),
+    };
+    let max_level = match algo {
+        "zlib" => 9,
+        "zstd" => 15,
+        "lzo" => 0,
+        _ => return Err(invalid()),
+    };
+    match level {
+        Some(level) if !(1..=max_level).contains(&level) => Err(invalid()),
+        _ => Ok(()),
+    }
+}
+
+/// Create a regular snapshot, save butter specific metadata, conditionally make it read-only.
+///
+/// Nested subvolumes are included and made read-only as well if `recursive`.
+pub fn create_snapshot(
+    backend: &dyn BtrfsBackend,
+    src_path: &Path,
+    dst_path: &Path,
+    readonly: bool,
+    recursive: bool,
+    group: Option<&SnapshotGroup>,
+) -> io::Result<()> {
+    let src_subvol_path = backend.subvolume_path(src_path)?;
     if let Some(dst_parent) = dst_path.parent() {
         std::fs::create_dir_all(dst_parent)?;
     }
-    libbtrfsutil::CreateSnapshotOptions::new()
-        .create(src_path, dst_path)
-        .map_err(|e| e.os_error())?;
-
-    let metadata_dir = dst_path.join(".butter");
-    std::fs::create_dir_all(&metadata_dir)?;
+    backend.create_snapshot(src_path, dst_path, recursive)?;
 
     let metadata = SnapshotMetadata {
         created_from: src_subvol_path,
-        uuid: libbtrfsutil::subvolume_info(dst_path)
-            .map_err(|e| e.os_error())?
-            .uuid(),
+        uuid: backend.subvolume_info(dst_path)?.uuid,
+        group: group.cloned(),
+        imported: None,
     };
+    metadata.write(backend, dst_path)?;
+
+    if readonly && recursive {
+        for nested in backend.nested_subvolumes(dst_path)? {
+            backend.set_read_only(&nested, true)?;
+        }
+    }
+    backend.set_read_only(dst_path, readonly)?;
+    Ok(())
+}
 
-    let mut f = BufWriter::new(File::create(metadata_dir.join("info.json"))?);
-    serde_json::to_writer_pretty(&mut f, &metadata)?;
-    f.write_all(b"\n")?;
-    f.flush()?;
+/// Delete the subvolume at `path` and all subvolumes below it, even if they
+/// are read-only, along with their stored metadata
+pub fn delete_subvolume(backend: &dyn BtrfsBackend, path: &Path) -> io::Result<()> {
+    let mut subvols = vec![(path.to_owned(), backend.subvolume_info(path)?)];
+    for p in backend.nested_subvolumes(path)? {
+        let info = backend.subvolume_info(&p)?;
+        subvols.push((p, info));
+    }
+    let res = (|| -> io::Result<()> {
+        if subvols.len() > 1 {
+            // nested subvolumes cannot be unlinked from a read-only parent
+            for (p, _) in &subvols {
+                backend.set_read_only(p, false)?;
+            }
+        }
+        backend.delete_subvolume(path)
+    })();
+    if let Err(err) = res {
+        // best efforts, so that whatever is left stays protected
+        for (p, info) in &subvols {
+            if info.is_read_only() {
+                let _ = backend.set_read_only(p, true);
+            }
+        }
+        return Err(err);
+    }
 
-    libbtrfsutil::set_subvolume_read_only(dst_path, readonly).map_err(|e| e.os_error())?;
+    let parent = path.parent().unwrap_or(path);
+    for (_, info) in subvols {
+        SnapshotMetadata::remove(backend, parent, info.uuid)?;
+    }
     Ok(())
 }
@@ -0,0 +1,463 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs, io,
+    os::{
+        fd::AsRawFd,
+        unix::fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use zbus::{fdo, interface, message::Header, names::BusName, zvariant::ObjectPath};
+
+use crate::{
+    config, create_snapshot, delete_subvolume, AuditLog, BtrfsBackend, Polkit, SnapshotMetadata,
+    ToFdo, ZPathBuf,
+};
+
+/// Lets unprivileged users snapshot subvolumes they own.
+///
+/// Snapshots are kept in [`UserSnapshots::DIR_NAME`] right under the
+/// subvolume. The directory belongs to root so that users cannot write into
+/// it, and it is only ever reached through open directories, as users can
+/// replace it with a symlink at any time.
+pub struct UserSnapshots {
+    dbus: fdo::DBusProxy<'static>,
+    backend: Arc<dyn BtrfsBackend>,
+    polkit: Polkit,
+    audit: AuditLog,
+}
+
+static ACTION_ID: &str = "org.zhangyuannie.butter.manage-own-snapshot";
+
+/// A directory held open, so that it stays the same directory even if the
+/// path it was opened through is changed to point elsewhere
+struct OpenDir(fs::File);
+
+impl OpenDir {
+    /// Open the directory at `path`, refusing a symlink as last component
+    fn open(path: &Path) -> io::Result<Self> {
+        fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_DIRECTORY | libc::O_NOFOLLOW)
+            .open(path)
+            .map(Self)
+    }
+
+    /// Path leading to this very directory for as long as it is open
+    fn path(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/self/fd/{}", self.0.as_raw_fd()))
+    }
+
+    fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.0.metadata()
+    }
+}
+
+/// A snapshot made through [`UserSnapshots`], with its directory held open
+struct UserSnapshot {
+    /// canonical path of the source subvolume
+    subvol_path: PathBuf,
+    subvol: OpenDir,
+    dir: OpenDir,
+    name: OsString,
+}
+
+impl UserSnapshot {
+    fn path(&self) -> PathBuf {
+        self.dir.path().join(&self.name)
+    }
+}
+
+/// `subvol_path => [uid]` read from [`config::USER_SNAPSHOT_ALLOWLIST`]
+fn read_allowlist() -> io::Result<HashMap<PathBuf, Vec<u32>>> {
+    match fs::read(config::USER_SNAPSHOT_ALLOWLIST) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Canonicalize `subvol_path`, open it and check that `uid` may manage its
+/// snapshots
+fn open_subvolume(
+    backend: &dyn BtrfsBackend,
+    uid: u32,
+    subvol_path: &Path,
+) -> fdo::Result<(PathBuf, OpenDir)> {
+    if subvol_path.is_relative() {
+        return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+    }
+    let path = subvol_path.canonicalize().to_fdo()?;
+    let subvol = OpenDir::open(&path).to_fdo()?;
+    if !backend.is_subvolume(&subvol.path()).unwrap_or(false) {
+        return Err(fdo::Error::InvalidArgs(format!(
+            "'{}' is not a subvolume",
+            path.display()
+        )));
+    }
+
+    let is_owner = subvol.metadata().to_fdo()?.uid() == uid;
+    let is_allowed = || {
+        read_allowlist()
+            .map(|list| list.get(&path).is_some_and(|uids| uids.contains(&uid)))
+            .unwrap_or(false)
+    };
+    if uid != 0 && !is_owner && !is_allowed() {
+        return Err(fdo::Error::AccessDenied(format!(
+            "Not allowed to manage snapshots of '{}'",
+            path.display()
+        )));
+    }
+    Ok((path, subvol))
+}
+
+/// Open the snapshot directory of the subvolume `subvol` at `subvol_path`,
+/// creating it if `create`
+fn open_snapshot_dir(subvol_path: &Path, subvol: &OpenDir, create: bool) -> io::Result<OpenDir> {
+    let path = subvol.path().join(UserSnapshots::DIR_NAME);
+    if create {
+        match fs::create_dir(&path) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {}
+        }
+    }
+    let not_owned = || {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "'{}' is not a directory owned by root",
+                subvol_path.join(UserSnapshots::DIR_NAME).display()
+            ),
+        )
+    };
+    let dir = match OpenDir::open(&path) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+        // a symlink or not a directory
+        Err(_) => return Err(not_owned()),
+    };
+    // the daemon runs as root
+    if dir.metadata()?.uid() != unsafe { libc::geteuid() } {
+        return Err(not_owned());
+    }
+    Ok(dir)
+}
+
+/// Check `snapshot_path` is a snapshot made through [`UserSnapshots`] of a
+/// subvolume `uid` may manage and open its directory
+fn open_user_snapshot(
+    backend: &dyn BtrfsBackend,
+    uid: u32,
+    snapshot_path: &Path,
+) -> fdo::Result<UserSnapshot> {
+    let invalid = || {
+        fdo::Error::InvalidArgs(format!(
+            "'{}' is not a user snapshot",
+            snapshot_path.display()
+        ))
+    };
+    if snapshot_path.is_relative() {
+        return Err(fdo::Error::InvalidArgs("Path must be absolute".to_owned()));
+    }
+    let canonical = snapshot_path.canonicalize().to_fdo()?;
+    let name = canonical.file_name().ok_or_else(invalid)?.to_owned();
+    let dir_path = canonical.parent().ok_or_else(invalid)?;
+    if dir_path.file_name() != Some(OsStr::new(UserSnapshots::DIR_NAME)) {
+        return Err(invalid());
+    }
+    let (subvol_path, subvol) =
+        open_subvolume(backend, uid, dir_path.parent().ok_or_else(invalid)?)?;
+    let dir = open_snapshot_dir(&subvol_path, &subvol, false).to_fdo()?;
+    let ret = UserSnapshot {
+        subvol_path,
+        subvol,
+        dir,
+        name,
+    };
+
+    let metadata = SnapshotMetadata::read(backend, &ret.path()).ok_or_else(invalid)?;
+    let source = backend
+        .subvolume_path(&ret.subvol.path())
+        .context("Failed to get subvolume path")
+        .to_fdo()?;
+    if metadata.created_from != source {
+        return Err(invalid());
+    }
+    Ok(ret)
+}
+
+/// Create a read-only snapshot named `name` of the subvolume at `subvol_path`
+/// on behalf of `uid`, returning its path
+fn create_user_snapshot(
+    backend: &dyn BtrfsBackend,
+    uid: u32,
+    subvol_path: &Path,
+    name: &str,
+) -> fdo::Result<PathBuf> {
+    let (subvol_path, subvol) = open_subvolume(backend, uid, subvol_path)?;
+    if name.is_empty() || name.starts_with('.') || name.contains('/') {
+        return Err(fdo::Error::InvalidArgs("Invalid snapshot name".to_owned()));
+    }
+
+    let dir = open_snapshot_dir(&subvol_path, &subvol, true).to_fdo()?;
+    create_snapshot(
+        backend,
+        &subvol.path(),
+        &dir.path().join(name),
+        true,
+        false,
+        None,
+    )
+    .context("Failed to create snapshot")
+    .to_fdo()?;
+    Ok(subvol_path.join(UserSnapshots::DIR_NAME).join(name))
+}
+
+/// Delete the snapshot at `snapshot_path` on behalf of `uid`
+fn remove_user_snapshot(
+    backend: &dyn BtrfsBackend,
+    uid: u32,
+    snapshot_path: &Path,
+) -> fdo::Result<()> {
+    let snapshot = open_user_snapshot(backend, uid, snapshot_path)?;
+    delete_subvolume(backend, &snapshot.path())
+        .context("Failed to delete subvolume")
+        .to_fdo()
+}
+
+/// Create a writable copy of the snapshot at `snapshot_path` at `dst_path`
+/// on behalf of `uid`
+fn restore_user_snapshot(
+    backend: &dyn BtrfsBackend,
+    uid: u32,
+    snapshot_path: &Path,
+    dst_path: &Path,
+) -> fdo::Result<()> {
+    let snapshot = open_user_snapshot(backend, uid, snapshot_path)?;
+
+    let invalid_target = || fdo::Error::InvalidArgs("Invalid target".to_owned());
+    let dst_name = dst_path.file_name().ok_or_else(invalid_target)?;
+    let dst_parent = dst_path
+        .parent()
+        .and_then(|p| p.canonicalize().ok())
+        .ok_or_else(invalid_target)?;
+    let outside =
+        || fdo::Error::InvalidArgs("Target must be inside the source subvolume".to_owned());
+    let rel = dst_parent
+        .strip_prefix(&snapshot.subvol_path)
+        .map_err(|_| outside())?;
+    if rel.starts_with(UserSnapshots::DIR_NAME) {
+        return Err(outside());
+    }
+
+    // walk down from the subvolume so that no symlink leads out of it
+    let mut parent: Option<OpenDir> = None;
+    for component in rel.components() {
+        let Component::Normal(name) = component else {
+            return Err(outside());
+        };
+        let base = parent.as_ref().unwrap_or(&snapshot.subvol);
+        parent = Some(OpenDir::open(&base.path().join(name)).map_err(|_| outside())?);
+    }
+    let dst = parent
+        .as_ref()
+        .unwrap_or(&snapshot.subvol)
+        .path()
+        .join(dst_name);
+    if fs::symlink_metadata(&dst).is_ok() {
+        // best efforts
+        return Err(fdo::Error::InvalidArgs("Target already exists".to_owned()));
+    }
+
+    create_snapshot(backend, &snapshot.path(), &dst, false, true, None)
+        .context("Failed to restore snapshot")
+        .to_fdo()
+}
+
+impl UserSnapshots {
+    pub const PATH: ObjectPath<'static> =
+        ObjectPath::from_static_str_unchecked("/org/zhangyuannie/Butter1/UserSnapshots");
+    pub const DIR_NAME: &'static str = ".butter-snapshots";
+
+    pub async fn new(
+        conn: &zbus::Connection,
+        backend: Arc<dyn BtrfsBackend>,
+        polkit: Polkit,
+        audit: AuditLog,
+    ) -> zbus::Result<Self> {
+        Ok(Self {
+            dbus: fdo::DBusProxy::new(conn).await?,
+            backend,
+            polkit,
+            audit,
+        })
+    }
+
+    async fn caller_uid(&self, header: &Header<'_>) -> fdo::Result<u32> {
+        let sender = header
+            .sender()
+            .ok_or_else(|| fdo::Error::AuthFailed("Unknown sender".to_owned()))?;
+        self.dbus
+            .get_connection_unix_user(BusName::from(sender.to_owned()))
+            .await
+    }
+
+    async fn create_impl(
+        &self,
+        header: &Header<'_>,
+        subvol_path: &Path,
+        name: &str,
+    ) -> fdo::Result<ZPathBuf> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let uid = self.caller_uid(header).await?;
+        create_user_snapshot(self.backend.as_ref(), uid, subvol_path, name).map(Into::into)
+    }
+
+    async fn remove_impl(&self, header: &Header<'_>, snapshot_path: &Path) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let uid = self.caller_uid(header).await?;
+        remove_user_snapshot(self.backend.as_ref(), uid, snapshot_path)
+    }
+
+    async fn restore_impl(
+        &self,
+        header: &Header<'_>,
+        snapshot_path: &Path,
+        dst_path: &Path,
+    ) -> fdo::Result<()> {
+        self.polkit.validate(header, ACTION_ID).await?;
+        let uid = self.caller_uid(header).await?;
+        restore_user_snapshot(self.backend.as_ref(), uid, snapshot_path, dst_path)
+    }
+}
+
+#[interface(
+    name = "org.zhangyuannie.Butter1.UserSnapshots",
+    proxy(
+        gen_blocking = true,
+        default_service = "org.zhangyuannie.Butter1",
+        default_path = "/org/zhangyuannie/Butter1/UserSnapshots",
+    )
+)]
+impl UserSnapshots {
+    /// Create a read-only snapshot named `name` of a subvolume owned by the caller
+    async fn create_snapshot(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        subvol_path: ZPathBuf,
+        name: String,
+    ) -> fdo::Result<ZPathBuf> {
+        let args = vec![subvol_path.as_path().display().to_string(), name.clone()];
+        let res = self
+            .create_impl(&header, subvol_path.as_path(), &name)
+            .await;
+        self.audit
+            .record(&header, "UserSnapshots.CreateSnapshot", args, &res)
+            .await;
+        res
+    }
+
+    async fn remove_snapshot(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        snapshot_path: ZPathBuf,
+    ) -> fdo::Result<()> {
+        let args = vec![snapshot_path.as_path().display().to_string()];
+        let res = self.remove_impl(&header, snapshot_path.as_path()).await;
+        self.audit
+            .record(&header, "UserSnapshots.RemoveSnapshot", args, &res)
+            .await;
+        res
+    }
+
+    /// Create a writable copy of `snapshot_path` at `dst_path` inside the
+    /// source subvolume
+    async fn restore_snapshot(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        snapshot_path: ZPathBuf,
+        dst_path: ZPathBuf,
+    ) -> fdo::Result<()> {
+        let args = vec![
+            snapshot_path.as_path().display().to_string(),
+            dst_path.as_path().display().to_string(),
+        ];
+        let res = self
+            .restore_impl(&header, snapshot_path.as_path(), dst_path.as_path())
+            .await;
+        self.audit
+            .record(&header, "UserSnapshots.RestoreSnapshot", args, &res)
+            .await;
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::FakeBtrfs;
+
+    fn fixture() -> (TempDir, FakeBtrfs, u32) {
+        let dir = tempfile::tempdir().unwrap();
+        let btrfs = FakeBtrfs::new(dir.path());
+        btrfs.create_subvolume(&dir.path().join("@home")).unwrap();
+        fs::write(dir.path().join("@home/file"), "hello").unwrap();
+        fs::create_dir(dir.path().join("elsewhere")).unwrap();
+        let uid = fs::metadata(dir.path()).unwrap().uid();
+        (dir, btrfs, uid)
+    }
+
+    #[test]
+    fn test_create_remove_restore() {
+        let (dir, btrfs, uid) = fixture();
+        let home = dir.path().join("@home");
+
+        let snapshot = create_user_snapshot(&btrfs, uid, &home, "s1").unwrap();
+        assert_eq!(snapshot, home.join(".butter-snapshots/s1"));
+        assert_eq!(fs::read(snapshot.join("file")).unwrap(), b"hello");
+        assert!(btrfs.is_read_only(&snapshot).unwrap());
+        assert!(create_user_snapshot(&btrfs, uid, &home, "../s2").is_err());
+        assert!(create_user_snapshot(&btrfs, uid + 1, &home, "s2").is_err());
+
+        fs::create_dir(home.join("restored")).unwrap();
+        restore_user_snapshot(&btrfs, uid, &snapshot, &home.join("restored/s1")).unwrap();
+        assert_eq!(fs::read(home.join("restored/s1/file")).unwrap(), b"hello");
+        assert!(!btrfs.is_read_only(&home.join("restored/s1")).unwrap());
+
+        remove_user_snapshot(&btrfs, uid, &snapshot).unwrap();
+        assert!(!snapshot.exists());
+    }
+
+    #[test]
+    fn test_symlinked_snapshot_dir() {
+        let (dir, btrfs, uid) = fixture();
+        let home = dir.path().join("@home");
+        symlink(dir.path().join("elsewhere"), home.join(".butter-snapshots")).unwrap();
+
+        assert!(create_user_snapshot(&btrfs, uid, &home, "s1").is_err());
+        assert!(!dir.path().join("elsewhere/s1").exists());
+    }
+
+    #[test]
+    fn test_restore_outside() {
+        let (dir, btrfs, uid) = fixture();
+        let home = dir.path().join("@home");
+        let snapshot = create_user_snapshot(&btrfs, uid, &home, "s1").unwrap();
+        symlink(dir.path().join("elsewhere"), home.join("link")).unwrap();
+
+        assert!(restore_user_snapshot(&btrfs, uid, &snapshot, &home.join("link/s1")).is_err());
+        assert!(
+            restore_user_snapshot(&btrfs, uid, &snapshot, &dir.path().join("elsewhere/s1"))
+                .is_err()
+        );
+        assert!(!dir.path().join("elsewhere/s1").exists());
+    }
+}
@@ -0,0 +1,1181 @@
+use std::{
+    ffi::CString,
+    fs,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::Path,
+    sync::Arc,
+};
+
+use butterd::{
+    create_snapper_snapshot, create_snapshot, delete_subvolume, import_snapshots, preview_prune,
+    prune_rule, AuditLog, BtrfsBackend, FakeBtrfs, FilesystemProxy, FilesystemSpace, ImportTool,
+    KeepReason, Polkit, RuleConfig, RuleSubvolumeConfig, SnapperInfo, SnapshotGroup,
+    SnapshotLayout, SnapshotMetadata, Storage, StorageProxy, Subvolume, SubvolumeCreateOptions,
+    SubvolumeFilter, SubvolumeProperties,
+};
+use tempfile::TempDir;
+use tokio::net::UnixStream;
+use uuid::Uuid;
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// A fake filesystem with a `@home` subvolume and an empty `snapshots` directory
+fn fixture() -> (TempDir, Arc<FakeBtrfs>) {
+    let dir = tempfile::tempdir().unwrap();
+    let btrfs = Arc::new(FakeBtrfs::new(dir.path()));
+    btrfs.create_subvolume(&dir.path().join("@home")).unwrap();
+    fs::write(dir.path().join("@home/file"), "hello").unwrap();
+    fs::create_dir(dir.path().join("snapshots")).unwrap();
+    (dir, btrfs)
+}
+
+/// Serve a `Storage` backed by `btrfs` over a peer-to-peer connection
+async fn serve(btrfs: Arc<FakeBtrfs>) -> zbus::Result<(zbus::Connection, zbus::Connection)> {
+    let (server_stream, client_stream) = UnixStream::pair()?;
+    let (server, client) = futures::try_join!(
+        zbus::connection::Builder::unix_stream(server_stream)
+            .server(zbus::Guid::generate())?
+            .p2p()
+            .build(),
+        zbus::connection::Builder::unix_stream(client_stream)
+            .p2p()
+            .build(),
+    )?;
+
+    let polkit = Polkit::disabled();
+    let audit = AuditLog::new(&server, polkit.clone()).await?;
+    server
+        .object_server()
+        .at(Storage::PATH, Storage::new(btrfs, polkit, audit).await?)
+        .await?;
+    Ok((server, client))
+}
+
+async fn filesystem<'a>(client: &zbus::Connection, btrfs: &FakeBtrfs) -> FilesystemProxy<'a> {
+    let path = format!("{}/{}", Storage::PATH, btrfs.uuid().simple());
+    FilesystemProxy::builder(client)
+        .path(path)
+        .unwrap()
+        .build()
+        .await
+        .unwrap()
+}
+
+async fn list_subvolumes(client: &zbus::Connection, btrfs: &FakeBtrfs) -> Vec<Subvolume> {
+    filesystem(client, btrfs)
+        .await
+        .list_subvolumes()
+        .await
+        .unwrap()
+}
+
+fn find<'a>(subvols: &'a [Subvolume], root_path: &str) -> Option<&'a Subvolume> {
+    subvols
+        .iter()
+        .find(|s| s.root_path.as_path() == Path::new(root_path))
+}
+
+#[tokio::test]
+async fn test_list_and_snapshot() {
+    let (dir, btrfs) = fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let home = find(&subvols, "@home").unwrap();
+    assert_eq!(home.paths[0].as_path(), dir.path().join("@home"));
+    assert!(home.is_likely_primary());
+
+    let snapshot_path = dir.path().join("snapshots/s1");
+    storage
+        .create_snapshot(
+            dir.path().join("@home").into(),
+            snapshot_path.clone().into(),
+            true,
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(fs::read(snapshot_path.join("file")).unwrap(), b"hello");
+    assert!(!snapshot_path.join(".butter").exists());
+    assert!(SnapshotMetadata::read(btrfs.as_ref(), &snapshot_path).is_some());
+    assert!(btrfs.is_read_only(&snapshot_path).unwrap());
+
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let snapshot = find(&subvols, "snapshots/s1").unwrap();
+    assert_eq!(
+        snapshot.created_from_root_path.as_ref().unwrap().as_path(),
+        Path::new("@home")
+    );
+    assert!(!snapshot.is_likely_primary());
+}
+
+#[tokio::test]
+async fn test_remove_protected() {
+    let (dir, btrfs) = fixture();
+    let snapshot_path = dir.path().join("snapshots/s1");
+    create_snapshot(
+        btrfs.as_ref(),
+        &dir.path().join("@home"),
+        &snapshot_path,
+        true,
+        false,
+        None,
+    )
+    .unwrap();
+
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    assert!(storage
+        .remove_subvolumes(vec![dir.path().join("@home").into()], false)
+        .await
+        .is_err());
+    // reaching it through `..` or a symlink does not lift the protection
+    assert!(storage
+        .remove_subvolumes(vec![dir.path().join("snapshots/../@home").into()], false)
+        .await
+        .is_err());
+    std::os::unix::fs::symlink(dir.path().join("@home"), dir.path().join("snapshots/link"))
+        .unwrap();
+    assert!(storage
+        .remove_subvolumes(vec![dir.path().join("snapshots/link").into()], false)
+        .await
+        .is_err());
+    assert!(dir.path().join("@home").exists());
+
+    storage
+        .remove_subvolumes(vec![snapshot_path.clone().into()], false)
+        .await
+        .unwrap();
+    assert!(!snapshot_path.exists());
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    assert!(find(&subvols, "snapshots/s1").is_none());
+}
+
+#[tokio::test]
+async fn test_empty_trash() {
+    let (dir, btrfs) = fixture();
+    let old = dir.path().join("@old.pre-restore-1000");
+    let recent = dir
+        .path()
+        .join(format!("@new.pre-restore-{}", u64::MAX / 2));
+    btrfs.create_subvolume(&old).unwrap();
+    btrfs.create_subvolume(&recent).unwrap();
+    // still in use
+    let default = dir.path().join("@.pre-restore-1000");
+    btrfs.create_subvolume(&default).unwrap();
+    btrfs.set_default_subvolume(&default).unwrap();
+
+    let (server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    let iface_ref = server
+        .object_server()
+        .interface::<_, Storage>(Storage::PATH)
+        .await
+        .unwrap();
+    let deleted = iface_ref
+        .get()
+        .await
+        .empty_trash(&server.object_server(), DAY as u64)
+        .await
+        .unwrap();
+    assert_eq!(deleted, 1);
+    assert!(!old.exists());
+    assert!(recent.exists());
+    assert!(default.exists());
+    assert!(dir.path().join("@home").exists());
+}
+
+#[tokio::test]
+async fn test_recursive_snapshot() {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    btrfs.create_subvolume(&home.join(".cache")).unwrap();
+    fs::write(home.join(".cache/file"), "cached").unwrap();
+
+    let flat = dir.path().join("snapshots/flat");
+    create_snapshot(btrfs.as_ref(), &home, &flat, true, false, None).unwrap();
+    assert!(flat.join(".cache").is_dir());
+    assert!(!flat.join(".cache/file").exists());
+
+    let snapshot_path = dir.path().join("snapshots/s1");
+    create_snapshot(btrfs.as_ref(), &home, &snapshot_path, true, true, None).unwrap();
+    assert_eq!(
+        fs::read(snapshot_path.join(".cache/file")).unwrap(),
+        b"cached"
+    );
+    assert!(btrfs.is_read_only(&snapshot_path.join(".cache")).unwrap());
+
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let nested = find(&subvols, "snapshots/s1/.cache").unwrap();
+    assert_eq!(
+        nested.created_from_root_path.as_ref().unwrap().as_path(),
+        Path::new("@home/.cache")
+    );
+
+    storage
+        .remove_subvolumes(
+            vec![
+                snapshot_path.join(".cache").into(),
+                snapshot_path.clone().into(),
+            ],
+            false,
+        )
+        .await
+        .unwrap();
+    assert!(!snapshot_path.exists());
+    assert!(home.join(".cache/file").exists());
+}
+
+#[test]
+fn test_delete_subvolume_failure() {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    btrfs.create_subvolume(&home.join(".cache")).unwrap();
+    let snapshot_path = dir.path().join("snapshots/s1");
+    create_snapshot(btrfs.as_ref(), &home, &snapshot_path, true, true, None).unwrap();
+    btrfs.fail("delete_subvolume", &snapshot_path);
+
+    assert!(delete_subvolume(btrfs.as_ref(), &snapshot_path).is_err());
+    assert!(btrfs.is_read_only(&snapshot_path).unwrap());
+    assert!(btrfs.is_read_only(&snapshot_path.join(".cache")).unwrap());
+}
+
+#[tokio::test]
+async fn test_transids() {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    create_snapshot(
+        btrfs.as_ref(),
+        &home,
+        &dir.path().join("snapshots/s1"),
+        true,
+        false,
+        None,
+    )
+    .unwrap();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    StorageProxy::new(&client)
+        .await
+        .unwrap()
+        .refresh()
+        .await
+        .unwrap();
+
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let snapshot = find(&subvols, "snapshots/s1").unwrap();
+    assert!(snapshot.is_read_only);
+    assert!(snapshot.ctransid > snapshot.otransid);
+    assert_eq!((snapshot.stransid, snapshot.rtransid), (0, 0));
+    assert!(snapshot.received_uuid.is_none());
+    assert!(!find(&subvols, "@home").unwrap().is_changed_since(snapshot));
+
+    btrfs.touch(&home).unwrap();
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let snapshot = find(&subvols, "snapshots/s1").unwrap();
+    assert!(find(&subvols, "@home").unwrap().is_changed_since(snapshot));
+}
+
+#[tokio::test]
+async fn test_list_subvolumes_since() {
+    let (dir, btrfs) = fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    let fs = filesystem(&client, &btrfs).await;
+
+    let full = fs.list_subvolumes_since(0, 0).await.unwrap();
+    assert!(full.is_full);
+    assert_eq!(full.changed.len(), 2);
+
+    let unchanged = fs
+        .list_subvolumes_since(full.epoch, full.generation)
+        .await
+        .unwrap();
+    assert!(!unchanged.is_full);
+    assert!(unchanged.changed.is_empty() && unchanged.removed.is_empty());
+    assert_eq!(unchanged.generation, full.generation);
+
+    let snapshot_path = dir.path().join("snapshots/s1");
+    storage
+        .create_snapshot(
+            dir.path().join("@home").into(),
+            snapshot_path.clone().into(),
+            true,
+            false,
+        )
+        .await
+        .unwrap();
+    let created = fs
+        .list_subvolumes_since(full.epoch, full.generation)
+        .await
+        .unwrap();
+    assert!(!created.is_full);
+    assert_eq!(created.changed.len(), 1);
+    assert_eq!(
+        created.changed[0].root_path.as_path(),
+        Path::new("snapshots/s1")
+    );
+
+    storage
+        .remove_subvolumes(vec![snapshot_path.into()], false)
+        .await
+        .unwrap();
+    let removed = fs
+        .list_subvolumes_since(full.epoch, created.generation)
+        .await
+        .unwrap();
+    assert!(removed.changed.is_empty());
+    assert_eq!(removed.removed, [created.changed[0].uuid]);
+
+    // generations of another daemon instance are not trusted
+    let other = fs
+        .list_subvolumes_since(full.epoch.wrapping_add(1), removed.generation)
+        .await
+        .unwrap();
+    assert!(other.is_full);
+    assert_eq!(other.epoch, full.epoch);
+    assert_eq!(other.changed.len(), 2);
+}
+
+#[tokio::test]
+async fn test_list_subvolumes_filtered() {
+    let (dir, btrfs) = fixture();
+    for i in 0..4 {
+        btrfs.set_now(1_700_000_000 + i * DAY);
+        let dst = dir.path().join(format!("snapshots/{}", i));
+        create_snapshot(
+            btrfs.as_ref(),
+            &dir.path().join("@home"),
+            &dst,
+            i % 2 == 0,
+            false,
+            None,
+        )
+        .unwrap();
+    }
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    StorageProxy::new(&client)
+        .await
+        .unwrap()
+        .refresh()
+        .await
+        .unwrap();
+    let fs = filesystem(&client, &btrfs).await;
+    let names = |subvols: Vec<Subvolume>| -> Vec<String> {
+        subvols
+            .iter()
+            .map(|s| s.root_path.as_path().display().to_string())
+            .collect()
+    };
+
+    let primary = SubvolumeFilter {
+        is_primary: Some(true),
+        ..Default::default()
+    };
+    let home = fs.list_subvolumes_filtered(primary, 0, 0).await.unwrap();
+    assert_eq!(names(home.clone()), ["", "@home"]);
+
+    let snapshots = SubvolumeFilter {
+        source_uuid: Some(home[1].uuid),
+        created_after: Some(1_700_000_000 + DAY),
+        ..Default::default()
+    };
+    let page = fs
+        .list_subvolumes_filtered(snapshots.clone(), 0, 2)
+        .await
+        .unwrap();
+    assert_eq!(names(page), ["snapshots/1", "snapshots/2"]);
+    let page = fs.list_subvolumes_filtered(snapshots, 2, 2).await.unwrap();
+    assert_eq!(names(page), ["snapshots/3"]);
+
+    let read_only = SubvolumeFilter {
+        read_only: Some(true),
+        path_prefix: Some(dir.path().join("snapshots").into()),
+        ..Default::default()
+    };
+    let page = fs.list_subvolumes_filtered(read_only, 0, 0).await.unwrap();
+    assert_eq!(names(page), ["snapshots/0", "snapshots/2"]);
+}
+
+fn list_dir(path: &Path) -> Vec<String> {
+    let mut ret: Vec<String> = fs::read_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    ret.sort();
+    ret
+}
+
+#[test]
+fn test_prune_rule() {
+    let (dir, btrfs) = fixture();
+    let subvol_cfg = RuleSubvolumeConfig {
+        path: dir.path().join("@home"),
+        target_dir: dir.path().join("snapshots"),
+        recursive: false,
+        ..Default::default()
+    };
+    // two snapshots a day for four days
+    for i in 0..8 {
+        btrfs.set_now(1_700_000_000 + i * DAY / 2);
+        let dst = subvol_cfg.target_dir.join(i.to_string());
+        create_snapshot(btrfs.as_ref(), &subvol_cfg.path, &dst, true, false, None).unwrap();
+    }
+
+    let rule_cfg = RuleConfig {
+        keep_daily: 2,
+        subvolumes: vec![subvol_cfg.clone()],
+        ..Default::default()
+    };
+    let out = prune_rule(btrfs.as_ref(), &rule_cfg);
+
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["6", "7"]);
+    assert_eq!(out.pruned.len(), 6);
+    assert!(out.errors.is_empty());
+}
+
+#[test]
+fn test_preview_prune() {
+    let (dir, btrfs) = fixture();
+    let subvol_cfg = RuleSubvolumeConfig {
+        path: dir.path().join("@home"),
+        target_dir: dir.path().join("snapshots"),
+        ..Default::default()
+    };
+    for i in 0..4 {
+        btrfs.set_now(1_700_000_000 + i * DAY / 2);
+        let dst = subvol_cfg.target_dir.join(i.to_string());
+        create_snapshot(btrfs.as_ref(), &subvol_cfg.path, &dst, true, false, None).unwrap();
+    }
+    let rule_cfg = RuleConfig {
+        keep_hourly: 1,
+        keep_daily: 2,
+        subvolumes: vec![subvol_cfg.clone()],
+        ..Default::default()
+    };
+
+    let preview = preview_prune(btrfs.as_ref(), &rule_cfg).unwrap();
+    let kept_by: Vec<_> = preview
+        .iter()
+        .map(|d| {
+            (
+                d.path.file_name().unwrap().to_str().unwrap(),
+                d.kept_by.clone(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        kept_by,
+        [
+            ("3", vec![KeepReason::Hourly, KeepReason::Daily]),
+            ("2", vec![KeepReason::Daily]),
+            ("1", vec![]),
+            ("0", vec![]),
+        ]
+    );
+    // nothing deleted
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["0", "1", "2", "3"]);
+
+    prune_rule(btrfs.as_ref(), &rule_cfg);
+    let kept: Vec<_> = preview
+        .into_iter()
+        .filter(|d| d.is_kept())
+        .map(|d| d.path)
+        .collect();
+    assert_eq!(
+        kept,
+        ["3", "2"].map(|n| subvol_cfg.target_dir.join(n)).to_vec()
+    );
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["2", "3"]);
+}
+
+#[test]
+fn test_prune_space_limits() {
+    const GIB: u64 = 1 << 30;
+    let (dir, btrfs) = fixture();
+    let subvol_cfg = RuleSubvolumeConfig {
+        path: dir.path().join("@home"),
+        target_dir: dir.path().join("snapshots"),
+        ..Default::default()
+    };
+    for i in 0..4 {
+        btrfs.set_now(1_700_000_000 + i * DAY);
+        let dst = subvol_cfg.target_dir.join(i.to_string());
+        create_snapshot(btrfs.as_ref(), &subvol_cfg.path, &dst, true, false, None).unwrap();
+    }
+    let mut rule_cfg = RuleConfig {
+        keep_last: 1,
+        keep_daily: 10,
+        min_free_bytes: 30 * GIB,
+        subvolumes: vec![subvol_cfg.clone()],
+        ..Default::default()
+    };
+
+    // without quotas there is no telling what deleting frees, so the limits
+    // are skipped
+    let decisions = preview_prune(btrfs.as_ref(), &rule_cfg).unwrap();
+    assert_eq!(decisions.len(), 4);
+    assert!(decisions.iter().all(|d| !d.for_space));
+
+    btrfs.set_space(FilesystemSpace {
+        total_bytes: 100 * GIB,
+        free_bytes: 15 * GIB,
+    });
+    for i in 0..4 {
+        btrfs
+            .set_exclusive_bytes(&subvol_cfg.target_dir.join(i.to_string()), 10 * GIB)
+            .unwrap();
+    }
+    let for_space: Vec<_> = preview_prune(btrfs.as_ref(), &rule_cfg)
+        .unwrap()
+        .into_iter()
+        .filter(|d| d.for_space)
+        .map(|d| d.path)
+        .collect();
+    assert_eq!(
+        for_space,
+        ["1", "0"].map(|n| subvol_cfg.target_dir.join(n)).to_vec()
+    );
+
+    let out = prune_rule(btrfs.as_ref(), &rule_cfg);
+    assert!(out.errors.is_empty());
+    assert_eq!(out.pruned_for_space, for_space);
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["2", "3"]);
+
+    // the newest is kept by keep_last whatever the limits
+    rule_cfg.max_snapshots_percent = 5;
+    let out = prune_rule(btrfs.as_ref(), &rule_cfg);
+    assert_eq!(out.pruned_for_space, [subvol_cfg.target_dir.join("2")]);
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["3"]);
+}
+
+/// Snapshot `@home` and `@var` together once a day for three days
+fn grouped_fixture() -> (TempDir, Arc<FakeBtrfs>, RuleConfig) {
+    let (dir, btrfs) = fixture();
+    btrfs.create_subvolume(&dir.path().join("@var")).unwrap();
+    let rule_cfg = RuleConfig {
+        keep_daily: 2,
+        subvolumes: ["@home", "@var"]
+            .into_iter()
+            .map(|name| RuleSubvolumeConfig {
+                path: dir.path().join(name),
+                target_dir: dir.path().join("snapshots").join(name),
+                recursive: false,
+                ..Default::default()
+            })
+            .collect(),
+        ..Default::default()
+    };
+    for i in 0..3 {
+        let group = SnapshotGroup {
+            uuid: Uuid::new_v4(),
+            created_unix_secs: 1_700_000_000 + i * DAY,
+        };
+        for (j, subvol_cfg) in rule_cfg.subvolumes.iter().enumerate() {
+            // members of a group are not created at the very same second
+            btrfs.set_now(group.created_unix_secs + j as i64 * DAY / 2);
+            let dst = subvol_cfg.target_dir.join(i.to_string());
+            create_snapshot(
+                btrfs.as_ref(),
+                &subvol_cfg.path,
+                &dst,
+                true,
+                false,
+                Some(&group),
+            )
+            .unwrap();
+        }
+    }
+    (dir, btrfs, rule_cfg)
+}
+
+#[test]
+fn test_prune_group() {
+    let (dir, btrfs, rule_cfg) = grouped_fixture();
+    prune_rule(btrfs.as_ref(), &rule_cfg);
+
+    assert_eq!(list_dir(&dir.path().join("snapshots/@home")), ["1", "2"]);
+    assert_eq!(list_dir(&dir.path().join("snapshots/@var")), ["1", "2"]);
+}
+
+#[tokio::test]
+async fn test_snapshot_group() {
+    let (dir, btrfs, _) = grouped_fixture();
+    fs::write(dir.path().join("@home/file"), "changed").unwrap();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    let group_uuid = find(&subvols, "snapshots/@home/0")
+        .unwrap()
+        .group_uuid
+        .unwrap();
+    assert_eq!(
+        *find(&subvols, "snapshots/@var/0").unwrap().group_uuid,
+        Some(group_uuid)
+    );
+    assert!(find(&subvols, "@home").unwrap().group_uuid.is_none());
+
+    let group = SubvolumeFilter {
+        group_uuid: Some(group_uuid),
+        ..Default::default()
+    };
+    let members = filesystem(&client, &btrfs)
+        .await
+        .list_subvolumes_filtered(group, 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(members.len(), 2);
+
+    let backups = storage
+        .restore_snapshots(members.iter().map(|s| s.paths[0].clone()).collect())
+        .await
+        .unwrap();
+    assert_eq!(backups.len(), 2);
+    assert_eq!(fs::read(dir.path().join("@home/file")).unwrap(), b"hello");
+    assert_eq!(
+        fs::read(backups[0].as_path().join("file")).unwrap(),
+        b"changed"
+    );
+    assert!(!btrfs.is_read_only(&dir.path().join("@home")).unwrap());
+
+    storage
+        .remove_subvolumes(members.iter().map(|s| s.paths[0].clone()).collect(), false)
+        .await
+        .unwrap();
+    assert_eq!(list_dir(&dir.path().join("snapshots/@home")), ["1", "2"]);
+    assert_eq!(list_dir(&dir.path().join("snapshots/@var")), ["1", "2"]);
+}
+
+#[tokio::test]
+async fn test_restore_failure() {
+    let (dir, btrfs, _) = grouped_fixture();
+    fs::write(dir.path().join("@home/file"), "changed").unwrap();
+    btrfs.fail("create_snapshot", &dir.path().join("@var"));
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    let snapshots = ["snapshots/@home/0", "snapshots/@var/0"];
+    assert!(storage
+        .restore_snapshots(snapshots.map(|p| dir.path().join(p).into()).to_vec())
+        .await
+        .is_err());
+    // everything is back in place
+    assert_eq!(fs::read(dir.path().join("@home/file")).unwrap(), b"changed");
+    assert_eq!(
+        list_dir(dir.path()),
+        [".butter", "@home", "@var", "snapshots"]
+    );
+    let subvols = list_subvolumes(&client, &btrfs).await;
+    assert!(find(&subvols, "@home")
+        .unwrap()
+        .created_from_root_path
+        .is_none());
+    assert!(find(&subvols, "@var").is_some());
+}
+
+/// A snapshot of `@home` taken before `@home` was deleted and recreated
+fn orphan_fixture() -> (TempDir, Arc<FakeBtrfs>) {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    btrfs.set_now(1_700_000_000);
+    create_snapshot(
+        btrfs.as_ref(),
+        &home,
+        &dir.path().join("snapshots/old"),
+        true,
+        false,
+        None,
+    )
+    .unwrap();
+    delete_subvolume(btrfs.as_ref(), &home).unwrap();
+    btrfs.create_subvolume(&home).unwrap();
+    create_snapshot(
+        btrfs.as_ref(),
+        &home,
+        &dir.path().join("snapshots/new"),
+        true,
+        false,
+        None,
+    )
+    .unwrap();
+    (dir, btrfs)
+}
+
+#[tokio::test]
+async fn test_adopt_orphans() {
+    let (dir, btrfs) = orphan_fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+
+    let orphaned = SubvolumeFilter {
+        is_orphaned: Some(true),
+        ..Default::default()
+    };
+    let orphans = filesystem(&client, &btrfs)
+        .await
+        .list_subvolumes_filtered(orphaned, 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(orphans.len(), 1);
+    assert_eq!(orphans[0].root_path.as_path(), Path::new("snapshots/old"));
+
+    assert!(storage
+        .adopt_snapshots(
+            vec![dir.path().join("snapshots/new").into()],
+            dir.path().join("@home").into(),
+            dir.path().join("snapshots/home").into(),
+        )
+        .await
+        .is_err());
+
+    // a taken destination fails before anything is touched
+    btrfs.create_subvolume(&dir.path().join("@var")).unwrap();
+    fs::create_dir_all(dir.path().join("snapshots/var/old")).unwrap();
+    assert!(storage
+        .adopt_snapshots(
+            vec![orphans[0].paths[0].clone()],
+            dir.path().join("@var").into(),
+            dir.path().join("snapshots/var").into(),
+        )
+        .await
+        .is_err());
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), orphans[0].paths[0].as_path()).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+
+    let adopted = storage
+        .adopt_snapshots(
+            vec![orphans[0].paths[0].clone()],
+            dir.path().join("@home").into(),
+            dir.path().join("snapshots/home").into(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(adopted[0].as_path(), dir.path().join("snapshots/home/old"));
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), adopted[0].as_path()).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+    assert!(btrfs.is_read_only(adopted[0].as_path()).unwrap());
+}
+
+#[test]
+fn test_prune_orphans() {
+    let (dir, btrfs) = orphan_fixture();
+    // snapshots not taken by butter are kept
+    let tmp = dir.path().join("@tmp");
+    btrfs.create_subvolume(&tmp).unwrap();
+    BtrfsBackend::create_snapshot(
+        btrfs.as_ref(),
+        &tmp,
+        &dir.path().join("snapshots/foreign"),
+        false,
+    )
+    .unwrap();
+    btrfs.delete_subvolume(&tmp).unwrap();
+    let rule_cfg = RuleConfig {
+        keep_orphaned_days: 1,
+        subvolumes: vec![RuleSubvolumeConfig {
+            path: dir.path().join("@home"),
+            target_dir: dir.path().join("snapshots"),
+            recursive: false,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    prune_rule(btrfs.as_ref(), &rule_cfg);
+
+    assert_eq!(list_dir(&dir.path().join("snapshots")), ["foreign", "new"]);
+}
+
+#[tokio::test]
+async fn test_metadata_store() {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    let store_dir = dir.path().join(".butter/metadata");
+
+    // the store is used even if the top-level subvolume is not mounted
+    btrfs.unmount(dir.path());
+    let snapshot_path = dir.path().join("snapshots/s1");
+    create_snapshot(btrfs.as_ref(), &home, &snapshot_path, true, false, None).unwrap();
+    let uuid = btrfs.subvolume_info(&snapshot_path).unwrap().uuid;
+    assert!(store_dir.join(format!("{}.json", uuid)).exists());
+    assert!(!snapshot_path.join(".butter").exists());
+    btrfs.mount(dir.path()).unwrap();
+
+    // older versions kept it inside the snapshot
+    let legacy = dir.path().join("snapshots/legacy");
+    let received = dir.path().join("snapshots/received");
+    for path in [&legacy, &received] {
+        create_snapshot(btrfs.as_ref(), &home, path, false, false, None).unwrap();
+        let uuid = btrfs.subvolume_info(path).unwrap().uuid;
+        fs::create_dir(path.join(".butter")).unwrap();
+        fs::rename(
+            store_dir.join(format!("{}.json", uuid)),
+            path.join(".butter/info.json"),
+        )
+        .unwrap();
+        btrfs.set_read_only(path, true).unwrap();
+    }
+    btrfs.set_received(&received, Uuid::new_v4()).unwrap();
+    // received snapshots are never written to, which would break
+    // incremental sends
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &received).unwrap();
+    metadata.write(btrfs.as_ref(), &received).unwrap();
+    assert!(received.join(".butter/info.json").exists());
+    assert!(btrfs.is_read_only(&received).unwrap());
+
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &legacy).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    assert_eq!(storage.migrate_metadata(true).await.unwrap(), 2);
+    assert!(!legacy.join(".butter").exists());
+    assert!(received.join(".butter/info.json").exists());
+    assert!(btrfs.is_read_only(&legacy).unwrap());
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &legacy).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+
+    delete_subvolume(btrfs.as_ref(), &snapshot_path).unwrap();
+    assert!(!store_dir.join(format!("{}.json", uuid)).exists());
+}
+
+#[test]
+fn test_import_snapper() {
+    let (dir, btrfs) = fixture();
+    let home = dir.path().join("@home");
+    let snapper_dir = home.join(".snapshots");
+    btrfs.create_subvolume(&snapper_dir).unwrap();
+    fs::create_dir(snapper_dir.join("1")).unwrap();
+    fs::write(
+        snapper_dir.join("1/info.xml"),
+        "<?xml version=\"1.0\"?>\n<snapshot>\n  <type>post</type>\n  <num>1</num>\n  \
+         <date>2024-01-02 03:04:05</date>\n  <pre_num>0</pre_num>\n  \
+         <description>zypp &amp; more</description>\n</snapshot>\n",
+    )
+    .unwrap();
+    btrfs
+        .create_snapshot(&home, &snapper_dir.join("1/snapshot"), false)
+        .unwrap();
+    btrfs
+        .set_read_only(&snapper_dir.join("1/snapshot"), true)
+        .unwrap();
+
+    let paths = import_snapshots(btrfs.as_ref(), ImportTool::Snapper, &snapper_dir, None).unwrap();
+    assert_eq!(paths, [snapper_dir.join("1/snapshot")]);
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &paths[0]).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+    let imported = metadata.imported.unwrap();
+    assert_eq!(imported.kind, "post");
+    assert_eq!(imported.number, Some(1));
+    assert_eq!(imported.pre_number, Some(0));
+    assert_eq!(imported.description, "zypp & more");
+    assert_eq!(imported.created_unix_secs, 1704164645);
+
+    let rule_cfg = RuleConfig {
+        subvolumes: vec![RuleSubvolumeConfig {
+            path: home.clone(),
+            target_dir: dir.path().join("snapshots/home"),
+            recursive: false,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let paths = import_snapshots(
+        btrfs.as_ref(),
+        ImportTool::Snapper,
+        &snapper_dir,
+        Some(&rule_cfg),
+    )
+    .unwrap();
+    assert_eq!(paths, [dir.path().join("snapshots/home/snapper-1")]);
+    assert!(btrfs.is_read_only(&paths[0]).unwrap());
+    assert!(!snapper_dir.join("1").exists());
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &paths[0]).unwrap();
+    assert_eq!(metadata.imported.unwrap().number, Some(1));
+}
+
+#[test]
+fn test_import_timeshift() {
+    let (dir, btrfs) = fixture();
+    let container = dir.path().join("timeshift/snapshots/2024-01-02_03-04-05");
+    fs::create_dir_all(&container).unwrap();
+    fs::write(
+        container.join("info.json"),
+        r#"{"created": "1704164645", "comments": "before upgrade", "tags": "O D"}"#,
+    )
+    .unwrap();
+    btrfs
+        .create_snapshot(&dir.path().join("@home"), &container.join("@home"), false)
+        .unwrap();
+
+    let paths = import_snapshots(
+        btrfs.as_ref(),
+        ImportTool::Timeshift,
+        &dir.path().join("timeshift/snapshots"),
+        None,
+    )
+    .unwrap();
+    assert_eq!(paths, [container.join("@home")]);
+    let metadata = SnapshotMetadata::read(btrfs.as_ref(), &paths[0]).unwrap();
+    assert_eq!(metadata.created_from, Path::new("@home"));
+    assert_eq!(metadata.group.unwrap().created_unix_secs, 1704164645);
+    let imported = metadata.imported.unwrap();
+    assert_eq!(imported.kind, "O D");
+    assert_eq!(imported.description, "before upgrade");
+}
+
+#[test]
+fn test_snapper_layout() {
+    let (dir, btrfs) = fixture();
+    let rule_cfg = RuleConfig {
+        keep_daily: 2,
+        subvolumes: vec![RuleSubvolumeConfig {
+            path: dir.path().join("@home"),
+            target_dir: dir.path().join("snapshots"),
+            layout: SnapshotLayout::Snapper,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let subvol_cfg = &rule_cfg.subvolumes[0];
+    for i in 0..3 {
+        btrfs.set_now(1_700_000_000 + i * DAY);
+        let path = create_snapper_snapshot(
+            btrfs.as_ref(),
+            &subvol_cfg.path,
+            &subvol_cfg.target_dir,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            path,
+            subvol_cfg.target_dir.join(format!("{}/snapshot", i + 1))
+        );
+        assert!(btrfs.is_read_only(&path).unwrap());
+    }
+    let info = SnapperInfo::read(&subvol_cfg.target_dir.join("3")).unwrap();
+    assert_eq!(info.number, 3);
+    assert_eq!(info.kind, "single");
+    assert_eq!(info.created_unix_secs, 1_700_000_000 + 2 * DAY);
+
+    prune_rule(btrfs.as_ref(), &rule_cfg);
+    assert_eq!(list_dir(&subvol_cfg.target_dir), ["2", "3"]);
+}
+
+#[tokio::test]
+async fn test_subvolume_properties() {
+    let (dir, btrfs) = fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    let home = dir.path().join("@home");
+
+    let props = SubvolumeProperties {
+        compression: Some("zstd:3".to_owned()),
+        nodatacow: Some(true),
+    };
+    storage
+        .set_subvolume_properties(home.clone().into(), props.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        storage
+            .subvolume_properties(home.clone().into())
+            .await
+            .unwrap(),
+        props
+    );
+
+    let invalid = SubvolumeProperties {
+        compression: Some("zstd:99".to_owned()),
+        ..Default::default()
+    };
+    assert!(storage
+        .set_subvolume_properties(home.clone().into(), invalid)
+        .await
+        .is_err());
+    assert!(storage
+        .set_subvolume_properties(home.join("file").into(), props)
+        .await
+        .is_err());
+
+    storage
+        .set_filesystem_label(btrfs.uuid().into(), "data".to_owned())
+        .await
+        .unwrap();
+    assert_eq!(btrfs.label(dir.path()).unwrap(), "data");
+}
+
+#[tokio::test]
+async fn test_create_subvolume() {
+    let (dir, btrfs) = fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    let cache = dir.path().join("@cache");
+
+    let options = SubvolumeCreateOptions {
+        inherit_qgroups: Some(vec!["1/100".to_owned()]),
+        compression: Some("zstd".to_owned()),
+        nodatacow: Some(false),
+    };
+    storage
+        .create_subvolume(cache.clone().into(), options)
+        .await
+        .unwrap();
+    assert!(btrfs.is_subvolume(&cache).unwrap());
+    assert_eq!(btrfs.qgroups(&cache).unwrap(), vec![(1 << 48) | 100]);
+    assert_eq!(btrfs.compression(&cache).unwrap(), "zstd");
+    assert!(SnapshotMetadata::read(btrfs.as_ref(), &cache).is_none());
+
+    // nothing is left behind by invalid options
+    let invalid = SubvolumeCreateOptions {
+        compression: Some("lzo:1".to_owned()),
+        ..Default::default()
+    };
+    let tmp = dir.path().join("@tmp");
+    assert!(storage
+        .create_subvolume(tmp.clone().into(), invalid)
+        .await
+        .is_err());
+    assert!(!tmp.exists());
+    assert!(storage
+        .create_subvolume(cache.into(), Default::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_convert_to_subvolume() {
+    let (dir, btrfs) = fixture();
+    let (_server, client) = serve(btrfs.clone()).await.unwrap();
+    let storage = StorageProxy::new(&client).await.unwrap();
+    storage.refresh().await.unwrap();
+    let cache = dir.path().join("@home/.cache");
+    fs::create_dir_all(cache.join("app")).unwrap();
+    fs::write(cache.join("app/data"), "cached").unwrap();
+    std::os::unix::fs::symlink("app/data", cache.join("link")).unwrap();
+    // not every filesystem the tests run on has user xattrs
+    let has_xattrs = set_xattr(&cache.join("app/data"), "user.butter", b"kept");
+
+    storage
+        .convert_to_subvolume(cache.clone().into())
+        .await
+        .unwrap();
+    assert!(btrfs.is_subvolume(&cache).unwrap());
+    assert_eq!(fs::read_to_string(cache.join("link")).unwrap(), "cached");
+    if has_xattrs {
+        assert_eq!(
+            get_xattr(&cache.join("app/data"), "user.butter").as_deref(),
+            Some(&b"kept"[..])
+        );
+    }
+    assert_eq!(list_dir(&dir.path().join("@home")), vec![".cache", "file"]);
+    assert!(find(&list_subvolumes(&client, &btrfs).await, "@home/.cache").is_some());
+
+    // already a subvolume, or not a directory
+    assert!(storage.convert_to_subvolume(cache.into()).await.is_err());
+    assert!(storage
+        .convert_to_subvolume(dir.path().join("@home/file").into())
+        .await
+        .is_err());
+
+    // nested subvolumes cannot be copied
+    let outer = dir.path().join("@home/vm");
+    fs::create_dir(&outer).unwrap();
+    btrfs.create_subvolume(&outer.join("images")).unwrap();
+    assert!(storage
+        .convert_to_subvolume(outer.clone().into())
+        .await
+        .is_err());
+    assert!(!btrfs.is_subvolume(&outer).unwrap());
+    assert!(btrfs.is_subvolume(&outer.join("images")).unwrap());
+
+    // leftovers of the original do not fail the conversion
+    let locked = dir.path().join("@home/locked");
+    fs::create_dir(&locked).unwrap();
+    fs::write(locked.join("file"), "kept").unwrap();
+    // needs root and a filesystem with inode flags
+    if set_immutable(&locked.join("file"), true) {
+        storage
+            .convert_to_subvolume(locked.clone().into())
+            .await
+            .unwrap();
+        assert!(btrfs.is_subvolume(&locked).unwrap());
+        assert_eq!(fs::read_to_string(locked.join("file")).unwrap(), "kept");
+        let original = dir.path().join("@home/.locked.butter-convert/file");
+        assert!(set_immutable(&original, false));
+    }
+
+    // only directories on known filesystems
+    let elsewhere = tempfile::tempdir().unwrap();
+    assert!(storage
+        .convert_to_subvolume(elsewhere.path().to_owned().into())
+        .await
+        .is_err());
+}
+
+/// Set or clear `FS_IMMUTABLE_FL` on `path`, `false` if that failed
+fn set_immutable(path: &Path, immutable: bool) -> bool {
+    const FS_IOC_GETFLAGS: libc::c_ulong = 0x8008_6601;
+    const FS_IOC_SETFLAGS: libc::c_ulong = 0x4008_6602;
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+    let Ok(f) = fs::File::open(path) else {
+        return false;
+    };
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } < 0 {
+        return false;
+    }
+    if immutable {
+        flags |= FS_IMMUTABLE_FL;
+    } else {
+        flags &= !FS_IMMUTABLE_FL;
+    }
+    unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) == 0 }
+}
+
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> bool {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let ret = unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            0,
+        )
+    };
+    ret == 0
+}
+
+fn get_xattr(path: &Path, name: &str) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    let name = CString::new(name).unwrap();
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    (len >= 0).then(|| buf[..len as usize].to_vec())
+}
@@ -2,10 +2,17 @@ mod object;
 mod schedule_exec;
 mod ui;
 
-use butterd::{config, ReadScheduleDir};
-use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+use butterd::{
+    config, FilesystemProxyBlocking, ReadScheduleDir, RuleConfig, RuleHistory, RuleRun,
+    StorageProxyBlocking, SubvolumeFilter,
+};
+use clap::{Args, Parser, Subcommand};
 use gtk::{gio, prelude::*};
 use ui::{store::Store, Application};
+use uuid::Uuid;
+use zbus::{blocking::fdo::ObjectManagerProxy, proxy::ProxyDefault};
 
 #[derive(Parser)]
 struct Cli {
@@ -19,21 +26,139 @@ enum Cmd {
         #[clap(subcommand)]
         cmd: ScheduleCmd,
     },
+    /// List subvolumes of every Btrfs filesystem
+    List(ListArgs),
+    /// Make orphaned snapshots snapshots of another subvolume, so that the
+    /// rule snapshotting it into the target directory prunes them
+    Adopt {
+        /// Subvolume the snapshots now belong to
+        #[clap(long)]
+        source: PathBuf,
+        /// Directory the snapshots are moved into
+        #[clap(long)]
+        target_dir: PathBuf,
+        /// Orphaned snapshots
+        #[clap(required = true)]
+        snapshots: Vec<PathBuf>,
+    },
+    /// Move snapshot metadata stored inside snapshots by older versions to
+    /// the top-level subvolume of each filesystem
+    MigrateMetadata {
+        /// Delete the old copies, except in received snapshots
+        #[clap(long)]
+        remove_old: bool,
+    },
+    /// Turn snapshots taken by snapper or Timeshift into Butter snapshots
+    Import {
+        #[clap(value_parser = ["snapper", "timeshift"])]
+        tool: String,
+        /// snapper's `.snapshots` or Timeshift's `snapshots` directory
+        dir: PathBuf,
+        /// Move the snapshots into the target directories of this rule, which
+        /// prunes them from then on
+        #[clap(long)]
+        rule: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Only snapshots of the subvolume with this UUID
+    #[clap(long)]
+    source: Option<Uuid>,
+    /// Only subvolumes under this path
+    #[clap(long)]
+    path_prefix: Option<PathBuf>,
+    /// Only subvolumes created at or after this unix time
+    #[clap(long)]
+    created_after: Option<i64>,
+    /// Only subvolumes created before this unix time
+    #[clap(long)]
+    created_before: Option<i64>,
+    /// Only snapshots
+    #[clap(long, conflicts_with = "primary")]
+    snapshots: bool,
+    /// Only primary subvolumes
+    #[clap(long)]
+    primary: bool,
+    /// Only read-only subvolumes
+    #[clap(long)]
+    read_only: bool,
+    /// Only snapshots of the snapshot group with this UUID
+    #[clap(long)]
+    group: Option<Uuid>,
+    /// Only snapshots whose source subvolume no longer exists
+    #[clap(long)]
+    orphaned: bool,
+    /// Skip this many subvolumes of each filesystem
+    #[clap(long, default_value_t = 0)]
+    offset: u32,
+    /// List at most this many subvolumes of each filesystem, 0 for no limit
+    #[clap(long, default_value_t = 0)]
+    limit: u32,
+}
+
+impl From<&ListArgs> for SubvolumeFilter {
+    fn from(args: &ListArgs) -> Self {
+        Self {
+            source_uuid: args.source.map(Into::into),
+            path_prefix: args.path_prefix.clone().map(Into::into),
+            created_after: args.created_after,
+            created_before: args.created_before,
+            is_snapshot: args.snapshots.then_some(true),
+            is_primary: args.primary.then_some(true),
+            read_only: args.read_only.then_some(true),
+            group_uuid: args.group.map(Into::into),
+            is_orphaned: args.orphaned.then_some(true),
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum ScheduleCmd {
-    Snapshot,
-    Prune,
+    /// Snapshot every rule without its own schedule
+    Snapshot {
+        /// Only snapshot this rule, even if it has its own schedule
+        #[clap(long)]
+        rule: Option<String>,
+    },
+    /// Prune every rule
+    Prune {
+        /// Only prune this rule
+        #[clap(long)]
+        rule: Option<String>,
+        /// Print which snapshots would be kept and why, without deleting any
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Snapshot and prune the rule NAME, run by its own timer
+    Run { name: String },
 }
 
 fn main() {
     let cli = Cli::parse();
     match cli.cmd {
         Some(Cmd::Schedule { cmd }) => match cmd {
-            ScheduleCmd::Snapshot => cmd_snapshot(),
-            ScheduleCmd::Prune => cmd_prune(),
+            ScheduleCmd::Snapshot { rule } => {
+                cmd_snapshot(rule.as_deref()).expect("Failed to snapshot")
+            }
+            ScheduleCmd::Prune { rule, dry_run } => {
+                cmd_prune(rule.as_deref(), dry_run).expect("Failed to prune")
+            }
+            ScheduleCmd::Run { name } => cmd_run(&name).expect("Failed to run rule"),
         },
+        Some(Cmd::List(args)) => cmd_list(&args).expect("Failed to list subvolumes"),
+        Some(Cmd::Adopt {
+            source,
+            target_dir,
+            snapshots,
+        }) => cmd_adopt(source, target_dir, snapshots).expect("Failed to adopt snapshots"),
+        Some(Cmd::MigrateMetadata { remove_old }) => {
+            cmd_migrate_metadata(remove_old).expect("Failed to migrate metadata")
+        }
+        Some(Cmd::Import { tool, dir, rule }) => {
+            cmd_import(tool, dir, rule).expect("Failed to import snapshots")
+        }
         None => gui(),
     }
 }
@@ -55,20 +180,136 @@ fn gui() {
     app.run();
 }
 
-pub fn cmd_snapshot() {
-    for (_, config) in ReadScheduleDir::new()
-        .expect("Failed to read config directory")
-        .flatten()
-    {
-        schedule_exec::snapshot(&config);
+/// Record `run` of the rule `name` in its history, returning whether it
+/// succeeded
+fn finish_run(name: &str, mut run: RuleRun) -> bool {
+    run.finish();
+    if let Err(err) = RuleHistory::new(name).push(&run) {
+        eprintln!("failed to record the run of '{}': {}", name, err);
     }
+    run.is_ok()
 }
 
-pub fn cmd_prune() {
-    for (_, config) in ReadScheduleDir::new()
-        .expect("Failed to read config directory")
-        .flatten()
+/// Fail if any of the `failed` rules did
+fn check_failed(failed: Vec<String>) -> anyhow::Result<()> {
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("rules failed: {}", failed.join(", ")))
+    }
+}
+
+/// The rule `name`, or every rule
+fn read_rules(name: Option<&str>) -> anyhow::Result<Vec<(String, RuleConfig)>> {
+    Ok(match name {
+        Some(name) => vec![(name.to_owned(), RuleConfig::read(name)?)],
+        None => ReadScheduleDir::new()?.flatten().collect(),
+    })
+}
+
+pub fn cmd_snapshot(rule: Option<&str>) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    for (name, config) in read_rules(rule)?
+        .into_iter()
+        .filter(|(_, config)| rule.is_some() || config.schedule.is_empty())
     {
-        schedule_exec::prune(&config);
+        let mut run = RuleRun::start();
+        schedule_exec::snapshot(&name, &config, &mut run);
+        if !finish_run(&name, run) {
+            failed.push(name);
+        }
+    }
+    check_failed(failed)
+}
+
+pub fn cmd_prune(rule: Option<&str>, dry_run: bool) -> anyhow::Result<()> {
+    if dry_run {
+        for (name, config) in read_rules(rule)? {
+            schedule_exec::preview_prune(&name, &config)?;
+        }
+        return Ok(());
+    }
+    let mut failed = Vec::new();
+    for (name, config) in read_rules(rule)? {
+        let mut run = RuleRun::start();
+        // nothing worth recording without a retention policy
+        if schedule_exec::prune(&config, &mut run) && !finish_run(&name, run) {
+            failed.push(name);
+        }
+    }
+    check_failed(failed)
+}
+
+pub fn cmd_run(name: &str) -> anyhow::Result<()> {
+    let config = RuleConfig::read(name)?;
+    let mut run = RuleRun::start();
+    schedule_exec::snapshot(name, &config, &mut run);
+    schedule_exec::prune(&config, &mut run);
+    check_failed(if finish_run(name, run) {
+        Vec::new()
+    } else {
+        vec![name.to_owned()]
+    })
+}
+
+pub fn cmd_list(args: &ListArgs) -> anyhow::Result<()> {
+    let conn = zbus::blocking::Connection::system()?;
+    let manager = ObjectManagerProxy::new(
+        &conn,
+        StorageProxyBlocking::DESTINATION.unwrap(),
+        StorageProxyBlocking::PATH.unwrap(),
+    )?;
+    let mut filesystems: Vec<_> = manager.get_managed_objects()?.into_keys().collect();
+    filesystems.sort_unstable();
+
+    let filter = SubvolumeFilter::from(args);
+    for path in filesystems {
+        let subvols = FilesystemProxyBlocking::new(&conn, path)?.list_subvolumes_filtered(
+            filter.clone(),
+            args.offset,
+            args.limit,
+        )?;
+        for subvol in subvols {
+            let path = subvol.paths.first().unwrap_or(&subvol.root_path);
+            println!("{}", path.as_path().display());
+        }
+    }
+    Ok(())
+}
+
+pub fn cmd_adopt(
+    source: PathBuf,
+    target_dir: PathBuf,
+    snapshots: Vec<PathBuf>,
+) -> anyhow::Result<()> {
+    let conn = zbus::blocking::Connection::system()?;
+    let paths = StorageProxyBlocking::new(&conn)?.adopt_snapshots(
+        snapshots.into_iter().map(Into::into).collect(),
+        source.into(),
+        target_dir.into(),
+    )?;
+    for path in paths {
+        println!("{}", path.as_path().display());
+    }
+    Ok(())
+}
+
+pub fn cmd_migrate_metadata(remove_old: bool) -> anyhow::Result<()> {
+    let conn = zbus::blocking::Connection::system()?;
+    let count = StorageProxyBlocking::new(&conn)?.migrate_metadata(remove_old)?;
+    println!("migrated {} snapshots", count);
+    Ok(())
+}
+
+pub fn cmd_import(tool: String, dir: PathBuf, rule: Option<String>) -> anyhow::Result<()> {
+    let conn = zbus::blocking::Connection::system()?;
+    let paths = StorageProxyBlocking::new(&conn)?.import_snapshots(
+        tool,
+        dir.into(),
+        rule.unwrap_or_default(),
+    )?;
+    for path in paths {
+        println!("{}", path.as_path().display());
     }
+    Ok(())
 }
@@ -13,7 +13,7 @@ mod imp {
         pub path: OnceCell<OwnedObjectPath>,
         #[property(get)]
         pub uuid: RefCell<String>,
-        #[property(get)]
+        #[property(get, set)]
         pub label: RefCell<String>,
         #[property(get)]
         pub devices: RefCell<Vec<String>>,
@@ -1,13 +1,13 @@
-use std::cell::RefMut;
+use std::cell::{Ref, RefMut};
 
-use butterd::RuleConfig;
+use butterd::{RuleConfig, RuleRun};
 use gtk::subclass::prelude::*;
 use zbus::zvariant::OwnedObjectPath;
 
 mod imp {
     use std::cell::{Cell, OnceCell, RefCell};
 
-    use butterd::RuleConfig;
+    use butterd::{RuleConfig, RuleRun};
     use gtk::{glib, prelude::*, subclass::prelude::*};
     use zbus::zvariant::OwnedObjectPath;
 
@@ -20,6 +20,7 @@ mod imp {
         #[property(get, set)]
         pub is_enabled: Cell<bool>,
         pub config: RefCell<RuleConfig>,
+        pub last_run: RefCell<RuleRun>,
     }
 
     #[glib::object_subclass]
@@ -56,18 +57,29 @@ impl Rule {
     }
 
     pub fn deep_clone(&self) -> Self {
-        Self::new(
+        let ret = Self::new(
             self.object_path().clone(),
             self.name(),
             self.is_enabled(),
             self.config().clone(),
-        )
+        );
+        ret.set_last_run(self.last_run().clone());
+        ret
     }
 
     pub fn config(&self) -> RefMut<'_, RuleConfig> {
         self.imp().config.borrow_mut()
     }
 
+    /// The latest run, with a start time of 0 if the rule never ran
+    pub fn last_run(&self) -> Ref<'_, RuleRun> {
+        self.imp().last_run.borrow()
+    }
+
+    pub fn set_last_run(&self, run: RuleRun) {
+        self.imp().last_run.replace(run);
+    }
+
     pub fn object_path(&self) -> &OwnedObjectPath {
         &self.imp().path.get().unwrap()
     }
@@ -47,6 +47,12 @@ mod imp {
                     glib::ParamSpecString::builder(Attribute::UUID)
                         .read_only()
                         .build(),
+                    glib::ParamSpecUInt64::builder(Attribute::GENERATION)
+                        .read_only()
+                        .build(),
+                    glib::ParamSpecString::builder(Attribute::RECEIVED_UUID)
+                        .read_only()
+                        .build(),
                 ]
             });
             PROPERTIES.as_ref()
@@ -60,6 +66,8 @@ mod imp {
                 Attribute::PARENT_PATH => obj.attribute_str(Attribute::ParentPath).to_value(),
                 Attribute::CREATED => obj.created().to_value(),
                 Attribute::UUID => obj.attribute_str(Attribute::Uuid).to_value(),
+                Attribute::GENERATION => obj.generation().to_value(),
+                Attribute::RECEIVED_UUID => obj.attribute_str(Attribute::ReceivedUuid).to_value(),
                 _ => unimplemented!(),
             }
         }
@@ -85,7 +93,16 @@ impl Subvolume {
         self.data().uuid.into()
     }
 
+    /// UUID of the snapshot group this was taken in
+    pub fn group_uuid(&self) -> Option<Uuid> {
+        self.data().group_uuid.map(Into::into)
+    }
+
+    /// File name, or the number of a snapshot in snapper's layout
     pub fn name(&self) -> Cow<str> {
+        if let Some(number) = butterd::snapper_number(self.subvol_path()) {
+            return number.to_string().into();
+        }
         self.subvol_path().file_name().unwrap().to_string_lossy()
     }
 
@@ -102,10 +119,19 @@ impl Subvolume {
         self.data().is_likely_primary()
     }
 
+    /// Snapshot whose source subvolume no longer exists
+    pub fn is_orphaned(&self) -> bool {
+        self.data().is_orphaned()
+    }
+
     pub fn created(&self) -> glib::DateTime {
         glib::DateTime::from_unix_local(self.data().created_unix_secs).unwrap()
     }
 
+    pub fn generation(&self) -> u64 {
+        self.data().generation
+    }
+
     pub fn attribute_str(&self, attribute: Attribute) -> String {
         match attribute {
             Attribute::Name => self.name().to_string(),
@@ -117,6 +143,11 @@ impl Subvolume {
                 .map_or(String::new(), |p| p.as_path().to_string_lossy().into()),
             Attribute::Created => self.created().format("%c").unwrap().into(),
             Attribute::Uuid => self.uuid().to_string(),
+            Attribute::Generation => self.generation().to_string(),
+            Attribute::ReceivedUuid => self
+                .data()
+                .received_uuid
+                .map_or(String::new(), |uuid| Uuid::from(uuid).to_string()),
         }
     }
 }
@@ -13,6 +13,10 @@ pub enum Attribute {
     /// Creation time
     Created,
     Uuid,
+    /// Transaction of the last change to the content
+    Generation,
+    /// UUID of the subvolume this was received from
+    ReceivedUuid,
 }
 
 impl Attribute {
@@ -21,6 +25,8 @@ impl Attribute {
     pub const PARENT_PATH: &'static str = "parent-path";
     pub const CREATED: &'static str = "created";
     pub const UUID: &'static str = "uuid";
+    pub const GENERATION: &'static str = "generation";
+    pub const RECEIVED_UUID: &'static str = "received-uuid";
 
     pub fn as_str(&self) -> &'static str {
         match self {
@@ -29,12 +35,20 @@ impl Attribute {
             Self::ParentPath => Self::PARENT_PATH,
             Self::Created => Self::CREATED,
             Self::Uuid => Self::UUID,
+            Self::Generation => Self::GENERATION,
+            Self::ReceivedUuid => Self::RECEIVED_UUID,
         }
     }
 
     pub fn sorter(&self) -> gtk::Sorter {
         match self {
             Attribute::Created => GSubvolumeCreatedSorter::new().upcast(),
+            Attribute::Generation => gtk::NumericSorter::new(Some(&gtk::PropertyExpression::new(
+                Subvolume::static_type(),
+                None::<&gtk::Expression>,
+                self.as_str(),
+            )))
+            .upcast(),
             _ => gtk::StringSorter::new(Some(&gtk::PropertyExpression::new(
                 Subvolume::static_type(),
                 None::<&gtk::Expression>,
@@ -1,6 +1,6 @@
 use gtk::{gio, glib, prelude::*, subclass::prelude::*};
 use indexmap::IndexMap;
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::HashMap};
 use uuid::Uuid;
 
 use super::Subvolume;
@@ -12,6 +12,8 @@ mod imp {
     #[derive(Default)]
     pub struct SubvolList {
         pub subvols: RefCell<IndexMap<Uuid, Subvolume>>,
+        /// UUIDs of the members of each snapshot group
+        pub groups: RefCell<HashMap<Uuid, Vec<Uuid>>>,
     }
 
     #[glib::object_subclass]
@@ -55,7 +57,44 @@ impl SubvolList {
         subvols.get(id).and_then(|subvol| Some(subvol.clone()))
     }
 
+    /// Snapshots of `group`, ordered by path
+    pub fn group_members(&self, group: &Uuid) -> Vec<Subvolume> {
+        let groups = self.imp().groups.borrow();
+        let mut ret: Vec<Subvolume> = groups
+            .get(group)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| self.by_id(id))
+            .collect();
+        ret.sort_by(|a, b| a.subvol_path().cmp(b.subvol_path()));
+        ret
+    }
+
+    /// `true` if `subvol` stands for its whole group, or is not in any
+    pub fn is_group_leader(&self, subvol: &Subvolume) -> bool {
+        let Some(group) = subvol.group_uuid() else {
+            return true;
+        };
+        self.group_members(&group)
+            .first()
+            .map_or(true, |leader| leader.uuid() == subvol.uuid())
+    }
+
+    fn leave_group(&self, subvol: &Subvolume) {
+        let Some(group) = subvol.group_uuid() else {
+            return;
+        };
+        let mut groups = self.imp().groups.borrow_mut();
+        if let Some(members) = groups.get_mut(&group) {
+            members.retain(|id| *id != subvol.uuid());
+            if members.is_empty() {
+                groups.remove(&group);
+            }
+        }
+    }
+
     pub fn clear(&self) {
+        self.imp().groups.borrow_mut().clear();
         let mut subvols = self.imp().subvols.borrow_mut();
         let removed = subvols.len();
         subvols.clear();
@@ -65,6 +104,17 @@ impl SubvolList {
     }
 
     pub fn insert(&self, subvol: Subvolume) -> Option<Subvolume> {
+        if let Some(prev) = self.by_id(&subvol.uuid()) {
+            self.leave_group(&prev);
+        }
+        if let Some(group) = subvol.group_uuid() {
+            self.imp()
+                .groups
+                .borrow_mut()
+                .entry(group)
+                .or_default()
+                .push(subvol.uuid());
+        }
         let mut subvols = self.imp().subvols.borrow_mut();
         let (idx, ret) = subvols.insert_full(subvol.uuid(), subvol);
         drop(subvols);
@@ -73,6 +123,17 @@ impl SubvolList {
 
         ret
     }
+
+    pub fn remove(&self, id: &Uuid) -> Option<Subvolume> {
+        let mut subvols = self.imp().subvols.borrow_mut();
+        let (idx, _, ret) = subvols.shift_remove_full(id)?;
+        drop(subvols);
+        self.leave_group(&ret);
+
+        self.items_changed(idx as u32, 1, 0);
+
+        Some(ret)
+    }
 }
 
 impl Default for SubvolList {
@@ -1,8 +1,14 @@
-use butterd::{create_snapshot, RuleConfig, RuleSubvolumeConfig, SnapshotMetadata};
+use butterd::{
+    create_snapper_snapshot, create_snapshot, expand_name_template, hostname,
+    preview_prune as plan_prune, prune_rule, should_prune, LibBtrfsutil, NameContext, RuleConfig,
+    RuleRun, RuleSubvolumeConfig, SnapshotGroup, SnapshotLayout,
+};
 
-use std::{cmp, fs, io, os::unix::prelude::OsStrExt, path::PathBuf};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
 
-use chrono::{DateTime, Datelike, NaiveDateTime, Timelike, Utc};
 use log;
 
 mod name {
@@ -64,53 +70,123 @@ mod name {
     }
 }
 
-fn should_prune(c: &RuleConfig) -> bool {
-    c.keep_hourly != 0
-        || c.keep_daily != 0
-        || c.keep_weekly != 0
-        || c.keep_monthly != 0
-        || c.keep_yearly != 0
-}
-
-pub fn snapshot(c: &RuleConfig) {
+/// Snapshot every subvolume of the rule `rule` as one group, under the same
+/// name unless they have name templates
+pub fn snapshot(rule: &str, c: &RuleConfig, run: &mut RuleRun) {
+    let group = SnapshotGroup::new();
+    let mut name = name::RandomName::new();
+    let hostname = hostname();
+    let now = NameContext::now(rule, Path::new(""), &hostname);
     for subvol in &c.subvolumes {
         log::info!(
             "creating a snapshot from '{}' in '{}'",
             subvol.path.display(),
             subvol.target_dir.display()
         );
-        let ret = snapshot_subvol(subvol);
-        if let Err(e) = ret {
-            log::error!(
-                "failed to create a snapshot from '{}': {}",
-                subvol.path.display(),
-                e
-            );
+        let ctx = NameContext {
+            source: &subvol.path,
+            ..now.clone()
+        };
+        match snapshot_subvol(subvol, &group, &ctx, &mut name) {
+            Ok(path) => run.created.push(path),
+            Err(e) => {
+                let msg = format!(
+                    "failed to create a snapshot from '{}': {}",
+                    subvol.path.display(),
+                    e
+                );
+                log::error!("{}", msg);
+                run.errors.push(msg);
+            }
         }
     }
 }
-pub fn prune(c: &RuleConfig) {
+
+/// Prune `c`, returning whether it has a retention policy at all
+pub fn prune(c: &RuleConfig, run: &mut RuleRun) -> bool {
     if !should_prune(c) {
-        return;
+        return false;
     }
 
-    for subvol in &c.subvolumes {
-        let res = prune_subvol(subvol, c);
-        if let Err(err) = res {
-            eprintln!("failed to prune '{}': {}", subvol.target_dir.display(), err);
+    let out = prune_rule(&LibBtrfsutil, c);
+    for path in &out.pruned {
+        log::info!("deleted '{}'", path.display());
+    }
+    for err in &out.errors {
+        log::error!("{}", err);
+    }
+    if !out.pruned_for_space.is_empty() {
+        log::info!(
+            "deleted {} snapshots to meet the space limits",
+            out.pruned_for_space.len()
+        );
+    }
+    run.pruned.extend(out.pruned);
+    run.errors.extend(out.errors);
+    true
+}
+
+/// Print what pruning the rule `name` would do
+pub fn preview_prune(name: &str, c: &RuleConfig) -> anyhow::Result<()> {
+    if !should_prune(c) {
+        println!("{}: no retention policy, nothing to prune", name);
+        return Ok(());
+    }
+    println!("{}:", name);
+    for decision in plan_prune(&LibBtrfsutil, c)? {
+        let kept_by: Vec<&str> = decision.kept_by.iter().map(|r| r.as_str()).collect();
+        if decision.is_kept() {
+            println!(
+                "  keep   {} ({})",
+                decision.path.display(),
+                kept_by.join(", ")
+            );
+        } else if decision.for_space {
+            println!("  delete {} (space limits)", decision.path.display());
+        } else {
+            println!("  delete {}", decision.path.display());
         }
     }
+    Ok(())
 }
 
-fn snapshot_subvol(c: &RuleSubvolumeConfig) -> anyhow::Result<()> {
-    let mut name = name::RandomName::new();
-    for _ in 0..16 {
-        let target_path = c.target_dir.join(name.as_str());
-        match create_snapshot(&c.path, &target_path, true) {
-            Ok(_) => return Ok(()),
+fn snapshot_subvol(
+    c: &RuleSubvolumeConfig,
+    group: &SnapshotGroup,
+    ctx: &NameContext,
+    name: &mut name::RandomName,
+) -> anyhow::Result<PathBuf> {
+    if c.layout == SnapshotLayout::Snapper {
+        return Ok(create_snapper_snapshot(
+            &LibBtrfsutil,
+            &c.path,
+            &c.target_dir,
+            c.recursive,
+            Some(group),
+        )?);
+    }
+    for counter in 1..=16 {
+        let target_path = if c.name_template.is_empty() {
+            c.target_dir.join(name.as_str())
+        } else {
+            c.target_dir.join(
+                expand_name_template(&c.name_template, ctx, counter).map_err(anyhow::Error::msg)?,
+            )
+        };
+        match create_snapshot(
+            &LibBtrfsutil,
+            &c.path,
+            &target_path,
+            true,
+            c.recursive,
+            Some(group),
+        ) {
+            Ok(_) => return Ok(target_path),
             Err(e) => {
                 if e.kind() == io::ErrorKind::AlreadyExists {
-                    name.inc_len();
+                    if c.name_template.is_empty() {
+                        name.inc_len();
+                    }
                     continue;
                 } else {
                     return Err(e.into());
@@ -120,96 +196,3 @@ fn snapshot_subvol(c: &RuleSubvolumeConfig) -> anyhow::Result<()> {
     }
     Err(anyhow::anyhow!("name exhausted"))
 }
-
-fn prune_subvol(subvol_cfg: &RuleSubvolumeConfig, rule_cfg: &RuleConfig) -> anyhow::Result<()> {
-    #[derive(Debug, PartialEq, Eq)]
-    struct Snapshot {
-        created: NaiveDateTime,
-        path: PathBuf,
-    }
-    struct Bucket {
-        keep: u32,
-        last: i32,
-        algo: fn(&NaiveDateTime) -> i32,
-    }
-
-    let source_subvol_path = libbtrfsutil::subvolume_path(&subvol_cfg.path)?;
-
-    let mut snapshots: Vec<Snapshot> = fs::read_dir(&subvol_cfg.target_dir)?
-        .filter_map(|entry| {
-            let entry = entry.ok()?;
-            if entry.file_name().as_bytes()[0] == b'.' {
-                return None;
-            }
-            let path = entry.path();
-            let info = libbtrfsutil::subvolume_info(&path).ok()?;
-            if let Some(metadata) = SnapshotMetadata::read(&path) {
-                if metadata.created_from == source_subvol_path {
-                    return Some(Snapshot {
-                        path,
-                        created: DateTime::<Utc>::from(info.created()).naive_local(),
-                    });
-                }
-            }
-            return None;
-        })
-        .collect();
-    snapshots.sort_by_key(|e| cmp::Reverse(e.created));
-
-    let mut buckets = [
-        Bucket {
-            keep: rule_cfg.keep_hourly,
-            last: 0,
-            algo: |dt| dt.year() * 100000 + dt.ordinal() as i32 * 100 + dt.hour() as i32,
-        },
-        Bucket {
-            keep: rule_cfg.keep_daily,
-            last: 0,
-            algo: |dt| dt.year() * 1000 + dt.ordinal() as i32,
-        },
-        Bucket {
-            keep: rule_cfg.keep_weekly,
-            last: 0,
-            algo: |dt| {
-                let week = dt.iso_week();
-                week.year() * 100 + week.week() as i32
-            },
-        },
-        Bucket {
-            keep: rule_cfg.keep_monthly,
-            last: 0,
-            algo: |dt| dt.year() * 100 + dt.month() as i32,
-        },
-        Bucket {
-            keep: rule_cfg.keep_yearly,
-            last: 0,
-            algo: |dt| dt.year(),
-        },
-    ];
-
-    for snapshot in snapshots {
-        let mut should_remove = true;
-        for bucket in &mut buckets {
-            if bucket.keep > 0 {
-                let val = (bucket.algo)(&snapshot.created);
-                if val != bucket.last {
-                    bucket.keep -= 1;
-                    bucket.last = val;
-                    should_remove = false;
-                }
-            }
-        }
-
-        if should_remove {
-            println!("deleting '{}'", snapshot.path.display());
-            let res = libbtrfsutil::DeleteSubvolumeOptions::new()
-                .recursive(true)
-                .delete(&snapshot.path);
-            if let Err(err) = res {
-                eprintln!("failed to delete '{}': {}", snapshot.path.display(), err);
-            }
-        }
-    }
-
-    Ok(())
-}
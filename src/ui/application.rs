@@ -6,7 +6,9 @@ use crate::object::Filesystem;
 use crate::{config, ui::prelude::*};
 
 use super::store::Store;
-use super::widgets::{AppWindow, ScheduleView, SnapshotView};
+use super::widgets::{
+    AppWindow, ScheduleView, SnapshotView, SubvolumeCreationWindow, SubvolumePropertiesWindow,
+};
 
 mod imp {
     use std::cell::OnceCell;
@@ -132,5 +134,23 @@ impl Application {
             about_window.set_visible(true);
         });
         self.add_action(&about_action);
+
+        let properties_action = gio::SimpleAction::new("properties", None);
+        properties_action.connect_activate(glib::clone!(@weak self as app => move |_, _| {
+            let win = SubvolumePropertiesWindow::new(&app.store());
+            win.set_transient_for(app.active_window().as_ref());
+            win.set_modal(true);
+            win.present();
+        }));
+        self.add_action(&properties_action);
+
+        let new_subvolume_action = gio::SimpleAction::new("new-subvolume", None);
+        new_subvolume_action.connect_activate(glib::clone!(@weak self as app => move |_, _| {
+            let win = SubvolumeCreationWindow::new(&app.store());
+            win.set_transient_for(app.active_window().as_ref());
+            win.set_modal(true);
+            win.present();
+        }));
+        self.add_action(&new_subvolume_action);
     }
 }
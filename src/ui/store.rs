@@ -1,19 +1,19 @@
-use std::collections::HashMap;
+use std::path::PathBuf;
 
 use anyhow::Context;
 use gtk::{gio, glib, prelude::*, subclass::prelude::*};
-use uuid::Uuid;
 
 use butterd::{
-    FilesystemProxyBlocking, RuleProxyBlocking, ScheduleProxyBlocking, StorageProxyBlocking,
-    ZPathBuf,
+    DaemonProxyBlocking, FilesystemProxyBlocking, PruneDecision, RuleConfig, RuleProxyBlocking,
+    ScheduleProxyBlocking, StorageProxyBlocking, SubvolumeCreateOptions, SubvolumeFilter,
+    SubvolumeProperties, ZPathBuf,
 };
 use zbus::{blocking::fdo::ObjectManagerProxy, proxy::ProxyDefault, zvariant::OwnedObjectPath};
 
 use crate::object::{list::SubvolList, Filesystem, Rule, Subvolume};
 
 mod imp {
-    use std::cell::{OnceCell, RefCell};
+    use std::cell::{Cell, OnceCell, RefCell};
 
     use butterd::FilesystemProxyBlocking;
     use gtk::{gio, glib, subclass::prelude::*};
@@ -24,6 +24,8 @@ mod imp {
     pub struct Store {
         pub conn: OnceCell<Connection>,
         pub model: SubvolList,
+        /// epoch and generation `model` is up to date with, 0 if empty
+        pub subvol_generation: Cell<(u64, u64)>,
         pub filesystems: gio::ListStore,
         pub cur_fs: RefCell<Option<FilesystemProxyBlocking<'static>>>,
         pub rules: gio::ListStore,
@@ -34,6 +36,7 @@ mod imp {
             Self {
                 conn: Default::default(),
                 model: Default::default(),
+                subvol_generation: Default::default(),
                 filesystems: gio::ListStore::new::<Filesystem>(),
                 cur_fs: Default::default(),
                 rules: gio::ListStore::new::<Rule>(),
@@ -83,6 +86,10 @@ impl Store {
         )?)
     }
 
+    fn daemon(&self) -> anyhow::Result<DaemonProxyBlocking> {
+        Ok(DaemonProxyBlocking::new(&self.imp().conn.get().unwrap())?)
+    }
+
     fn schedule(&self) -> anyhow::Result<ScheduleProxyBlocking> {
         Ok(ScheduleProxyBlocking::new(&self.imp().conn.get().unwrap())?)
     }
@@ -96,27 +103,47 @@ impl Store {
     }
 
     pub fn refresh_subvolumes(&self) -> anyhow::Result<()> {
-        let subvols = self
+        let (epoch, generation) = self.imp().subvol_generation.get();
+        let delta = self
             .filesystem()
             .context("filesystem not selected")?
-            .list_subvolumes()?;
-        let subvols = {
-            let mut map: HashMap<Uuid, Subvolume> = HashMap::with_capacity(subvols.len());
-            for subvol in subvols {
-                map.insert(*subvol.uuid.as_uuid(), Subvolume::new(subvol));
-            }
-            map
-        };
+            .list_subvolumes_since(epoch, generation)?;
 
         let model = self.model();
-        model.clear();
-        for (_, subvol) in subvols {
-            model.insert(subvol);
+        if delta.is_full {
+            model.clear();
+        }
+        for uuid in delta.removed {
+            model.remove(uuid.as_uuid());
+        }
+        for subvol in delta.changed {
+            model.insert(Subvolume::new(subvol));
         }
+        self.imp()
+            .subvol_generation
+            .set((delta.epoch, delta.generation));
 
         Ok(())
     }
 
+    /// Primary subvolumes of the current filesystem, i.e. snapshot sources
+    pub fn primary_subvolumes(&self) -> anyhow::Result<gio::ListStore> {
+        let filter = SubvolumeFilter {
+            is_primary: Some(true),
+            ..Default::default()
+        };
+        let subvols = self
+            .filesystem()
+            .context("filesystem not selected")?
+            .list_subvolumes_filtered(filter, 0, 0)?;
+
+        let ret = gio::ListStore::new::<Subvolume>();
+        for subvol in subvols {
+            ret.append(&Subvolume::new(subvol));
+        }
+        Ok(ret)
+    }
+
     pub fn refresh_filesystems(&self) {
         self.storage().unwrap().refresh().unwrap();
         let filesystems = self
@@ -174,23 +201,33 @@ impl Store {
             &self.imp().conn.get().unwrap(),
             fs.object_path().clone(),
         )?));
+        self.imp().subvol_generation.set((0, 0));
         self.refresh_subvolumes()?;
 
         Ok(())
     }
 
     pub fn delete_snapshots(&self, paths: Vec<ZPathBuf>) -> anyhow::Result<()> {
-        self.storage()?.remove_subvolumes(paths)?;
+        self.storage()?.remove_subvolumes(paths, false)?;
         self.refresh_subvolumes()?;
         Ok(())
     }
 
+    /// Put snapshots back in place of their sources, returning where the
+    /// replaced subvolumes were moved
+    pub fn restore_snapshots(&self, paths: Vec<ZPathBuf>) -> anyhow::Result<Vec<ZPathBuf>> {
+        let ret = self.storage()?.restore_snapshots(paths)?;
+        self.refresh_subvolumes()?;
+        Ok(ret)
+    }
+
     pub fn rename_snapshot(
         &self,
         before_path: ZPathBuf,
         after_path: ZPathBuf,
     ) -> anyhow::Result<()> {
-        self.storage()?.move_subvolume(before_path, after_path)?;
+        self.storage()?
+            .move_subvolume(before_path, after_path, false)?;
         self.refresh_subvolumes()?;
         Ok(())
     }
@@ -200,12 +237,68 @@ impl Store {
         src: ZPathBuf,
         dest: ZPathBuf,
         readonly: bool,
+        recursive: bool,
     ) -> anyhow::Result<()> {
-        self.storage()?.create_snapshot(src, dest, readonly)?;
+        self.storage()?
+            .create_snapshot(src, dest, readonly, recursive)?;
+        self.refresh_subvolumes()?;
+        Ok(())
+    }
+
+    pub fn create_subvolume(
+        &self,
+        path: ZPathBuf,
+        options: SubvolumeCreateOptions,
+    ) -> anyhow::Result<()> {
+        self.storage()?.create_subvolume(path, options)?;
+        self.refresh_subvolumes()?;
+        Ok(())
+    }
+
+    pub fn convert_to_subvolume(&self, path: ZPathBuf) -> anyhow::Result<()> {
+        self.storage()?.convert_to_subvolume(path)?;
         self.refresh_subvolumes()?;
         Ok(())
     }
 
+    pub fn subvolume_properties(&self, path: ZPathBuf) -> anyhow::Result<SubvolumeProperties> {
+        Ok(self.storage()?.subvolume_properties(path)?)
+    }
+
+    pub fn set_subvolume_properties(
+        &self,
+        path: ZPathBuf,
+        properties: SubvolumeProperties,
+    ) -> anyhow::Result<()> {
+        self.storage()?.set_subvolume_properties(path, properties)?;
+        Ok(())
+    }
+
+    /// Change the label of the current filesystem
+    pub fn set_filesystem_label(&self, label: &str) -> anyhow::Result<()> {
+        let proxy = self.filesystem().context("filesystem not selected")?;
+        self.storage()?
+            .set_filesystem_label(proxy.uuid()?, label.to_owned())?;
+        for fs in self.filesystems().iter::<Filesystem>().flatten() {
+            if proxy.inner().path() == &fs.object_path().as_ref() {
+                fs.set_label(label);
+            }
+        }
+        Ok(())
+    }
+
+    /// Label of the current filesystem
+    pub fn filesystem_label(&self) -> anyhow::Result<String> {
+        Ok(self
+            .filesystem()
+            .context("filesystem not selected")?
+            .label()?)
+    }
+
+    pub fn default_snapshot_dir(&self) -> anyhow::Result<PathBuf> {
+        Ok(self.daemon()?.config()?.snapshot_dir)
+    }
+
     pub fn is_schedule_enabled(&self) -> bool {
         self.schedule().unwrap().is_enabled().unwrap()
     }
@@ -230,7 +323,9 @@ impl Store {
             let name = proxy.name()?;
             let is_enabled = proxy.is_enabled()?;
             let config = proxy.config()?;
-            model.append(&Rule::new(path, name, is_enabled, config));
+            let rule = Rule::new(path, name, is_enabled, config);
+            rule.set_last_run(proxy.last_run().unwrap_or_default());
+            model.append(&rule);
         }
         Ok(())
     }
@@ -248,6 +343,25 @@ impl Store {
         Ok(())
     }
 
+    /// What pruning `rule` with `config` would do, without deleting anything
+    pub fn preview_prune(
+        &self,
+        rule: &Rule,
+        config: RuleConfig,
+    ) -> anyhow::Result<Vec<PruneDecision>> {
+        let proxy =
+            RuleProxyBlocking::new(&self.imp().conn.get().unwrap(), rule.object_path().clone())?;
+        Ok(proxy.preview_prune(Some(config))?)
+    }
+
+    /// Snapshot `rule` now in the background
+    pub fn run_rule_snapshot(&self, rule: &Rule) -> anyhow::Result<()> {
+        let proxy =
+            RuleProxyBlocking::new(&self.imp().conn.get().unwrap(), rule.object_path().clone())?;
+        proxy.run_snapshot()?;
+        Ok(())
+    }
+
     pub fn update_rule(&self, prev: &Rule, next: &Rule) -> anyhow::Result<()> {
         let path = if prev.name() != next.name() {
             self.schedule()?.move_rule(prev.name(), next.name())?
@@ -18,3 +18,7 @@ mod snapshot_creation_window;
 pub use snapshot_creation_window::SnapshotCreationWindow;
 mod snapshot_view;
 pub use snapshot_view::SnapshotView;
+mod subvolume_properties_window;
+pub use subvolume_properties_window::SubvolumePropertiesWindow;
+mod subvolume_creation_window;
+pub use subvolume_creation_window::SubvolumeCreationWindow;
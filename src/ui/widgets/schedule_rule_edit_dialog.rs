@@ -1,4 +1,5 @@
-use butterd::RuleSubvolumeConfig;
+use butterd::{RetentionDuration, RuleConfig, RuleSubvolumeConfig, SnapshotLayout};
+use gettext::gettext;
 use gtk::glib;
 use std::path::PathBuf;
 
@@ -35,6 +36,12 @@ mod imp {
         #[template_child]
         pub name_entry: TemplateChild<gtk::Entry>,
         #[template_child]
+        pub schedule_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub last_cell: TemplateChild<gtk::Adjustment>,
+        #[template_child]
+        pub minutely_cell: TemplateChild<gtk::Adjustment>,
+        #[template_child]
         pub hourly_cell: TemplateChild<gtk::Adjustment>,
         #[template_child]
         pub daily_cell: TemplateChild<gtk::Adjustment>,
@@ -45,6 +52,24 @@ mod imp {
         #[template_child]
         pub yearly_cell: TemplateChild<gtk::Adjustment>,
         #[template_child]
+        pub within_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_minutely_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_hourly_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_daily_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_weekly_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_monthly_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub within_yearly_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub orphaned_cell: TemplateChild<gtk::Adjustment>,
+        #[template_child]
+        pub run_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
         pub remove_group: TemplateChild<adw::PreferencesGroup>,
         #[template_child]
         pub stack: TemplateChild<gtk::Stack>,
@@ -58,6 +83,14 @@ mod imp {
         pub subvol_path_entry: TemplateChild<FileChooserEntry>,
         #[template_child]
         pub target_dir_entry: TemplateChild<FileChooserEntry>,
+        #[template_child]
+        pub name_template_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub recursive_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub snapper_layout_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub exclude_path_entry: TemplateChild<FileChooserEntry>,
         pub store: OnceCell<Store>,
         pub original: OnceCell<Rule>,
         pub rule: RefCell<Rule>,
@@ -67,6 +100,19 @@ mod imp {
         pub fn is_new(&self) -> bool {
             self.original.get().is_none()
         }
+
+        /// Entries of the retention durations
+        pub fn within_entries(&self) -> [&gtk::Entry; 7] {
+            [
+                &self.within_entry,
+                &self.within_minutely_entry,
+                &self.within_hourly_entry,
+                &self.within_daily_entry,
+                &self.within_weekly_entry,
+                &self.within_monthly_entry,
+                &self.within_yearly_entry,
+            ]
+        }
     }
 
     #[glib::object_subclass]
@@ -111,12 +157,31 @@ mod imp {
                         self.original.set(rule).unwrap();
                         let rule = self.rule.borrow();
                         self.name_entry.set_text(&rule.name());
+                        self.schedule_entry.set_text(&rule.config().schedule);
+                        self.last_cell.set_value(rule.config().keep_last as f64);
+                        self.minutely_cell
+                            .set_value(rule.config().keep_minutely as f64);
                         self.hourly_cell.set_value(rule.config().keep_hourly as f64);
                         self.daily_cell.set_value(rule.config().keep_daily as f64);
                         self.weekly_cell.set_value(rule.config().keep_weekly as f64);
                         self.monthly_cell
                             .set_value(rule.config().keep_monthly as f64);
                         self.yearly_cell.set_value(rule.config().keep_yearly as f64);
+                        self.orphaned_cell
+                            .set_value(rule.config().keep_orphaned_days as f64);
+                        let config = rule.config();
+                        self.within_entry.set_text(&config.keep_within);
+                        self.within_minutely_entry
+                            .set_text(&config.keep_within_minutely);
+                        self.within_hourly_entry
+                            .set_text(&config.keep_within_hourly);
+                        self.within_daily_entry.set_text(&config.keep_within_daily);
+                        self.within_weekly_entry
+                            .set_text(&config.keep_within_weekly);
+                        self.within_monthly_entry
+                            .set_text(&config.keep_within_monthly);
+                        self.within_yearly_entry
+                            .set_text(&config.keep_within_yearly);
                     } else {
                         self.hourly_cell.set_value(24.0);
                         self.daily_cell.set_value(30.0);
@@ -133,6 +198,7 @@ mod imp {
             if self.is_new() {
                 self.save_button.set_label(&gettext("Create"));
                 obj.set_title(Some(&gettext("New Rule")));
+                self.run_group.set_visible(false);
                 self.remove_group.set_visible(false);
             } else {
                 self.save_button.set_label(&gettext("Apply"));
@@ -198,6 +264,16 @@ impl ScheduleRuleEditDialog {
         if name.contains('/') {
             return Some("Name cannot contain '/'.");
         }
+        let schedule = imp.schedule_entry.text();
+        if !schedule.trim().is_empty() && butterd::validate_calendar(&schedule).is_err() {
+            return Some("Schedule is invalid.");
+        }
+        for entry in imp.within_entries() {
+            let within = entry.text();
+            if !within.trim().is_empty() && within.parse::<RetentionDuration>().is_err() {
+                return Some("Retention duration is invalid.");
+            }
+        }
         return None;
     }
 
@@ -208,19 +284,109 @@ impl ScheduleRuleEditDialog {
             return;
         }
         let imp = self.imp();
-        let store = imp.store.get().unwrap();
 
         imp.rule.borrow().set_name(imp.name_entry.text());
         {
             let new_rule = imp.rule.borrow();
             let mut config = new_rule.config();
+            config.schedule = imp.schedule_entry.text().trim().to_owned();
+            config.keep_last = imp.last_cell.value() as u32;
+            config.keep_minutely = imp.minutely_cell.value() as u32;
             config.keep_hourly = imp.hourly_cell.value() as u32;
             config.keep_daily = imp.daily_cell.value() as u32;
             config.keep_weekly = imp.weekly_cell.value() as u32;
             config.keep_monthly = imp.monthly_cell.value() as u32;
             config.keep_yearly = imp.yearly_cell.value() as u32;
+            config.keep_orphaned_days = imp.orphaned_cell.value() as u32;
+            let [within, minutely, hourly, daily, weekly, monthly, yearly] =
+                imp.within_entries().map(|e| e.text().trim().to_owned());
+            config.keep_within = within;
+            config.keep_within_minutely = minutely;
+            config.keep_within_hourly = hourly;
+            config.keep_within_daily = daily;
+            config.keep_within_weekly = weekly;
+            config.keep_within_monthly = monthly;
+            config.keep_within_yearly = yearly;
         }
 
+        match self.deleted_by_new_retention() {
+            Ok(to_delete) if to_delete.is_empty() => self.save(),
+            res => self.confirm_retention(res),
+        }
+    }
+
+    /// Snapshots the changed retention settings would delete on the next
+    /// prune. Empty for new rules or unchanged settings.
+    fn deleted_by_new_retention(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let imp = self.imp();
+        let Some(original) = imp.original.get() else {
+            return Ok(Vec::new());
+        };
+        let config = imp.rule.borrow().config().clone();
+        // only the retention policy
+        let retention = |c: &RuleConfig| RuleConfig {
+            is_enabled: false,
+            schedule: String::new(),
+            subvolumes: Vec::new(),
+            ..c.clone()
+        };
+        if retention(&*original.config()) == retention(&config) {
+            return Ok(Vec::new());
+        }
+        let decisions = imp.store.get().unwrap().preview_prune(original, config)?;
+        Ok(decisions
+            .into_iter()
+            .filter(|d| !d.is_kept())
+            .map(|d| d.path)
+            .collect())
+    }
+
+    /// Ask before saving retention settings that delete `to_delete`, or
+    /// whose effect could not be previewed
+    fn confirm_retention(&self, to_delete: anyhow::Result<Vec<PathBuf>>) {
+        const MAX_LISTED: usize = 10;
+        let to_delete = match to_delete {
+            Ok(to_delete) => to_delete,
+            Err(err) => {
+                let body = gettext(
+                    "Failed to preview which snapshots will be deleted the next time the rule is pruned: {}",
+                )
+                .replace("{}", &err.to_string());
+                self.present_confirmation(&gettext("Apply Retention Settings?"), &body);
+                return;
+            }
+        };
+        let mut body = gettext("These snapshots will be deleted the next time the rule is pruned:");
+        for path in to_delete.iter().take(MAX_LISTED) {
+            body.push('\n');
+            body.push_str(&path.to_string_lossy());
+        }
+        if to_delete.len() > MAX_LISTED {
+            body.push('\n');
+            body.push_str(
+                &gettext("and {} more").replace("{}", &(to_delete.len() - MAX_LISTED).to_string()),
+            );
+        }
+        self.present_confirmation(&gettext("Delete Snapshots?"), &body);
+    }
+
+    /// Save once the user applies the dialog with `heading` and `body`
+    fn present_confirmation(&self, heading: &str, body: &str) {
+        let dialog = adw::MessageDialog::new(Some(self), Some(heading), Some(body));
+        dialog.add_responses(&[("cancel", &gettext("Cancel")), ("apply", &gettext("Apply"))]);
+        dialog.set_response_appearance("apply", adw::ResponseAppearance::Destructive);
+        dialog.connect_response(
+            Some("apply"),
+            glib::clone!(@weak self as obj => move |_, _| {
+                obj.save();
+            }),
+        );
+        dialog.present();
+    }
+
+    fn save(&self) {
+        let imp = self.imp();
+        let store = imp.store.get().unwrap();
         let res = if let Some(original) = imp.original.get() {
             store.update_rule(original, &imp.rule.borrow())
         } else {
@@ -232,6 +398,18 @@ impl ScheduleRuleEditDialog {
         self.close();
     }
 
+    #[template_callback]
+    fn on_run_button_clicked(&self) {
+        let imp = self.imp();
+        let Some(rule) = imp.original.get() else {
+            return;
+        };
+        match imp.store.get().unwrap().run_rule_snapshot(rule) {
+            Ok(_) => self.alert(&gettext("Taking snapshots in the background.")),
+            Err(e) => self.alert(&e.to_string()),
+        }
+    }
+
     #[template_callback]
     fn on_remove_button_clicked(&self) {
         let imp = self.imp();
@@ -260,6 +438,13 @@ impl ScheduleRuleEditDialog {
     #[template_callback]
     fn on_add_subvolume_clicked(&self) {
         let imp = self.imp();
+        let name_template = imp.name_template_entry.text().trim().to_owned();
+        if !name_template.is_empty() {
+            if let Err(err) = butterd::validate_name_template(&name_template) {
+                self.alert(&err);
+                return;
+            }
+        }
         if imp.subvol_path_entry.text().len() > 0 && imp.target_dir_entry.text().len() > 0 {
             imp.rule
                 .borrow()
@@ -268,11 +453,35 @@ impl ScheduleRuleEditDialog {
                 .push(RuleSubvolumeConfig {
                     path: imp.subvol_path_entry.text().to_string().into(),
                     target_dir: imp.target_dir_entry.text().to_string().into(),
+                    recursive: imp.recursive_row.is_active(),
+                    layout: if imp.snapper_layout_row.is_active() {
+                        SnapshotLayout::Snapper
+                    } else {
+                        SnapshotLayout::Flat
+                    },
+                    name_template,
                 });
             self.reload_subvolume_list();
             imp.subvol_path_entry.set_text("");
             imp.target_dir_entry.set_text("");
+            imp.name_template_entry.set_text("");
+            imp.recursive_row.set_active(false);
+            imp.snapper_layout_row.set_active(false);
             imp.add_subvolume_row.set_expanded(false);
         }
     }
+
+    #[template_callback]
+    fn on_exclude_path_clicked(&self) {
+        let imp = self.imp();
+        let path = imp.exclude_path_entry.text();
+        if path.is_empty() {
+            return;
+        }
+        let store = imp.store.get().unwrap();
+        match store.convert_to_subvolume(PathBuf::from(path.as_str()).into()) {
+            Ok(_) => imp.exclude_path_entry.set_text(""),
+            Err(e) => self.alert(&e.to_string()),
+        }
+    }
 }
@@ -1,5 +1,6 @@
-use gtk::glib;
+use gettext::gettext;
 use gtk::subclass::prelude::*;
+use gtk::{glib, prelude::*};
 
 use crate::object::Rule;
 
@@ -20,6 +21,8 @@ mod imp {
     #[template(resource = "/org/zhangyuannie/butter/ui/schedule_rule_row.ui")]
     #[properties(wrapper_type = super::ScheduleRuleRow)]
     pub struct ScheduleRuleRow {
+        #[template_child]
+        pub status_icon: TemplateChild<gtk::Image>,
         #[template_child]
         pub switch: TemplateChild<gtk::Switch>,
         #[property(get, set, construct_only)]
@@ -59,6 +62,7 @@ mod imp {
             let obj = self.obj();
             obj.set_title(obj.rule().name().as_ref());
             self.switch.set_active(obj.rule().is_enabled());
+            obj.update_status();
         }
     }
     impl WidgetImpl for ScheduleRuleRow {}
@@ -81,4 +85,34 @@ impl ScheduleRuleRow {
     pub fn switch(&self) -> &gtk::Switch {
         &self.imp().switch
     }
+
+    /// Show whether the latest run of the rule succeeded
+    fn update_status(&self) {
+        let icon = &self.imp().status_icon;
+        let rule = self.rule();
+        let run = rule.last_run();
+        if run.started_unix_secs == 0 {
+            icon.set_visible(false);
+            return;
+        }
+        let finished = glib::DateTime::from_unix_local(run.finished_unix_secs)
+            .and_then(|dt| dt.format("%c"))
+            .map(String::from)
+            .unwrap_or_default();
+        if run.is_ok() {
+            icon.set_icon_name(Some("emblem-ok-symbolic"));
+            icon.set_tooltip_text(Some(
+                &gettext("Last run succeeded on {}").replace("{}", &finished),
+            ));
+        } else {
+            icon.set_icon_name(Some("dialog-warning-symbolic"));
+            icon.add_css_class("warning");
+            icon.set_tooltip_text(Some(&format!(
+                "{}\n{}",
+                gettext("Last run failed on {}").replace("{}", &finished),
+                run.errors.join("\n")
+            )));
+        }
+        icon.set_visible(true);
+    }
 }
@@ -1,7 +1,9 @@
 use std::path::PathBuf;
 
+use butterd::{expand_name_template, hostname, NameContext};
+use gettext::gettext;
 use gtk::subclass::prelude::*;
-use gtk::{glib, prelude::*, CompositeTemplate};
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
 
 use super::FileChooserEntry;
 use crate::object::Subvolume;
@@ -27,11 +29,15 @@ mod imp {
         #[template_child]
         pub name_entry: TemplateChild<gtk::Entry>,
         #[template_child]
+        pub name_preview_label: TemplateChild<gtk::Label>,
+        #[template_child]
         pub location_entry: TemplateChild<FileChooserEntry>,
         #[template_child]
         pub subvol_dropdown: TemplateChild<gtk::DropDown>,
         #[template_child]
         pub readonly_switch: TemplateChild<gtk::Switch>,
+        #[template_child]
+        pub recursive_switch: TemplateChild<gtk::Switch>,
 
         pub store: OnceCell<WeakRef<Store>>,
     }
@@ -59,16 +65,31 @@ mod imp {
             self.name_entry
                 .connect_text_notify(glib::clone!(@weak obj => move |entry| {
                     obj.create_button().set_sensitive(entry.text_length() > 0);
+                    obj.update_name_preview();
                 }));
             obj.setup_dropdown();
-            self.location_entry.set_text("/var/snapshots");
+            self.subvol_dropdown
+                .connect_selected_notify(glib::clone!(@weak obj => move |_| {
+                    obj.update_name_preview();
+                }));
+            let snapshot_dir = obj
+                .store()
+                .default_snapshot_dir()
+                .unwrap_or_else(|_| "/var/snapshots".into());
+            self.location_entry
+                .set_text(&snapshot_dir.to_string_lossy());
             self.create_button.connect_clicked(glib::clone!(@weak obj => move |_| {
                 let imp = obj.imp();
                 let item = imp.subvol_dropdown.selected_item().unwrap().downcast::<Subvolume>().unwrap();
+                let target_path = match obj.target_path() {
+                    Ok(path) => path,
+                    Err(error) => return obj.alert(&error),
+                };
                 let res = obj.store().create_snapshot(
                     item.mount_path().unwrap().to_owned().into(),
-                    obj.target_path().into(),
+                    target_path.into(),
                     imp.readonly_switch.is_active(),
+                    imp.recursive_switch.is_active(),
                 );
 
                 match res {
@@ -116,11 +137,10 @@ impl SnapshotCreationWindow {
 
     fn setup_dropdown(&self) {
         let imp = self.imp();
-        let filter = gtk::CustomFilter::new(|obj| {
-            let subvol = obj.downcast_ref::<Subvolume>().unwrap();
-            subvol.is_protected()
-        });
-        let model = gtk::FilterListModel::new(Some(self.store().model()), Some(filter));
+        let model = self
+            .store()
+            .primary_subvolumes()
+            .unwrap_or_else(|_| gio::ListStore::new::<Subvolume>());
 
         let exp = gtk::ClosureExpression::new::<String>(
             &[] as &[gtk::Expression],
@@ -138,11 +158,51 @@ impl SnapshotCreationWindow {
         imp.subvol_dropdown.set_model(Some(&model));
     }
 
-    fn target_path(&self) -> PathBuf {
+    /// Path of the new snapshot. A name with placeholders is expanded as a
+    /// name template, counting up until the path is free.
+    fn target_path(&self) -> Result<PathBuf, String> {
         let imp = self.imp();
-        let mut ret = PathBuf::from(imp.location_entry.text().to_string());
-        ret.push(imp.name_entry.text().to_string());
-        ret
+        let location = PathBuf::from(imp.location_entry.text().to_string());
+        let name = imp.name_entry.text();
+        if !name.contains(['{', '}']) {
+            return Ok(location.join(name.as_str()));
+        }
+
+        let source = imp
+            .subvol_dropdown
+            .selected_item()
+            .and_downcast::<Subvolume>()
+            .and_then(|sv| sv.mount_path().map(ToOwned::to_owned))
+            .unwrap_or_default();
+        let hostname = hostname();
+        let ctx = NameContext::now("", &source, &hostname);
+        let mut ret = location.join(expand_name_template(&name, &ctx, 1)?);
+        for counter in 2..=16 {
+            if !ret.exists() {
+                break;
+            }
+            ret = location.join(expand_name_template(&name, &ctx, counter)?);
+        }
+        Ok(ret)
+    }
+
+    /// Show what the placeholders in the name expand to
+    fn update_name_preview(&self) {
+        let imp = self.imp();
+        let name = imp.name_entry.text();
+        if !name.contains(['{', '}']) {
+            imp.name_preview_label.set_visible(false);
+            return;
+        }
+        let text = match self.target_path() {
+            Ok(path) => gettext("Named “{}”").replace(
+                "{}",
+                &path.file_name().unwrap_or_default().to_string_lossy(),
+            ),
+            Err(error) => error,
+        };
+        imp.name_preview_label.set_text(&text);
+        imp.name_preview_label.set_visible(true);
     }
 
     fn store(&self) -> Store {
@@ -1,5 +1,6 @@
 use adw::subclass::prelude::*;
 use butterd::ZPathBuf;
+use gettext::gettext;
 use gtk::{
     gdk, gio, glib, BitsetIter, ColumnView, ColumnViewColumn, SignalListItemFactory, Widget,
 };
@@ -25,7 +26,7 @@ mod imp {
         CompositeTemplate,
     };
     use std::{
-        cell::{OnceCell, RefCell},
+        cell::{Cell, OnceCell, RefCell},
         sync::LazyLock,
     };
 
@@ -47,6 +48,9 @@ mod imp {
         pub single_select_actions: RefCell<Vec<SimpleAction>>,
         pub store: OnceCell<WeakRef<Store>>,
         pub actions: gio::SimpleActionGroup,
+        pub filter: OnceCell<gtk::CustomFilter>,
+        /// only show snapshots whose source is gone
+        pub orphaned_only: Cell<bool>,
     }
 
     #[glib::object_subclass]
@@ -98,6 +102,20 @@ mod imp {
                 true,
                 &header_menu,
             );
+            obj.setup_column(
+                Attribute::Generation,
+                gettext("Generation").as_str(),
+                false,
+                &header_menu,
+            )
+            .set_visible(false);
+            obj.setup_column(
+                Attribute::ReceivedUuid,
+                gettext("Received UUID").as_str(),
+                false,
+                &header_menu,
+            )
+            .set_visible(false);
             // set default sort order
             self.column_view
                 .sort_by_column(Some(&created_col), gtk::SortType::Descending);
@@ -136,10 +154,22 @@ mod imp {
 
     impl SnapshotView {
         fn setup_model(&self) {
-            let filter = gtk::CustomFilter::new(|obj| {
-                !obj.downcast_ref::<Subvolume>().unwrap().is_protected()
-            });
-            let model = gtk::FilterListModel::new(Some(self.store().model()), Some(filter));
+            let list = self.store().model();
+            let view = self.obj();
+            // snapshots of a group are shown as a single row
+            let filter = gtk::CustomFilter::new(
+                glib::clone!(@weak list, @weak view => @default-return false, move |obj| {
+                    let subvol = obj.downcast_ref::<Subvolume>().unwrap();
+                    !subvol.is_protected()
+                        && list.is_group_leader(subvol)
+                        && (!view.imp().orphaned_only.get() || subvol.is_orphaned())
+                }),
+            );
+            list.connect_items_changed(glib::clone!(@weak filter => move |_, _, _, _| {
+                filter.changed(gtk::FilterChange::Different);
+            }));
+            self.filter.set(filter.clone()).unwrap();
+            let model = gtk::FilterListModel::new(Some(list), Some(filter));
 
             let model = gtk::SortListModel::new(Some(model), self.column_view.sorter());
             let model = gtk::MultiSelection::new(Some(model));
@@ -194,12 +224,12 @@ impl SnapshotView {
             let cell = SubvolumeLabelCell::new();
             item.set_child(Some(&cell));
         });
-        factory.connect_bind(move |_, item| {
+        factory.connect_bind(glib::clone!(@weak self as view => move |_, item| {
             let item = item.downcast_ref::<gtk::ListItem>().unwrap();
             let obj: Subvolume = item.item().unwrap().downcast().unwrap();
             let cell: SubvolumeLabelCell = item.child().unwrap().downcast().unwrap();
-            cell.label().set_label(&obj.attribute_str(attribute));
-        });
+            cell.label().set_label(&view.cell_text(&obj, attribute));
+        }));
         factory.connect_unbind(move |_, item| {
             let item = item.downcast_ref::<gtk::ListItem>().unwrap();
             let cell = item
@@ -230,6 +260,64 @@ impl SnapshotView {
         cvc
     }
 
+    /// Text of `attribute` in the row of `subvol`, which stands for its
+    /// whole group if it has one
+    fn cell_text(&self, subvol: &Subvolume, attribute: Attribute) -> String {
+        match (attribute, subvol.group_uuid()) {
+            (Attribute::ParentPath, Some(group)) => self
+                .store()
+                .model()
+                .group_members(&group)
+                .iter()
+                .map(|member| member.attribute_str(attribute))
+                .collect::<Vec<_>>()
+                .join(", "),
+            _ => subvol.attribute_str(attribute),
+        }
+    }
+
+    /// Selected snapshots, with every member of the selected groups
+    fn selected_snapshots(&self) -> Vec<Subvolume> {
+        let selection_model = self.model();
+        let selection = selection_model.selection();
+        let list = self.store().model();
+        let mut ret = Vec::new();
+        if let Some((mut it, mut idx)) = BitsetIter::init_first(&selection) {
+            loop {
+                let obj: Subvolume = selection_model
+                    .item(idx)
+                    .expect("Item must exist")
+                    .downcast()
+                    .unwrap();
+                match obj.group_uuid() {
+                    Some(group) => ret.extend(list.group_members(&group)),
+                    None => ret.push(obj),
+                }
+                if let Some(next) = it.next() {
+                    idx = next;
+                } else {
+                    break;
+                }
+            }
+        }
+        ret
+    }
+
+    fn restore_snapshots(&self, paths: Vec<ZPathBuf>) {
+        println!("restore: {:?}", paths);
+        match self.store().restore_snapshots(paths) {
+            Ok(backups) => {
+                let backups: Vec<String> = backups
+                    .iter()
+                    .map(|p| p.as_path().display().to_string())
+                    .collect();
+                let message = gettext("Restored. The replaced subvolumes were kept at {}.");
+                self.alert(&message.replace("{}", &backups.join(", ")));
+            }
+            Err(error) => self.alert(&error.to_string()),
+        }
+    }
+
     fn open_snapshot(&self, idx: u32) {
         let obj = self
             .model()
@@ -249,7 +337,6 @@ impl SnapshotView {
 
     fn setup_menu(&self) {
         let imp = self.imp();
-        let col_view = &imp.column_view.get();
 
         let open_action = gio::SimpleAction::new("open", None);
         open_action.connect_activate(glib::clone!(@weak self as view => move |_, _| {
@@ -278,38 +365,71 @@ impl SnapshotView {
         }));
 
         let delete_action = gio::SimpleAction::new("delete", None);
-        delete_action.connect_activate(
-            glib::clone!(@weak col_view, @weak self as view => move |_, _| {
-                let selection_model = col_view.model().unwrap();
-                let selection = selection_model.selection().copy();
-                let mut to_delete = Vec::<ZPathBuf>::new();
-                if let Some((mut it, mut idx)) =  BitsetIter::init_first(&selection) {
-                    loop {
-                        println!("{}", idx);
-                        let obj: Subvolume = selection_model
-                        .item(idx)
-                        .expect("Item must exist")
-                        .downcast()
-                        .unwrap();
-                        to_delete.push(obj.mount_path().map(|x| x.to_path_buf().into()).unwrap());
-                        if let Some(next) = it.next() {
-                            idx = next;
-                        } else {
-                            break;
-                        }
-                    }
-                }
-                if !to_delete.is_empty() {
-                    println!("delete: {:?}", to_delete);
-                    view.store().delete_snapshots(to_delete).unwrap();
+        delete_action.connect_activate(glib::clone!(@weak self as view => move |_, _| {
+            let to_delete: Vec<ZPathBuf> = view
+                .selected_snapshots()
+                .iter()
+                .filter_map(|obj| obj.mount_path().map(|x| x.to_path_buf().into()))
+                .collect();
+            if !to_delete.is_empty() {
+                println!("delete: {:?}", to_delete);
+                view.store().delete_snapshots(to_delete).unwrap();
+            }
+        }));
+
+        let restore_action = gio::SimpleAction::new("restore", None);
+        restore_action.connect_activate(glib::clone!(@weak self as view => move |_, _| {
+            let to_restore: Vec<ZPathBuf> = view
+                .selected_snapshots()
+                .iter()
+                .filter_map(|obj| obj.mount_path().map(|x| x.to_path_buf().into()))
+                .collect();
+            if to_restore.is_empty() {
+                return;
+            }
+            let win = view.root().and_then(|w| w.downcast::<gtk::Window>().ok());
+            let dialog = adw::MessageDialog::new(
+                win.as_ref(),
+                Some(&gettext("Restore Snapshots?")),
+                Some(&gettext("Their sources will be replaced by writable copies.")),
+            );
+            dialog.add_responses(&[
+                ("cancel", &gettext("Cancel")),
+                ("restore", &gettext("Restore")),
+            ]);
+            dialog.set_response_appearance("restore", adw::ResponseAppearance::Destructive);
+            dialog.connect_response(Some("restore"), glib::clone!(@weak view => move |_, _| {
+                view.restore_snapshots(to_restore.clone());
+            }));
+            dialog.present();
+        }));
+
+        let orphaned_action =
+            gio::SimpleAction::new_stateful("orphaned-only", None, &false.to_variant());
+        orphaned_action.connect_change_state(
+            glib::clone!(@weak self as view => move |action, state| {
+                let Some(orphaned_only) = state.and_then(|s| s.get::<bool>()) else {
+                    return;
+                };
+                action.set_state(&orphaned_only.to_variant());
+                let imp = view.imp();
+                imp.orphaned_only.set(orphaned_only);
+                if let Some(filter) = imp.filter.get() {
+                    filter.changed(if orphaned_only {
+                        gtk::FilterChange::MoreStrict
+                    } else {
+                        gtk::FilterChange::LessStrict
+                    });
                 }
             }),
         );
 
         let actions = &imp.actions;
+        actions.add_action(&orphaned_action);
         actions.add_action(&open_action);
         actions.add_action(&rename_action);
         actions.add_action(&delete_action);
+        actions.add_action(&restore_action);
 
         let mut single_actions = imp.single_select_actions.borrow_mut();
         single_actions.push(open_action);
@@ -335,17 +455,21 @@ impl SnapshotView {
                 .downcast()
                 .unwrap();
 
+            popover.popdown();
+
+            let win = view.root().unwrap().downcast::<AppWindow>().unwrap();
+            if butterd::snapper_number(obj.subvol_path()).is_some() {
+                win.alert(&gettext("Snapshots in snapper's layout cannot be renamed"));
+                return;
+            }
             let mut new_path = obj.mount_path().unwrap().to_path_buf();
             new_path.set_file_name(popover.text());
 
-            popover.popdown();
-
             let res = view
                 .store()
                 .rename_snapshot(obj.mount_path().unwrap().to_owned().into(), new_path.into());
 
             if let Err(error) = res {
-                let win = view.root().unwrap().downcast::<AppWindow>().unwrap();
                 win.alert(&error.to_string());
             }
         }));
@@ -0,0 +1,149 @@
+use std::path::PathBuf;
+
+use butterd::SubvolumeCreateOptions;
+use gtk::subclass::prelude::*;
+use gtk::{glib, prelude::*, CompositeTemplate};
+
+use super::subvolume_properties_window::ALGORITHMS;
+use super::FileChooserEntry;
+use crate::ui::prelude::*;
+use crate::ui::store::Store;
+
+mod imp {
+    use std::{cell::OnceCell, sync::LazyLock};
+
+    use glib::object::WeakRef;
+    use gtk::glib::{ParamSpec, ParamSpecObject, Value};
+
+    use super::*;
+
+    #[derive(CompositeTemplate, Default)]
+    #[template(resource = "/org/zhangyuannie/butter/ui/subvolume_creation_window.ui")]
+    pub struct SubvolumeCreationWindow {
+        #[template_child]
+        pub create_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub name_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub location_entry: TemplateChild<FileChooserEntry>,
+        #[template_child]
+        pub qgroups_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub compression_dropdown: TemplateChild<gtk::DropDown>,
+        #[template_child]
+        pub nodatacow_switch: TemplateChild<gtk::Switch>,
+
+        pub store: OnceCell<WeakRef<Store>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for SubvolumeCreationWindow {
+        const NAME: &'static str = "SubvolumeCreationWindow";
+        type Type = super::SubvolumeCreationWindow;
+        type ParentType = gtk::Window;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for SubvolumeCreationWindow {
+        fn constructed(&self) {
+            self.parent_constructed();
+            let obj = self.obj();
+            self.create_button.set_sensitive(false);
+            self.name_entry
+                .connect_text_notify(glib::clone!(@weak obj => move |_| {
+                    obj.update_create_button();
+                }));
+            self.location_entry
+                .connect_text_notify(glib::clone!(@weak obj => move |_| {
+                    obj.update_create_button();
+                }));
+            self.create_button
+                .connect_clicked(glib::clone!(@weak obj => move |_| {
+                    let res = obj
+                        .store()
+                        .create_subvolume(obj.target_path().into(), obj.options());
+                    match res {
+                        Ok(_) => obj.close(),
+                        Err(error) => obj.alert(&error.to_string()),
+                    }
+                }));
+        }
+
+        fn properties() -> &'static [ParamSpec] {
+            static PROPERTIES: LazyLock<Vec<ParamSpec>> = LazyLock::new(|| {
+                vec![ParamSpecObject::builder::<Store>("store")
+                    .construct_only()
+                    .build()]
+            });
+            PROPERTIES.as_ref()
+        }
+
+        fn set_property(&self, _id: usize, value: &Value, pspec: &ParamSpec) {
+            match pspec.name() {
+                "store" => self
+                    .store
+                    .set(value.get::<Store>().unwrap().downgrade())
+                    .unwrap(),
+
+                _ => unimplemented!(),
+            }
+        }
+    }
+    impl WidgetImpl for SubvolumeCreationWindow {}
+    impl WindowImpl for SubvolumeCreationWindow {}
+}
+
+glib::wrapper! {
+    /// Create an empty subvolume that is not a snapshot
+    pub struct SubvolumeCreationWindow(ObjectSubclass<imp::SubvolumeCreationWindow>)
+        @extends gtk::Window, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget,
+                    gtk::Native, gtk::Root, gtk::ShortcutManager;
+}
+
+impl SubvolumeCreationWindow {
+    pub fn new(store: &Store) -> Self {
+        glib::Object::builder().property("store", store).build()
+    }
+
+    fn update_create_button(&self) {
+        let imp = self.imp();
+        imp.create_button.set_sensitive(
+            imp.name_entry.text_length() > 0 && !imp.location_entry.text().is_empty(),
+        );
+    }
+
+    fn target_path(&self) -> PathBuf {
+        let imp = self.imp();
+        let mut ret = PathBuf::from(imp.location_entry.text().to_string());
+        ret.push(imp.name_entry.text().to_string());
+        ret
+    }
+
+    fn options(&self) -> SubvolumeCreateOptions {
+        let imp = self.imp();
+        let qgroups: Vec<String> = imp
+            .qgroups_entry
+            .text()
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect();
+        let algo = ALGORITHMS[imp.compression_dropdown.selected() as usize];
+        SubvolumeCreateOptions {
+            inherit_qgroups: (!qgroups.is_empty()).then_some(qgroups),
+            compression: (!algo.is_empty()).then(|| algo.to_owned()),
+            nodatacow: imp.nodatacow_switch.is_active().then_some(true),
+        }
+    }
+
+    fn store(&self) -> Store {
+        self.imp().store.get().unwrap().upgrade().unwrap()
+    }
+}
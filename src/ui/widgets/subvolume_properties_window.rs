@@ -0,0 +1,224 @@
+use butterd::SubvolumeProperties;
+use gettext::gettext;
+use gtk::subclass::prelude::*;
+use gtk::{gio, glib, prelude::*, CompositeTemplate};
+
+use crate::object::Subvolume;
+use crate::ui::prelude::*;
+use crate::ui::store::Store;
+
+/// Choices of the compression dropdown, in order
+pub(super) const ALGORITHMS: [&str; 4] = ["", "zlib", "lzo", "zstd"];
+
+mod imp {
+    use std::{cell::OnceCell, sync::LazyLock};
+
+    use glib::object::WeakRef;
+    use gtk::glib::{ParamSpec, ParamSpecObject, Value};
+
+    use super::*;
+
+    #[derive(CompositeTemplate, Default)]
+    #[template(resource = "/org/zhangyuannie/butter/ui/subvolume_properties_window.ui")]
+    pub struct SubvolumePropertiesWindow {
+        #[template_child]
+        pub apply_button: TemplateChild<gtk::Button>,
+        #[template_child]
+        pub label_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub subvol_dropdown: TemplateChild<gtk::DropDown>,
+        #[template_child]
+        pub compression_dropdown: TemplateChild<gtk::DropDown>,
+        #[template_child]
+        pub level_spin: TemplateChild<gtk::SpinButton>,
+        #[template_child]
+        pub nodatacow_switch: TemplateChild<gtk::Switch>,
+
+        pub store: OnceCell<WeakRef<Store>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for SubvolumePropertiesWindow {
+        const NAME: &'static str = "SubvolumePropertiesWindow";
+        type Type = super::SubvolumePropertiesWindow;
+        type ParentType = gtk::Window;
+
+        fn class_init(klass: &mut Self::Class) {
+            Self::bind_template(klass);
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for SubvolumePropertiesWindow {
+        fn constructed(&self) {
+            self.parent_constructed();
+            let obj = self.obj();
+            self.label_entry
+                .set_text(&obj.store().filesystem_label().unwrap_or_default());
+            self.compression_dropdown
+                .connect_selected_notify(glib::clone!(@weak obj => move |_| {
+                    obj.update_level_range();
+                }));
+            self.subvol_dropdown
+                .connect_selected_notify(glib::clone!(@weak obj => move |_| {
+                    obj.load_properties();
+                }));
+            obj.setup_dropdown();
+            obj.load_properties();
+            self.apply_button
+                .connect_clicked(glib::clone!(@weak obj => move |_| {
+                    match obj.apply() {
+                        Ok(_) => obj.close(),
+                        Err(error) => obj.alert(&error.to_string()),
+                    }
+                }));
+        }
+
+        fn properties() -> &'static [ParamSpec] {
+            static PROPERTIES: LazyLock<Vec<ParamSpec>> = LazyLock::new(|| {
+                vec![ParamSpecObject::builder::<Store>("store")
+                    .construct_only()
+                    .build()]
+            });
+            PROPERTIES.as_ref()
+        }
+
+        fn set_property(&self, _id: usize, value: &Value, pspec: &ParamSpec) {
+            match pspec.name() {
+                "store" => self
+                    .store
+                    .set(value.get::<Store>().unwrap().downgrade())
+                    .unwrap(),
+
+                _ => unimplemented!(),
+            }
+        }
+    }
+    impl WidgetImpl for SubvolumePropertiesWindow {}
+    impl WindowImpl for SubvolumePropertiesWindow {}
+}
+
+glib::wrapper! {
+    /// Compression and copy-on-write of primary subvolumes, and the label of
+    /// the filesystem
+    pub struct SubvolumePropertiesWindow(ObjectSubclass<imp::SubvolumePropertiesWindow>)
+        @extends gtk::Window, gtk::Widget,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget,
+                    gtk::Native, gtk::Root, gtk::ShortcutManager;
+}
+
+impl SubvolumePropertiesWindow {
+    pub fn new(store: &Store) -> Self {
+        glib::Object::builder().property("store", store).build()
+    }
+
+    fn setup_dropdown(&self) {
+        let imp = self.imp();
+        let model = self
+            .store()
+            .primary_subvolumes()
+            .unwrap_or_else(|_| gio::ListStore::new::<Subvolume>());
+
+        let exp = gtk::ClosureExpression::new::<String>(
+            &[] as &[gtk::Expression],
+            glib::closure!(|sv: Subvolume| {
+                let path = String::from(sv.subvol_path().to_string_lossy());
+                if path == "/" {
+                    "<FS_TREE>".to_string()
+                } else {
+                    path
+                }
+            }),
+        );
+
+        imp.subvol_dropdown.set_expression(Some(&exp));
+        imp.subvol_dropdown.set_model(Some(&model));
+    }
+
+    fn selected_subvolume(&self) -> Option<Subvolume> {
+        self.imp()
+            .subvol_dropdown
+            .selected_item()
+            .and_downcast::<Subvolume>()
+    }
+
+    /// Show the current properties of the selected subvolume
+    fn load_properties(&self) {
+        let imp = self.imp();
+        let Some(path) = self
+            .selected_subvolume()
+            .and_then(|sv| sv.mount_path().map(|p| p.to_owned()))
+        else {
+            imp.apply_button.set_sensitive(false);
+            return;
+        };
+        let props = match self.store().subvolume_properties(path.into()) {
+            Ok(props) => props,
+            Err(error) => {
+                imp.apply_button.set_sensitive(false);
+                self.alert(&error.to_string());
+                return;
+            }
+        };
+        imp.apply_button.set_sensitive(true);
+
+        let compression = props.compression.unwrap_or_default();
+        let (algo, level) = compression
+            .split_once(':')
+            .unwrap_or((compression.as_str(), ""));
+        let idx = ALGORITHMS.iter().position(|a| *a == algo).unwrap_or(0);
+        imp.compression_dropdown.set_selected(idx as u32);
+        self.update_level_range();
+        imp.level_spin.set_value(level.parse().unwrap_or(0.0));
+        imp.nodatacow_switch
+            .set_active(props.nodatacow.unwrap_or_default());
+    }
+
+    /// Levels supported by the selected algorithm, 0 being its default
+    fn update_level_range(&self) {
+        let imp = self.imp();
+        let max = match ALGORITHMS[imp.compression_dropdown.selected() as usize] {
+            "zlib" => 9.0,
+            "zstd" => 15.0,
+            _ => 0.0,
+        };
+        imp.level_spin.set_range(0.0, max);
+        imp.level_spin.set_sensitive(max > 0.0);
+    }
+
+    fn apply(&self) -> anyhow::Result<()> {
+        let imp = self.imp();
+        let path = self
+            .selected_subvolume()
+            .and_then(|sv| sv.mount_path().map(|p| p.to_owned()))
+            .ok_or_else(|| anyhow::anyhow!(gettext("The subvolume is not mounted")))?;
+
+        let algo = ALGORITHMS[imp.compression_dropdown.selected() as usize];
+        let level = imp.level_spin.value_as_int();
+        let compression = if algo.is_empty() || level == 0 {
+            algo.to_owned()
+        } else {
+            format!("{}:{}", algo, level)
+        };
+        self.store().set_subvolume_properties(
+            path.into(),
+            SubvolumeProperties {
+                compression: Some(compression),
+                nodatacow: Some(imp.nodatacow_switch.is_active()),
+            },
+        )?;
+
+        let label = imp.label_entry.text();
+        if label != self.store().filesystem_label().unwrap_or_default() {
+            self.store().set_filesystem_label(&label)?;
+        }
+        Ok(())
+    }
+
+    fn store(&self) -> Store {
+        self.imp().store.get().unwrap().upgrade().unwrap()
+    }
+}